@@ -0,0 +1,24 @@
+CREATE TABLE refresh_tokens
+(
+    id            uuid        NOT NULL PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id       uuid        NOT NULL,
+
+    token_hash    text        NOT NULL UNIQUE,
+
+    created_at    timestamptz NOT NULL DEFAULT now(),
+    expires_at    timestamptz NOT NULL,
+    revoked_at    timestamptz NULL,
+
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+-- Every refresh token a session was rotated away from, so that reuse of
+-- any of them is noticed and not only reuse of the last one
+CREATE TABLE rotated_refresh_tokens
+(
+    token_hash       text        NOT NULL PRIMARY KEY,
+    refresh_token_id uuid        NOT NULL,
+    rotated_at       timestamptz NOT NULL DEFAULT now(),
+
+    FOREIGN KEY (refresh_token_id) REFERENCES refresh_tokens (id) ON DELETE CASCADE
+);
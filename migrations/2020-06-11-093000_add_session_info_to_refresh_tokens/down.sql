@@ -0,0 +1,5 @@
+ALTER TABLE refresh_tokens
+    DROP COLUMN access_jti,
+    DROP COLUMN last_seen_at,
+    DROP COLUMN user_agent,
+    DROP COLUMN ip_address;
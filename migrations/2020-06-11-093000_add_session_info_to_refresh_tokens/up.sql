@@ -0,0 +1,5 @@
+ALTER TABLE refresh_tokens
+    ADD COLUMN access_jti   uuid        NOT NULL DEFAULT uuid_generate_v4(),
+    ADD COLUMN last_seen_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN user_agent   text        NULL,
+    ADD COLUMN ip_address   text        NULL;
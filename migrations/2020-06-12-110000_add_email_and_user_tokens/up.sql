@@ -0,0 +1,22 @@
+ALTER TABLE users
+    ADD COLUMN email             text        NULL,
+    ADD COLUMN email_verified_at timestamptz NULL;
+
+CREATE UNIQUE INDEX users_email_idx ON users (lower(email));
+
+-- Single use tokens that are mailed to a user
+CREATE TABLE user_tokens
+(
+    id         uuid        NOT NULL PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id    uuid        NOT NULL,
+    purpose    text        NOT NULL CHECK (purpose IN ('verify_email', 'reset_password')),
+
+    token_hash text        NOT NULL UNIQUE,
+    email      text        NOT NULL, -- the address the token was sent to
+
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL,
+    used_at    timestamptz NULL,
+
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,39 @@
+-- TOTP (RFC 6238) secrets, a user has 2FA enabled once the secret is confirmed
+CREATE TABLE two_factor
+(
+    user_id        uuid        NOT NULL PRIMARY KEY,
+    secret         text        NOT NULL, -- base32 encoded
+    enabled_at     timestamptz NULL,
+    last_used_step bigint      NULL,     -- a code can't be used twice
+
+    created_at     timestamptz NOT NULL DEFAULT now(),
+
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+-- One-time codes for when the authenticator is lost
+CREATE TABLE recovery_codes
+(
+    id        uuid        NOT NULL PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id   uuid        NOT NULL,
+    code_hash text        NOT NULL,
+    used_at   timestamptz NULL,
+
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
+
+-- A login that still needs a second factor
+CREATE TABLE two_factor_challenges
+(
+    id         uuid        NOT NULL PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id    uuid        NOT NULL,
+    token_hash text        NOT NULL UNIQUE,
+    attempts   integer     NOT NULL DEFAULT 0,
+
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL,
+
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
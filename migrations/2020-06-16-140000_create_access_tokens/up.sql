@@ -0,0 +1,18 @@
+-- Personal access tokens, for scripts and integrations
+CREATE TABLE access_tokens
+(
+    id           uuid        NOT NULL PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id      uuid        NOT NULL,
+    name         text        NOT NULL,
+    scopes       text[]      NOT NULL,
+
+    token_hash   text        NOT NULL UNIQUE,
+
+    created_at   timestamptz NOT NULL DEFAULT now(),
+    expires_at   timestamptz NULL,
+    last_used_at timestamptz NULL,
+
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX access_tokens_user_id_idx ON access_tokens (user_id);
@@ -0,0 +1,7 @@
+-- Rate limit counters, shared by every instance when RATE_LIMIT_STORE=postgres
+CREATE UNLOGGED TABLE rate_limits
+(
+    key       text        NOT NULL PRIMARY KEY,
+    hits      integer     NOT NULL,
+    resets_at timestamptz NOT NULL
+);
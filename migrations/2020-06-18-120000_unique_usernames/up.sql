@@ -0,0 +1,15 @@
+-- Usernames weren't unique before. Of every set of (case-insensitive)
+-- duplicates, the account with the most items keeps its name, the others
+-- get part of their id appended so they stay recognisable.
+UPDATE users
+SET username = users.username || '-' || substr(users.id::text, 1, 8)
+FROM (SELECT id,
+             row_number() OVER (
+                 PARTITION BY lower(username)
+                 ORDER BY (SELECT count(*) FROM items WHERE items.owner_id = users.id) DESC, id
+                 ) AS rank
+      FROM users) AS ranked
+WHERE users.id = ranked.id
+  AND ranked.rank > 1;
+
+CREATE UNIQUE INDEX users_username_idx ON users (lower(username));
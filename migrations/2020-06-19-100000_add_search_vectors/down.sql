@@ -0,0 +1,4 @@
+ALTER TABLE pages DROP COLUMN search;
+ALTER TABLE todos DROP COLUMN search;
+ALTER TABLE todo_items DROP COLUMN search;
+ALTER TABLE text_fields DROP COLUMN search;
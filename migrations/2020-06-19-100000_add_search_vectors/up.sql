@@ -0,0 +1,16 @@
+-- The search vectors are generated by postgres, so they can't go stale.
+-- The 'simple' configuration doesn't stem, journals are written in more
+-- than one language.
+ALTER TABLE pages
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (to_tsvector('simple', title)) STORED;
+ALTER TABLE todos
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (to_tsvector('simple', title)) STORED;
+ALTER TABLE todo_items
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (to_tsvector('simple', title)) STORED;
+ALTER TABLE text_fields
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (to_tsvector('simple', text)) STORED;
+
+CREATE INDEX pages_search_idx ON pages USING gin (search);
+CREATE INDEX todos_search_idx ON todos USING gin (search);
+CREATE INDEX todo_items_search_idx ON todo_items USING gin (search);
+CREATE INDEX text_fields_search_idx ON text_fields USING gin (search);
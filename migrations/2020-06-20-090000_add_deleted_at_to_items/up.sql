@@ -0,0 +1,6 @@
+-- Deleted items go to the trash first. Everything deleted along with an
+-- item gets the same deleted_at, so they can be restored together.
+ALTER TABLE items
+    ADD COLUMN deleted_at timestamptz NULL;
+
+CREATE INDEX items_deleted_at_idx ON items (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,17 @@
+-- Earlier versions of the subtype of an item, saved whenever it's updated
+CREATE TABLE item_revisions
+(
+    id         uuid        NOT NULL PRIMARY KEY DEFAULT uuid_generate_v4(),
+    item_id    uuid        NOT NULL,
+    item_type  smallint    NOT NULL,
+    owner_id   uuid        NOT NULL,
+    revision   integer     NOT NULL,
+    payload    jsonb       NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+
+    UNIQUE (item_id, revision),
+    FOREIGN KEY (item_id, item_type) REFERENCES items (id, item_type) ON DELETE CASCADE,
+    FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX item_revisions_owner_id_idx ON item_revisions (owner_id, created_at);
@@ -0,0 +1,10 @@
+DROP TRIGGER bump_version ON text_fields;
+DROP TRIGGER bump_version ON todo_items;
+DROP TRIGGER bump_version ON todos;
+DROP TRIGGER bump_version ON pages;
+DROP FUNCTION subtypes_bump_version();
+DROP TRIGGER bump_version ON items;
+DROP FUNCTION items_bump_version();
+
+ALTER TABLE items
+    DROP COLUMN version;
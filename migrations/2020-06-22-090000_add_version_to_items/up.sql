@@ -0,0 +1,40 @@
+-- The version of an item is its ETag. It's bumped whenever the item, or
+-- its subtype, is changed.
+ALTER TABLE items
+    ADD COLUMN version integer NOT NULL DEFAULT 1;
+
+CREATE OR REPLACE FUNCTION items_bump_version() RETURNS trigger AS $$
+BEGIN
+    IF (
+        NEW IS DISTINCT FROM OLD AND
+        NEW.version IS NOT DISTINCT FROM OLD.version
+    ) THEN
+        NEW.version := OLD.version + 1;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER bump_version BEFORE UPDATE ON items
+    FOR EACH ROW EXECUTE PROCEDURE items_bump_version();
+
+CREATE OR REPLACE FUNCTION subtypes_bump_version() RETURNS trigger AS $$
+BEGIN
+    UPDATE items SET version = version + 1
+    WHERE id = NEW.id AND item_type = NEW.item_type;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER bump_version AFTER UPDATE ON pages
+    FOR EACH ROW WHEN (NEW IS DISTINCT FROM OLD)
+    EXECUTE PROCEDURE subtypes_bump_version();
+CREATE TRIGGER bump_version AFTER UPDATE ON todos
+    FOR EACH ROW WHEN (NEW IS DISTINCT FROM OLD)
+    EXECUTE PROCEDURE subtypes_bump_version();
+CREATE TRIGGER bump_version AFTER UPDATE ON todo_items
+    FOR EACH ROW WHEN (NEW IS DISTINCT FROM OLD)
+    EXECUTE PROCEDURE subtypes_bump_version();
+CREATE TRIGGER bump_version AFTER UPDATE ON text_fields
+    FOR EACH ROW WHEN (NEW IS DISTINCT FROM OLD)
+    EXECUTE PROCEDURE subtypes_bump_version();
@@ -0,0 +1,22 @@
+-- The order of items within their parent. Positions are compared byte by
+-- byte, so new ones can always be made between any two others.
+ALTER TABLE items
+    ADD COLUMN position text COLLATE "C" NOT NULL DEFAULT '';
+
+-- Existing items keep the order they were created in. Positions never end
+-- in the lowest digit, hence the suffix.
+UPDATE items
+SET position = ranked.position
+FROM (
+    SELECT id,
+           lpad((row_number() OVER (
+               PARTITION BY owner_id, parent_id ORDER BY created_at, id
+           ))::text, 10, '0') || 'i' AS position
+    FROM items
+) AS ranked
+WHERE items.id = ranked.id;
+
+ALTER TABLE items
+    ALTER COLUMN position DROP DEFAULT;
+
+CREATE INDEX items_parent_id_position_idx ON items (parent_id, position);
@@ -0,0 +1,5 @@
+ALTER TABLE users
+    DROP COLUMN timezone;
+
+ALTER TABLE pages
+    DROP COLUMN is_template;
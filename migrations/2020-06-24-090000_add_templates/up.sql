@@ -0,0 +1,5 @@
+ALTER TABLE pages
+    ADD COLUMN is_template boolean NOT NULL DEFAULT false;
+
+ALTER TABLE users
+    ADD COLUMN timezone text NOT NULL DEFAULT 'UTC';
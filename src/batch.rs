@@ -0,0 +1,552 @@
+//! Several changes in one request.
+//!
+//! `POST /api/batch` takes a list of operations and makes them in order, in
+//! one transaction: either all of them are made or none are. An operation
+//! creates, updates or deletes an item of any type, or a tag:
+//!
+//! ```json
+//! { "operations": [
+//!     { "op": "create", "type": "Page", "temp_id": "week",
+//!       "body": { "title": "Week 27" } },
+//!     { "op": "create", "type": "Todo",
+//!       "body": { "title": "Chores", "page_id": { "$ref": "week" },
+//!                 "coord_x": 0, "coord_y": 0 } },
+//!     { "op": "update", "type": "Tag", "id": "...",
+//!       "body": { "color": "0x00FF00" } },
+//!     { "op": "delete", "type": "TodoItem", "id": "..." }
+//! ] }
+//! ```
+//!
+//! A created item or tag can be given a `temp_id`, which later operations
+//! use as `{ "$ref": "<temp_id>" }` wherever its id goes. The body of an
+//! update is a JSON Merge Patch, or a JSON Patch when it's an array. The
+//! response has the result of every operation, in order. When one fails,
+//! the error says which one it was.
+
+use std::collections::HashMap;
+
+use diesel::{pg::PgConnection, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::items::registry::{self, Registration};
+use crate::tags::tag::Tag;
+use crate::users::{access_token::Scope, User};
+use crate::utils::{
+    error::{ApiError, ErrMsg},
+    patch::Patch,
+};
+
+/// How many operations a batch can have at most
+const MAX_OPERATIONS: usize = 100;
+
+/// The type of the operations on tags
+const TAG: &str = "Tag";
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    operations: Vec<Operation>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Create {
+        #[serde(rename = "type")]
+        kind: String,
+        temp_id: Option<String>,
+        body: Value,
+    },
+    Update {
+        #[serde(rename = "type")]
+        kind: String,
+        id: Value,
+        body: Value,
+    },
+    Delete {
+        #[serde(rename = "type")]
+        kind: String,
+        id: Value,
+    },
+}
+
+/// What an operation works on
+enum Kind {
+    Item(&'static Registration),
+    Tag,
+}
+
+impl Kind {
+    fn of(name: &str) -> Result<Self, ApiError> {
+        if name == TAG {
+            return Ok(Kind::Tag);
+        }
+
+        registry::REGISTRY
+            .iter()
+            .find(|registration| registration.tag == name)
+            .map(Kind::Item)
+            .ok_or_else(|| {
+                ApiError::Unprocessable(format!("Unknown type {}.", name))
+            })
+    }
+
+    fn scope(&self) -> Scope {
+        match self {
+            Kind::Item(_) => Scope::ItemsWrite,
+            Kind::Tag => Scope::TagsWrite,
+        }
+    }
+}
+
+/// The result of an operation that was made
+#[derive(Serialize)]
+pub struct Outcome {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temp_id: Option<String>,
+    id: Uuid,
+    /// What was created or updated, `null` for deletes
+    body: Value,
+}
+
+/// The operation that made the batch fail, and why
+#[derive(Serialize)]
+pub struct Failure {
+    /// The index of the operation, missing when the transaction itself
+    /// failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operation: Option<usize>,
+    #[serde(flatten)]
+    error: ErrMsg,
+    #[serde(skip)]
+    status: actix_web::http::StatusCode,
+}
+
+impl Failure {
+    fn new(operation: Option<usize>, error: ApiError) -> Self {
+        use actix_web::ResponseError;
+
+        Failure { operation, error: error.body(), status: error.status_code() }
+    }
+}
+
+impl From<diesel::result::Error> for Failure {
+    fn from(error: diesel::result::Error) -> Self {
+        Failure::new(None, error.into())
+    }
+}
+
+/// Replaces every `{ "$ref": "<temp_id>" }` in the value with the id that
+/// was created under the temporary id
+fn resolve(
+    value: &mut Value,
+    ids: &HashMap<String, Uuid>,
+) -> Result<(), ApiError> {
+    let reference = match value {
+        Value::Object(object) if object.len() == 1 => {
+            match object.get("$ref") {
+                Some(Value::String(temp_id)) => Some(temp_id.clone()),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    if let Some(temp_id) = reference {
+        let id = ids.get(&temp_id).ok_or_else(|| {
+            ApiError::Unprocessable(format!(
+                "No earlier operation created {}.",
+                temp_id
+            ))
+        })?;
+        *value = json!(id);
+        return Ok(());
+    }
+
+    match value {
+        Value::Object(object) => {
+            object.values_mut().try_for_each(|value| resolve(value, ids))
+        }
+        Value::Array(values) => {
+            values.iter_mut().try_for_each(|value| resolve(value, ids))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The id an update or delete is for, which can be a reference
+fn resolve_id(
+    mut id: Value,
+    ids: &HashMap<String, Uuid>,
+) -> Result<Uuid, ApiError> {
+    resolve(&mut id, ids)?;
+
+    serde_json::from_value(id)
+        .map_err(|_| ApiError::Unprocessable("The id isn't valid.".into()))
+}
+
+fn parse<T: serde::de::DeserializeOwned>(body: Value) -> Result<T, ApiError> {
+    serde_json::from_value(body)
+        .map_err(|err| ApiError::Unprocessable(err.to_string()))
+}
+
+impl Operation {
+    fn kind(&self) -> Result<Kind, ApiError> {
+        match self {
+            Operation::Create { kind, .. }
+            | Operation::Update { kind, .. }
+            | Operation::Delete { kind, .. } => Kind::of(kind),
+        }
+    }
+
+    fn run(
+        self,
+        ids: &mut HashMap<String, Uuid>,
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<Outcome, ApiError> {
+        let kind = self.kind()?;
+
+        match self {
+            Operation::Create { temp_id, mut body, .. } => {
+                if let Some(temp_id) = &temp_id {
+                    if ids.contains_key(temp_id) {
+                        return Err(ApiError::Unprocessable(format!(
+                            "{} was already created.",
+                            temp_id
+                        )));
+                    }
+                }
+                resolve(&mut body, ids)?;
+
+                let (id, body) = match kind {
+                    Kind::Item(registration) => {
+                        let item =
+                            registration.create(body, user.clone(), conn)?;
+                        (item.id(), json!(item))
+                    }
+                    Kind::Tag => {
+                        let tag =
+                            Tag::create(parse(body)?, user.clone(), conn)?;
+                        (tag.id, json!(tag))
+                    }
+                };
+                if let Some(temp_id) = &temp_id {
+                    ids.insert(temp_id.clone(), id);
+                }
+
+                Ok(Outcome { temp_id, id, body })
+            }
+            Operation::Update { id, mut body, .. } => {
+                let id = resolve_id(id, ids)?;
+                resolve(&mut body, ids)?;
+                let patch = match body {
+                    Value::Array(_) => Patch::Operations(parse(body)?),
+                    body => Patch::Merge(body),
+                };
+
+                let body = match kind {
+                    Kind::Item(registration) => {
+                        json!(registration.update(
+                            id,
+                            patch,
+                            user.clone(),
+                            conn
+                        )?)
+                    }
+                    Kind::Tag => {
+                        json!(Tag::update(id, patch, user.clone(), conn)?)
+                    }
+                };
+
+                Ok(Outcome { temp_id: None, id, body })
+            }
+            Operation::Delete { id, .. } => {
+                let id = resolve_id(id, ids)?;
+                match kind {
+                    Kind::Item(registration) => {
+                        registration.delete(id, user.clone(), conn)?
+                    }
+                    Kind::Tag => Tag::delete(id, user.clone(), conn)?,
+                }
+
+                Ok(Outcome { temp_id: None, id, body: Value::Null })
+            }
+        }
+    }
+}
+
+pub struct Batch;
+
+impl Batch {
+    pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::batch);
+    }
+
+    /// The scopes the operations need, each once
+    fn scopes(operations: &[Operation]) -> Result<Vec<Scope>, ApiError> {
+        let mut scopes = Vec::new();
+        for operation in operations {
+            let scope = operation.kind()?.scope();
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+
+        Ok(scopes)
+    }
+
+    /// Makes the operations in order. Nothing is kept when one of them
+    /// fails.
+    fn run(
+        operations: Vec<Operation>,
+        user: User,
+        conn: &PgConnection,
+    ) -> Result<Vec<Outcome>, Failure> {
+        conn.transaction(|| {
+            let mut ids = HashMap::new();
+            operations
+                .into_iter()
+                .enumerate()
+                .map(|(index, operation)| {
+                    operation
+                        .run(&mut ids, &user, conn)
+                        .map_err(|error| Failure::new(Some(index), error))
+                })
+                .collect()
+        })
+    }
+}
+
+mod routes {
+    use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+    use serde_json::json;
+
+    use super::{Batch, BatchRequest, MAX_OPERATIONS};
+    use crate::database::exec_on_pool;
+    use crate::utils::{authorize, error::ApiError};
+    use crate::DbPool;
+
+    #[post("/batch")]
+    pub async fn batch(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        body: web::Json<BatchRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let operations = body.into_inner().operations;
+        if operations.len() > MAX_OPERATIONS {
+            return Err(ApiError::Unprocessable(format!(
+                "A batch can have at most {} operations.",
+                MAX_OPERATIONS
+            ))
+            .into());
+        }
+
+        let mut user = None;
+        for scope in Batch::scopes(&operations)? {
+            user = Some(authorize(&req, scope)?);
+        }
+        let user = match user {
+            Some(user) => user,
+            None => {
+                return Ok(HttpResponse::Ok().json(json!({ "results": [] })))
+            }
+        };
+
+        let result = exec_on_pool(&pool, move |conn| {
+            Ok::<_, ApiError>(Batch::run(operations, user, conn))
+        })
+        .await?;
+
+        Ok(match result {
+            Ok(results) => {
+                HttpResponse::Ok().json(json!({ "results": results }))
+            }
+            Err(failure) => HttpResponse::build(failure.status).json(failure),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_body, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use super::Batch;
+    use crate::items::{item::Item, registry};
+    use crate::tags::tag::Tag;
+    use crate::testing::remove_templates;
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[actix_rt::test]
+    async fn test_batch() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(Tag::routes)
+                            .configure(Batch::routes)
+                            .configure(registry::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (user, bearer) = login!(app, "batcher");
+                remove_templates(&user);
+
+                macro_rules! batch {
+                    ($operations:expr) => {{
+                        let resp = call_service(
+                            &mut app,
+                            TestRequest::post()
+                                .uri("/batch")
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .set_json(&json!({ "operations": $operations }))
+                                .to_request(),
+                        )
+                        .await;
+                        let status = resp.status();
+                        let body: Value = serde_json::from_slice(&read_body(resp).await)?;
+                        (status, body)
+                    }};
+                }
+                macro_rules! get {
+                    ($uri:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            TestRequest::get()
+                                .uri(&$uri)
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+
+                let (status, body) = batch!(json!([
+                    {
+                        "op": "create",
+                        "type": "Page",
+                        "temp_id": "week",
+                        "body": { "title": "Week 27" },
+                    },
+                    {
+                        "op": "create",
+                        "type": "Todo",
+                        "temp_id": "chores",
+                        "body": {
+                            "title": "Chores",
+                            "page_id": { "$ref": "week" },
+                            "coord_x": 0,
+                            "coord_y": 0,
+                        },
+                    },
+                    {
+                        "op": "create",
+                        "type": "TodoItem",
+                        "temp_id": "dishes",
+                        "body": {
+                            "title": "Dishes",
+                            "todo_id": { "$ref": "chores" },
+                            "is_checked": false,
+                        },
+                    },
+                    {
+                        "op": "create",
+                        "type": "TodoItem",
+                        "body": {
+                            "title": "Laundry",
+                            "todo_id": { "$ref": "chores" },
+                            "is_checked": false,
+                        },
+                    },
+                    {
+                        "op": "create",
+                        "type": "Tag",
+                        "temp_id": "home",
+                        "body": { "name": "home", "color": "0x00FF00" },
+                    },
+                    {
+                        "op": "update",
+                        "type": "TodoItem",
+                        "id": { "$ref": "dishes" },
+                        "body": { "is_checked": true },
+                    },
+                    {
+                        "op": "update",
+                        "type": "Tag",
+                        "id": { "$ref": "home" },
+                        "body": [{ "op": "replace", "path": "/color", "value": "0xFF0000" }],
+                    },
+                    { "op": "delete", "type": "TodoItem", "id": { "$ref": "dishes" } },
+                ]));
+                assert_eq!(status, StatusCode::OK);
+                let results = body["results"].as_array().unwrap();
+                assert_eq!(results.len(), 8);
+                assert_eq!(results[0]["temp_id"], "week");
+                assert_eq!(results[1]["body"]["item"]["parent_id"], results[0]["id"]);
+                assert_eq!(results[5]["body"]["TodoItem"]["is_checked"], true);
+                assert_eq!(results[6]["body"]["color"], "0xFF0000");
+                assert_eq!(results[7]["body"], Value::Null);
+
+                let chores = results[1]["id"].as_str().unwrap();
+                let todo_items = get!(format!("/items?parent_id={}", chores));
+                assert_eq!(todo_items["items"].as_array().unwrap().len(), 1);
+                assert_eq!(
+                    todo_items["items"][0]["subtype"]["TodoItem"]["title"],
+                    "Laundry"
+                );
+
+                // A failing operation undoes the ones before it
+                let (status, body) = batch!(json!([
+                    {
+                        "op": "create",
+                        "type": "Page",
+                        "temp_id": "doomed",
+                        "body": { "title": "Doomed" },
+                    },
+                    { "op": "create", "type": "Tag", "body": { "name": "doomed", "color": "0x000000" } },
+                    {
+                        "op": "create",
+                        "type": "TodoItem",
+                        "body": {
+                            "title": "Misplaced",
+                            "todo_id": { "$ref": "doomed" },
+                            "is_checked": false,
+                        },
+                    },
+                ]));
+                assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+                assert_eq!(body["operation"], 2);
+                assert_eq!(get!("/items")["items"].as_array().unwrap().len(), 3);
+                assert_eq!(get!("/tags").as_array().unwrap().len(), 1);
+
+                // Only ids created earlier in the batch can be referred to
+                let (status, body) = batch!(json!([
+                    { "op": "delete", "type": "Page", "id": { "$ref": "week" } },
+                ]));
+                assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+                assert_eq!(body["operation"], 0);
+
+                let (status, body) = batch!(json!([
+                    { "op": "delete", "type": "Notebook", "id": chores },
+                ]));
+                assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+                assert_eq!(body["message"], "Unknown type Notebook.");
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -4,24 +4,34 @@
 use actix_web::{middleware::Logger, web, App, HttpResponse, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use env_logger::Env;
-use serde::Serialize;
 
 use journali_api::{
-    create_pool,
-    items::{
-        item::Item, page::Page, text_field::TextField, todo::Todo,
-        todo_item::TodoItem,
-    },
+    batch::Batch,
+    create_mailer, create_pool,
+    items::{item::Item, registry, revision::Revision, trash::Trash},
     tags::tags::Tag,
-    users::User,
-    utils::validator,
-    version,
+    users::{AccessToken, RefreshToken, Session, TwoFactor, User, UserToken},
+    utils::{
+        error::ErrMsg,
+        keys::jwks,
+        rate_limit::{RateLimit, RateLimiter},
+        validator,
+    },
+    version, ApiError,
 };
 
-#[derive(Serialize)]
-struct ErrMsg {
-    status: String,
-    message: String,
+/// The routes that are rate limited per client IP
+const RATE_LIMITED_PATHS: &[&str] = &[
+    "/api/login",
+    "/api/login/2fa",
+    "/api/register",
+    "/api/password/forgot",
+    "/api/password/reset",
+];
+
+/// Refuses to start on a setting that can't be parsed
+fn invalid(err: Box<dyn std::error::Error>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string())
 }
 
 #[actix_rt::main]
@@ -33,32 +43,60 @@ async fn main() -> std::io::Result<()> {
 
     dotenv::dotenv().ok();
 
+    let pool = create_pool();
+    let limiter = web::Data::new(RateLimiter::from_env().map_err(invalid)?);
+    let retention = Trash::retention_from_env().map_err(invalid)?;
+    Revision::configure_from_env().map_err(invalid)?;
+    actix_rt::spawn(Trash::purge_periodically(pool.clone(), retention));
+
     HttpServer::new(move || {
         let auth = HttpAuthentication::bearer(validator);
         App::new()
-            .data(create_pool())
+            .data(pool.clone())
+            .data(create_mailer())
+            .app_data(limiter.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(ApiError::json_error_handler),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(ApiError::query_error_handler),
+            )
             .wrap(Logger::default())
             .wrap(Logger::new("%a %{User-Agent}i"))
             .default_service(web::to(|| {
                 HttpResponse::NotFound().json(ErrMsg {
                     status: "404".to_string(),
+                    code: ApiError::NotFound.code(),
                     message: "Page not found.".to_string(),
+                    errors: None,
                 })
             }))
+            .service(jwks)
             .service(
                 web::scope("/api")
+                    .wrap(RateLimit::new(limiter.clone(), RATE_LIMITED_PATHS))
                     .configure(User::routes)
+                    .configure(RefreshToken::routes)
+                    .configure(UserToken::routes)
+                    .configure(TwoFactor::routes)
                     .service(version)
                     .service(
                         web::scope("")
                             .wrap(auth)
                             .configure(Item::routes)
-                            .configure(Page::routes)
-                            .configure(Todo::routes)
-                            .configure(TodoItem::routes)
-                            .configure(TextField::routes)
+                            .configure(registry::routes)
+                            .configure(Revision::routes)
+                            .configure(Trash::routes)
+                            .configure(Batch::routes)
                             .configure(Tag::routes)
-                            .configure(User::route_me),
+                            .configure(User::route_me)
+                            .configure(RefreshToken::route_me)
+                            .configure(Session::route_me)
+                            .configure(UserToken::route_me)
+                            .configure(TwoFactor::route_me)
+                            .configure(AccessToken::route_me),
                     ),
             )
     })
@@ -1,12 +1,13 @@
-use core::{
-    fmt::{Debug, Display},
-    future::Future,
-};
+use core::fmt::Debug;
 
-use actix_web::{web, ResponseError};
+use actix_web::web;
 use diesel::r2d2::ConnectionManager;
 use diesel::{r2d2, PgConnection};
 
+use crate::utils::error::ApiError;
+
+sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
 pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
 pub fn create_pool() -> DbPool {
@@ -16,18 +17,18 @@ pub fn create_pool() -> DbPool {
     r2d2::Pool::builder().build(manager).expect("Failed to create pool.")
 }
 
-pub(crate) fn exec_on_pool<T, E, F>(
+pub(crate) async fn exec_on_pool<T, E, F>(
     pool: &DbPool,
     f: F,
-) -> impl Future<Output = Result<T, impl Debug + Display + ResponseError>>
+) -> Result<T, ApiError>
 where
     T: Send + 'static,
-    E: Send + 'static + Debug,
+    E: Send + 'static + Debug + Into<ApiError>,
     F: Send + 'static + FnOnce(&PgConnection) -> Result<T, E>,
 {
-    let conn = pool.get().expect("couldn't get db connection from pool");
+    let conn = pool.get()?;
     // use web::block to offload blocking Diesel code without blocking server thread
-    web::block(move || f(&conn))
+    web::block(move || f(&conn)).await.map_err(ApiError::from)
 }
 
 #[actix_rt::test]
@@ -0,0 +1,295 @@
+//! Which items may be placed in which.
+//!
+//! The rules are checked whenever an item is created in a parent or moved
+//! to another one. Breaking them results in a 422 telling the client where
+//! the item may be placed instead.
+
+use std::collections::HashSet;
+
+use diesel::{pg::PgConnection, prelude::*};
+use uuid::Uuid;
+
+use crate::items::{item::Item, ItemType, ItemTypeNames};
+use crate::schema::items;
+use crate::users::User;
+use crate::utils::{
+    error::{ApiError, FieldErrors},
+    policy::Policy,
+};
+
+use ItemTypeNames::*;
+
+/// The parents each type of item may be placed in, `None` being the top level
+const RULES: [(ItemTypeNames, &[Option<ItemTypeNames>]); 4] = [
+    (Page, &[None, Some(Page)]),
+    (Todo, &[Some(Page)]),
+    (TodoItem, &[Some(Todo)]),
+    (TextField, &[Some(Page)]),
+];
+
+pub fn allowed_parents(
+    child: ItemTypeNames,
+) -> &'static [Option<ItemTypeNames>] {
+    RULES
+        .iter()
+        .find(|(item_type, _)| *item_type == child)
+        .map_or(&[], |(_, parents)| parents)
+}
+
+fn describe(parent: Option<ItemTypeNames>) -> String {
+    match parent {
+        Some(parent) => format!("a {}", parent.name()),
+        None => "the top level".into(),
+    }
+}
+
+fn invalid(field: &'static str, message: String) -> ApiError {
+    let mut errors = FieldErrors::default();
+    errors.add(field, message);
+    ApiError::Invalid(errors)
+}
+
+/// Checks an item of type `child` may be placed in an item of type `parent`
+pub fn check(
+    child: ItemType,
+    parent: Option<ItemType>,
+) -> Result<(), ApiError> {
+    let child_name = ItemTypeNames::from_type(child).ok_or_else(|| {
+        ApiError::Unprocessable(format!("Unknown item type {}.", child))
+    })?;
+    let parent_name = match parent {
+        Some(parent) => {
+            Some(ItemTypeNames::from_type(parent).ok_or_else(|| {
+                invalid("parent_type", format!("{} is unknown.", parent))
+            })?)
+        }
+        None => None,
+    };
+
+    let allowed = allowed_parents(child_name);
+    if allowed.contains(&parent_name) {
+        return Ok(());
+    }
+
+    let allowed = allowed
+        .iter()
+        .map(|parent| describe(*parent))
+        .collect::<Vec<_>>()
+        .join(" or ");
+    Err(invalid(
+        "parent_id",
+        format!(
+            "a {} can only be placed in {}, not in {}.",
+            child_name.name(),
+            allowed,
+            describe(parent_name)
+        ),
+    ))
+}
+
+/// Checks an item of type `child` may be placed in the parent, and returns
+/// the type of the parent. The parent has to belong to the user.
+pub fn parent_type(
+    child: ItemType,
+    parent_id: Option<Uuid>,
+    user: &User,
+    conn: &PgConnection,
+) -> Result<Option<ItemType>, ApiError> {
+    let parent_type = match parent_id {
+        Some(parent_id) => {
+            Item::authorize(parent_id, user, conn)?;
+
+            let parent_type = items::table
+                .filter(items::id.eq(parent_id))
+                .select(items::item_type)
+                .first::<ItemType>(conn)?;
+            Some(parent_type)
+        }
+        None => None,
+    };
+
+    check(child, parent_type)?;
+    Ok(parent_type)
+}
+
+/// Checks the item may be moved to the parent, and returns the type of the
+/// parent
+pub fn reparent(
+    id: Uuid,
+    item_type: ItemType,
+    parent_id: Option<Uuid>,
+    user: &User,
+    conn: &PgConnection,
+) -> Result<Option<ItemType>, ApiError> {
+    let parent_type = parent_type(item_type, parent_id, user, conn)?;
+    if let Some(parent_id) = parent_id {
+        check_cycle(id, parent_id, conn)?;
+    }
+
+    Ok(parent_type)
+}
+
+/// Fails when moving the item to the parent would place it inside itself
+pub fn check_cycle(
+    id: Uuid,
+    parent_id: Uuid,
+    conn: &PgConnection,
+) -> Result<(), ApiError> {
+    let mut visited = HashSet::new();
+    let mut ancestor = Some(parent_id);
+
+    while let Some(current) = ancestor {
+        if current == id {
+            return Err(invalid(
+                "parent_id",
+                "an item can't be placed in itself or one of its children."
+                    .into(),
+            ));
+        }
+        if !visited.insert(current) {
+            break;
+        }
+
+        ancestor = items::table
+            .filter(items::id.eq(current))
+            .select(items::parent_id)
+            .first::<Option<Uuid>>(conn)
+            .optional()?
+            .flatten();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use super::check;
+    use crate::items::{
+        item::Item, page::Page, todo::Todo, todo_item::TodoItem,
+        ItemTypeNames::*,
+    };
+    use crate::users::User;
+    use crate::utils::{error::ApiError, validator};
+
+    #[test]
+    fn test_containment_rules() {
+        assert!(check(Page as i16, None).is_ok());
+        assert!(check(Page as i16, Some(Page as i16)).is_ok());
+        assert!(check(Todo as i16, Some(Page as i16)).is_ok());
+        assert!(check(TodoItem as i16, Some(Todo as i16)).is_ok());
+        assert!(check(TextField as i16, Some(Page as i16)).is_ok());
+
+        assert!(check(Page as i16, Some(Todo as i16)).is_err());
+        assert!(check(Todo as i16, None).is_err());
+        assert!(check(TodoItem as i16, Some(Page as i16)).is_err());
+
+        match check(TodoItem as i16, Some(TextField as i16)) {
+            Err(ApiError::Invalid(errors)) => assert_eq!(
+                errors.get("parent_id").unwrap(),
+                ["a todo item can only be placed in a todo, not in a text \
+                  field."]
+            ),
+            _ => panic!("a todo item can't be placed in a text field"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_invalid_parents() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(Page::routes)
+                            .configure(Todo::routes)
+                            .configure(TodoItem::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "nester");
+
+                let request = TestRequest::post()
+                    .uri("/pages")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "title": "diary" }))
+                    .to_request();
+                let page: Value = read_response_json(&mut app, request).await;
+                let page_id = &page["item"]["id"];
+
+                let request = TestRequest::post()
+                    .uri("/todos")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({
+                        "title": "chores",
+                        "page_id": page_id,
+                        "coord_x": 0,
+                        "coord_y": 0,
+                    }))
+                    .to_request();
+                let todo: Value = read_response_json(&mut app, request).await;
+                let todo_id = &todo["item"]["id"];
+
+                // A todo item can't be placed directly in a page
+                let request = TestRequest::post()
+                    .uri("/todo_items")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({
+                        "title": "dishes",
+                        "todo_id": page_id,
+                        "is_checked": false,
+                    }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                // Nor can a page be moved into a todo
+                let request = TestRequest::patch()
+                    .uri(&format!("/items/{}", page_id.as_str().unwrap()))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "parent_id": todo_id }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+                let body: Value =
+                    serde_json::from_slice(&actix_web::test::read_body(resp).await)?;
+                assert_eq!(
+                    body["errors"]["parent_id"],
+                    json!(["a page can only be placed in the top level or \
+                            a page, not in a todo."])
+                );
+
+                // The parent type is taken from the parent itself
+                let request = TestRequest::patch()
+                    .uri(&format!("/items/{}", todo_id.as_str().unwrap()))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "parent_id": page_id, "parent_type": 200 }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                let request = TestRequest::patch()
+                    .uri(&format!("/items/{}", todo_id.as_str().unwrap()))
+                    .header(header::AUTHORIZATION, bearer)
+                    .set_json(&json!({ "parent_id": page_id }))
+                    .to_request();
+                let moved: Value = read_response_json(&mut app, request).await;
+                assert_eq!(moved["parent_type"], json!(100));
+
+                Ok(())
+            }
+        }
+    }
+}
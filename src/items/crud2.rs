@@ -1,6 +1,57 @@
 use crate::items::item::Item;
 
+/// Implements `find_all` of [`raw_crud::Find`](raw_crud/trait.Find.html)
+/// for a model, given the table of its subtype
+macro_rules! find_all {
+    ($table:ident) => {
+        fn find_all(
+            ids: &[uuid::Uuid],
+            conn: &diesel::pg::PgConnection,
+        ) -> diesel::QueryResult<Vec<(uuid::Uuid, Self)>> {
+            use diesel::prelude::*;
+            use $crate::items::TypeMarker;
+            use $crate::schema::$table;
+
+            $table::table
+                .filter($table::id.eq_any(ids))
+                .filter($table::item_type.eq(Self::TYPE as i16))
+                .load::<Self>(conn)
+                .map(|models| {
+                    models.into_iter().map(|model| (model.id, model)).collect()
+                })
+        }
+    };
+}
+
+/// Implements `duplicate` of
+/// [`raw_crud::Duplicate`](raw_crud/trait.Duplicate.html) for a model,
+/// given the table of its subtype
+macro_rules! duplicate {
+    ($table:ident) => {
+        fn duplicate(
+            ids: &std::collections::HashMap<uuid::Uuid, uuid::Uuid>,
+            conn: &diesel::pg::PgConnection,
+        ) -> diesel::QueryResult<()> {
+            use diesel::prelude::*;
+            use $crate::items::crud2::raw_crud::Find;
+
+            let keys = ids.keys().copied().collect::<Vec<_>>();
+            let copies = Self::find_all(&keys, conn)?
+                .into_iter()
+                .map(|(id, model)| Self { id: ids[&id], ..model })
+                .collect::<Vec<_>>();
+
+            diesel::insert_into($crate::schema::$table::table)
+                .values(&copies)
+                .execute(conn)
+                .map(drop)
+        }
+    };
+}
+
 pub(crate) mod raw_crud {
+    use std::collections::HashMap;
+
     use diesel::pg::PgConnection;
     use diesel::result::QueryResult;
     use uuid::Uuid;
@@ -11,6 +62,13 @@ pub(crate) mod raw_crud {
 
     pub trait Find: Sized {
         fn find(key: Uuid, conn: &PgConnection) -> QueryResult<Self>;
+
+        /// Finds every model with one of the ids in a single query,
+        /// paired with its id and in no particular order
+        fn find_all(
+            keys: &[Uuid],
+            conn: &PgConnection,
+        ) -> QueryResult<Vec<(Uuid, Self)>>;
     }
 
     pub trait Update<U>: Sized {
@@ -24,6 +82,15 @@ pub(crate) mod raw_crud {
     pub trait Delete: Sized {
         fn delete(id: Uuid, conn: &PgConnection) -> QueryResult<()>;
     }
+
+    pub trait Duplicate: Sized {
+        /// Copies every model with one of the ids in a single query, each
+        /// under the id it's mapped to
+        fn duplicate(
+            ids: &HashMap<Uuid, Uuid>,
+            conn: &PgConnection,
+        ) -> QueryResult<()>;
+    }
 }
 
 pub trait ModelFromPartial<P> {
@@ -43,13 +110,21 @@ where
     }
 }
 
-pub(self) mod intermediate {
-    use diesel::{pg::PgConnection, QueryResult};
+pub(super) mod intermediate {
+    use diesel::{pg::PgConnection, Connection};
     use uuid::Uuid;
 
-    use crate::items::item::Item;
-    use crate::items::{ItemLike, Items, TypeMarker, ViewItem};
+    use crate::items::{
+        containment, item::Item, ordering, registry::Subtype,
+        revision::Revision, ItemLike, TypeMarker, ViewItem,
+    };
     use crate::users::user::User;
+    use crate::utils::{
+        error::ApiError,
+        etag::{Conditions, Versioned},
+        patch::{Changes, Patch},
+        policy::Policy,
+    };
 
     use super::raw_crud;
     use super::IntoModel;
@@ -58,59 +133,98 @@ pub(self) mod intermediate {
         create: impl IntoModel<M> + ItemLike,
         user: User,
         conn: &PgConnection,
-    ) -> QueryResult<ViewItem>
+    ) -> Result<ViewItem, ApiError>
     where
-        M: raw_crud::Create + Into<Items>,
+        M: raw_crud::Create + TypeMarker + serde::Serialize,
     {
         let mut item = create.as_item();
-        let model = create.into_model(&item);
+        item.parent_type = containment::parent_type(
+            item.item_type,
+            item.parent_id,
+            &user,
+            conn,
+        )?;
+        item.position = ordering::position(
+            item.parent_id,
+            create.placement(),
+            None,
+            &user,
+            conn,
+        )?;
 
+        let model = create.into_model(&item);
         item.owner_id = user.id;
 
         item.create(conn)?;
-        model.create(conn).map(|model| ViewItem::make(item, model.into()))
+        model
+            .create(conn)
+            .map(|model| ViewItem::make(item, Subtype::of(model)))
+            .map_err(ApiError::from)
     }
 
+    /// Patches the model, and keeps the version it replaces as a revision
     pub fn update<M, U>(
         id: Uuid,
-        update: U,
+        patch: Patch,
+        conditions: &Conditions,
         user: User,
         conn: &PgConnection,
-    ) -> QueryResult<M>
+    ) -> Result<Versioned<M>, ApiError>
     where
-        M: raw_crud::Update<U> + TypeMarker,
+        M: raw_crud::Update<U>
+            + raw_crud::Find
+            + TypeMarker
+            + serde::Serialize
+            + Policy,
+        U: Changes,
     {
-        if Item::has_owner::<M>(id, user.id, conn) {
-            M::update(id, update, conn)
-        } else {
-            Err(diesel::result::Error::NotFound)
-        }
+        M::authorize(id, &user, conn)?;
+
+        conn.transaction(|| {
+            conditions.check(Item::lock_version(id, conn)?)?;
+            let current = M::find(id, conn)?;
+            let update = patch.into_form::<U>(&current)?;
+
+            let value = if update.is_empty() {
+                current
+            } else {
+                Revision::save(id, &current, &user, conn)?;
+                M::update(id, update, conn)?
+            };
+            Ok(Versioned { value, version: Item::version(id, conn)? })
+        })
     }
 
-    pub fn find<M>(id: Uuid, user: User, conn: &PgConnection) -> QueryResult<M>
+    pub fn find<M>(
+        id: Uuid,
+        user: User,
+        conn: &PgConnection,
+    ) -> Result<Versioned<M>, ApiError>
     where
-        M: raw_crud::Find + TypeMarker,
+        M: raw_crud::Find + Policy,
     {
-        if Item::has_owner::<M>(id, user.id, conn) {
-            M::find(id, conn)
-        } else {
-            Err(diesel::result::Error::NotFound)
-        }
+        M::authorize(id, &user, conn)?;
+
+        // The version is loaded first, so it's never newer than the model
+        let version = Item::version(id, conn)?;
+        Ok(Versioned { value: M::find(id, conn)?, version })
     }
 
     pub fn delete<M>(
         id: Uuid,
+        conditions: &Conditions,
         user: User,
         conn: &PgConnection,
-    ) -> QueryResult<()>
+    ) -> Result<(), ApiError>
     where
-        M: raw_crud::Delete + TypeMarker,
+        M: raw_crud::Delete + Policy,
     {
-        if Item::has_owner::<M>(id, user.id, conn) {
-            M::delete(id, conn)
-        } else {
-            Err(diesel::result::Error::NotFound)
-        }
+        M::authorize(id, &user, conn)?;
+
+        conn.transaction(|| {
+            conditions.check(Item::lock_version(id, conn)?)?;
+            M::delete(id, conn).map_err(ApiError::from)
+        })
     }
 }
 
@@ -118,12 +232,16 @@ pub mod crud2http {
     use actix_web::{Error, HttpResponse};
     use uuid::Uuid;
 
-    use crate::items::Items;
     use crate::{
         database::exec_on_pool,
         items::{ItemLike, TypeMarker},
         users::user::User,
-        utils::responsable::Responsable,
+        utils::{
+            etag::{self, Conditions},
+            patch::Patch,
+            policy::Policy,
+            responsable::Responsable,
+        },
         DbPool,
     };
 
@@ -136,7 +254,11 @@ pub mod crud2http {
     ) -> Result<HttpResponse, Error>
     where
         N: 'static + Send + IntoModel<M> + ItemLike,
-        M: 'static + Send + super::raw_crud::Create + TypeMarker + Into<Items>,
+        M: 'static
+            + Send
+            + super::raw_crud::Create
+            + TypeMarker
+            + serde::Serialize,
     {
         exec_on_pool(pool, move |conn| intermediate::create(create, user, conn))
             .await
@@ -145,52 +267,57 @@ pub mod crud2http {
 
     pub async fn find<M>(
         id: Uuid,
+        conditions: Conditions,
         user: User,
         pool: &DbPool,
     ) -> Result<HttpResponse, Error>
     where
-        M: 'static
-            + Send
-            + super::raw_crud::Find
-            + serde::Serialize
-            + TypeMarker,
+        M: 'static + Send + super::raw_crud::Find + serde::Serialize + Policy,
     {
-        exec_on_pool(pool, move |conn| intermediate::find::<M>(id, user, conn))
-            .await
-            .into_response()
+        let result = exec_on_pool(pool, move |conn| {
+            intermediate::find::<M>(id, user, conn)
+        })
+        .await;
+
+        etag::respond_if_modified(result, &conditions)
     }
 
     pub async fn update<M, U>(
         id: Uuid,
-        update: U,
+        patch: Patch,
+        conditions: Conditions,
         user: User,
         pool: &DbPool,
     ) -> Result<HttpResponse, Error>
     where
-        U: 'static + Send,
+        U: 'static + Send + crate::utils::patch::Changes,
         M: 'static
             + Send
             + super::raw_crud::Update<U>
+            + super::raw_crud::Find
+            + TypeMarker
             + serde::Serialize
-            + TypeMarker,
+            + Policy,
     {
-        exec_on_pool(pool, move |conn| {
-            intermediate::update::<M, U>(id, update, user, conn)
+        let result = exec_on_pool(pool, move |conn| {
+            intermediate::update::<M, U>(id, patch, &conditions, user, conn)
         })
-        .await
-        .into_response()
+        .await;
+
+        etag::respond(result)
     }
 
     pub async fn delete<M>(
         id: Uuid,
+        conditions: Conditions,
         user: User,
         pool: &DbPool,
     ) -> Result<HttpResponse, Error>
     where
-        M: 'static + Send + super::raw_crud::Delete + TypeMarker,
+        M: 'static + Send + super::raw_crud::Delete + Policy,
     {
         exec_on_pool(pool, move |conn| {
-            intermediate::delete::<M>(id, user, conn)
+            intermediate::delete::<M>(id, &conditions, user, conn)
         })
         .await
         .into_response()
@@ -0,0 +1,269 @@
+//! Copying a page with everything on it.
+//!
+//! The copy gets new ids throughout, but keeps how the items are nested,
+//! where they are and how they're tagged. It's placed right after the page
+//! it was copied from. Copies of templates are regular pages.
+
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::schema::{items, pages, tags_items};
+use crate::tags::tags_items::TagsItem;
+use crate::users::User;
+use crate::utils::{error::ApiError, policy::Policy};
+
+use super::item::Item;
+use super::ordering::{self, Placement};
+use super::page::Page;
+use super::reex_diesel::*;
+use super::{registry, ItemType, TypeMarker, ViewItem};
+
+/// The item and everything inside it that isn't in the trash, parents
+/// before their children
+fn subtree(root: Item, conn: &PgConnection) -> QueryResult<Vec<Item>> {
+    let mut parents = vec![root.id];
+    let mut subtree = vec![root];
+
+    while !parents.is_empty() {
+        let children = items::table
+            .filter(items::parent_id.eq_any(&parents))
+            .filter(items::deleted_at.is_null())
+            .load::<Item>(conn)?;
+
+        parents = children.iter().map(|item| item.id).collect();
+        subtree.extend(children);
+    }
+
+    Ok(subtree)
+}
+
+/// Copies the page and everything inside it, and places the copy of the
+/// page at the position. None of the copied pages are templates. Returns
+/// the copies, the copy of the page first.
+pub(super) fn copy(
+    page: Item,
+    position: String,
+    conn: &PgConnection,
+) -> Result<Vec<Item>, ApiError> {
+    let originals = subtree(page, conn)?;
+
+    let ids = originals
+        .iter()
+        .map(|item| (item.id, Uuid::new_v4()))
+        .collect::<HashMap<_, _>>();
+    let now = Utc::now();
+    let mut copies = originals
+        .iter()
+        .map(|item| Item {
+            id: ids[&item.id],
+            parent_id: item
+                .parent_id
+                .map(|parent_id| *ids.get(&parent_id).unwrap_or(&parent_id)),
+            created_at: now,
+            updated_at: now,
+            version: 1,
+            ..item.clone()
+        })
+        .collect::<Vec<_>>();
+    copies[0].position = position;
+
+    diesel::insert_into(items::table).values(&copies).execute(conn)?;
+    let item_types =
+        originals.iter().map(|item| item.item_type).collect::<BTreeSet<_>>();
+    for item_type in item_types {
+        let registration = registry::lookup(item_type).ok_or_else(|| {
+            log::error!("items have unknown type {}", item_type);
+            ApiError::Internal
+        })?;
+        registration.duplicate(&ids, conn)?;
+    }
+
+    let keys = ids.keys().copied().collect::<Vec<_>>();
+    let tags = tags_items::table
+        .filter(tags_items::item_id.eq_any(&keys))
+        .load::<TagsItem>(conn)?
+        .into_iter()
+        .map(|tag| TagsItem { item_id: ids[&tag.item_id], ..tag })
+        .collect::<Vec<_>>();
+    diesel::insert_into(tags_items::table).values(&tags).execute(conn)?;
+
+    let copied = ids.values().collect::<Vec<_>>();
+    diesel::update(pages::table.filter(pages::id.eq_any(copied)))
+        .set(pages::is_template.eq(false))
+        .execute(conn)?;
+
+    Ok(copies)
+}
+
+/// Copies the page and everything on it in one go
+pub fn duplicate_page(
+    id: Uuid,
+    user: &User,
+    conn: &PgConnection,
+) -> Result<ViewItem, ApiError> {
+    Page::authorize(id, user, conn)?;
+
+    conn.transaction(|| {
+        let page = items::table
+            .filter(items::id.eq(id))
+            .filter(items::item_type.eq(Page::TYPE as ItemType))
+            .first::<Item>(conn)?;
+        let position = ordering::position(
+            page.parent_id,
+            Placement { before: None, after: Some(page.id) },
+            None,
+            user,
+            conn,
+        )?;
+
+        let mut copies = copy(page, position, conn)?;
+        copies.truncate(1);
+        Item::view_all(copies, conn)?.pop().ok_or(ApiError::Internal)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::header,
+        test::{read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use crate::items::{item::Item, registry};
+    use crate::tags::tag::Tag;
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[actix_rt::test]
+    async fn test_duplicate_page() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(Tag::routes)
+                            .configure(registry::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "planner");
+
+                macro_rules! send {
+                    ($request:expr, $body:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            $request
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .set_json(&$body)
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+                macro_rules! list {
+                    ($parent:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            TestRequest::get()
+                                .uri(&format!("/items?parent_id={}", $parent))
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .to_request(),
+                        )
+                        .await["items"]
+                            .as_array()
+                            .unwrap()
+                            .clone()
+                    };
+                }
+
+                let page = send!(
+                    TestRequest::post().uri("/pages"),
+                    json!({ "title": "week 27" })
+                );
+                let page_id = page["item"]["id"].as_str().unwrap().to_owned();
+                let todo = send!(
+                    TestRequest::post().uri("/todos"),
+                    json!({
+                        "title": "groceries",
+                        "page_id": page_id,
+                        "coord_x": 3,
+                        "coord_y": 4,
+                    })
+                );
+                let todo_id = todo["item"]["id"].as_str().unwrap().to_owned();
+                send!(
+                    TestRequest::post().uri("/todo_items"),
+                    json!({
+                        "title": "milk",
+                        "todo_id": todo_id,
+                        "is_checked": true,
+                    })
+                );
+                let tag = send!(
+                    TestRequest::post().uri("/tags"),
+                    json!({ "name": "weekly", "color": "0x00FF00" })
+                );
+                let tag_uri =
+                    format!("/tags/{}/items", tag["id"].as_str().unwrap());
+                send!(
+                    TestRequest::patch().uri(&tag_uri),
+                    json!([{ "id": todo_id, "item_type": 200 }])
+                );
+
+                let copy = send!(
+                    TestRequest::post()
+                        .uri(&format!("/pages/{}/duplicate", page_id)),
+                    json!({})
+                );
+                let copy_id = copy["item"]["id"].as_str().unwrap().to_owned();
+                assert_ne!(copy_id, page_id);
+                assert_eq!(copy["subtype"]["Page"]["title"], "week 27");
+
+                let todos = list!(copy_id);
+                assert_eq!(todos.len(), 1);
+                let copied_todo = &todos[0];
+                assert_ne!(copied_todo["item"]["id"], todo_id.as_str());
+                assert_eq!(copied_todo["subtype"]["Todo"]["coord_x"], 3);
+                assert_eq!(copied_todo["subtype"]["Todo"]["coord_y"], 4);
+
+                let todo_items = list!(copied_todo["item"]["id"].as_str().unwrap());
+                assert_eq!(todo_items.len(), 1);
+                assert_eq!(todo_items[0]["subtype"]["TodoItem"]["title"], "milk");
+                assert_eq!(todo_items[0]["subtype"]["TodoItem"]["is_checked"], true);
+
+                // The copy is tagged like the original
+                let tags: Value = read_response_json(
+                    &mut app,
+                    TestRequest::get()
+                        .uri("/tags")
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .to_request(),
+                )
+                .await;
+                let tagged = tags[0]["items"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|item| item[0].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>();
+                assert!(tagged.contains(&todo_id));
+                assert!(tagged.iter().any(|id| copied_todo["item"]["id"] == id.as_str()));
+
+                // The original is left alone
+                assert_eq!(list!(page_id).len(), 1);
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Pages inside pages.
+//!
+//! Pages can be nested as deep as needed. The whole subtree of a page is
+//! loaded as a tree, and the breadcrumbs of a page lead from the top level
+//! down to it. Both are found with a recursive query, instead of one query
+//! per level. Moving a page goes through `POST /api/items/{id}/move`, which
+//! keeps a page from being moved into one of its own sub-pages.
+
+use std::collections::HashMap;
+
+use diesel::sql_types::Uuid as SqlUuid;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::schema::items;
+use crate::users::User;
+use crate::utils::{error::ApiError, policy::Policy};
+
+use super::item::Item;
+use super::page::Page;
+use super::reex_diesel::*;
+use super::ViewItem;
+
+#[derive(QueryableByName)]
+struct Id {
+    #[sql_type = "SqlUuid"]
+    id: Uuid,
+}
+
+/// An item with everything inside it, in order
+#[derive(Serialize)]
+pub struct Node {
+    #[serde(flatten)]
+    item: ViewItem,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn build(
+        item: ViewItem,
+        children: &mut HashMap<Uuid, Vec<ViewItem>>,
+    ) -> Self {
+        let children = children
+            .remove(&item.id())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|child| Node::build(child, children))
+            .collect();
+
+        Node { item, children }
+    }
+}
+
+/// Loads the items with the ids, in their order within their parents
+fn load(ids: Vec<Id>, conn: &PgConnection) -> Result<Vec<ViewItem>, ApiError> {
+    let ids = ids.into_iter().map(|Id { id }| id).collect::<Vec<_>>();
+    let items = items::table
+        .filter(items::id.eq_any(ids))
+        .order((items::position, items::id))
+        .load::<Item>(conn)?;
+
+    Item::view_all(items, conn)
+}
+
+/// The page with everything inside it that isn't in the trash
+pub fn tree(
+    id: Uuid,
+    user: &User,
+    conn: &PgConnection,
+) -> Result<Node, ApiError> {
+    Page::authorize(id, user, conn)?;
+
+    let ids = diesel::sql_query(
+        "WITH RECURSIVE subtree AS (
+             SELECT id FROM items WHERE id = $1
+             UNION
+             SELECT items.id FROM items
+             JOIN subtree ON items.parent_id = subtree.id
+             WHERE items.deleted_at IS NULL
+         )
+         SELECT id FROM subtree",
+    )
+    .bind::<SqlUuid, _>(id)
+    .load::<Id>(conn)?;
+
+    let mut root = None;
+    let mut children = HashMap::<Uuid, Vec<ViewItem>>::new();
+    for item in load(ids, conn)? {
+        if item.id() == id {
+            root = Some(item);
+        } else if let Some(parent_id) = item.parent_id() {
+            children.entry(parent_id).or_default().push(item);
+        }
+    }
+    let root = root.ok_or(ApiError::NotFound)?;
+
+    Ok(Node::build(root, &mut children))
+}
+
+/// The pages from the top level down to the page, the page itself last
+pub fn breadcrumbs(
+    id: Uuid,
+    user: &User,
+    conn: &PgConnection,
+) -> Result<Vec<ViewItem>, ApiError> {
+    Page::authorize(id, user, conn)?;
+
+    let ids = diesel::sql_query(
+        "WITH RECURSIVE ancestors AS (
+             SELECT id, parent_id FROM items WHERE id = $1
+             UNION
+             SELECT items.id, items.parent_id FROM items
+             JOIN ancestors ON items.id = ancestors.parent_id
+         )
+         SELECT id FROM ancestors",
+    )
+    .bind::<SqlUuid, _>(id)
+    .load::<Id>(conn)?;
+
+    let mut pages = load(ids, conn)?
+        .into_iter()
+        .map(|page| (page.id(), page))
+        .collect::<HashMap<_, _>>();
+    let mut breadcrumbs = Vec::new();
+    let mut current = Some(id);
+    while let Some(page) = current.and_then(|id| pages.remove(&id)) {
+        current = page.parent_id();
+        breadcrumbs.push(page);
+    }
+    breadcrumbs.reverse();
+
+    Ok(breadcrumbs)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use crate::items::{item::Item, registry};
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[actix_rt::test]
+    async fn test_nested_pages() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(registry::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "nester");
+
+                macro_rules! get {
+                    ($uri:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            TestRequest::get()
+                                .uri(&$uri)
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+                macro_rules! create {
+                    ($uri:expr, $body:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            TestRequest::post()
+                                .uri($uri)
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .set_json(&$body)
+                                .to_request(),
+                        )
+                        .await["item"]["id"]
+                            .as_str()
+                            .unwrap()
+                            .to_owned()
+                    };
+                }
+                macro_rules! titles {
+                    ($pages:expr) => {
+                        $pages
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|page| page["subtype"]["Page"]["title"].clone())
+                            .collect::<Vec<_>>()
+                    };
+                }
+
+                let year = create!("/pages", json!({ "title": "2020" }));
+                let june = create!(
+                    "/pages",
+                    json!({ "title": "June", "parent_id": year })
+                );
+                let july = create!(
+                    "/pages",
+                    json!({ "title": "July", "parent_id": year })
+                );
+                let week = create!(
+                    "/pages",
+                    json!({ "title": "Week 26", "parent_id": june })
+                );
+                let _todo = create!(
+                    "/todos",
+                    json!({
+                        "title": "chores",
+                        "page_id": week,
+                        "coord_x": 0,
+                        "coord_y": 0,
+                    })
+                );
+
+                let tree = get!(format!("/pages/{}/tree", year));
+                assert_eq!(tree["subtype"]["Page"]["title"], "2020");
+                assert_eq!(titles!(tree["children"]), ["June", "July"]);
+                let june_node = &tree["children"][0];
+                assert_eq!(titles!(june_node["children"]), ["Week 26"]);
+                let week_node = &june_node["children"][0];
+                assert_eq!(week_node["children"][0]["subtype"]["Todo"]["title"], "chores");
+                assert_eq!(week_node["children"][0]["children"], json!([]));
+
+                let breadcrumbs = get!(format!("/pages/{}/breadcrumbs", week));
+                assert_eq!(titles!(breadcrumbs), ["2020", "June", "Week 26"]);
+
+                // A page can't be moved into one of its sub-pages
+                let request = TestRequest::post()
+                    .uri(&format!("/items/{}/move", year))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "parent_id": week }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                let request = TestRequest::post()
+                    .uri(&format!("/items/{}/move", week))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "parent_id": july }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                let breadcrumbs = get!(format!("/pages/{}/breadcrumbs", week));
+                assert_eq!(titles!(breadcrumbs), ["2020", "July", "Week 26"]);
+
+                let request = TestRequest::post()
+                    .uri(&format!("/items/{}/move", week))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "parent_id": null }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                let breadcrumbs = get!(format!("/pages/{}/breadcrumbs", week));
+                assert_eq!(titles!(breadcrumbs), ["Week 26"]);
+                let tree = get!(format!("/pages/{}/tree", year));
+                assert_eq!(tree["children"][1]["children"], json!([]));
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,21 +1,30 @@
+use std::collections::{BTreeMap, HashMap};
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::items::page::Page;
-use crate::items::text_field::TextField;
-use crate::items::todo::Todo;
-use crate::items::todo_item::TodoItem;
-use crate::items::{Items, ViewItem};
+use crate::items::{
+    listing::{ItemsQuery, Paginated},
+    registry, template,
+    trash::Trash,
+    ViewItem,
+};
 use crate::schema::items;
 use crate::users::user::User;
+use crate::utils::{
+    error::{ApiError, FieldErrors},
+    etag::Conditions,
+    patch::{nullable, Changes, Patch},
+    policy::Policy,
+};
 
-use super::crud2::raw_crud::Find;
+use super::ordering::{self, Placement};
 use super::reex_diesel::*;
-use super::{ItemLike, ItemType};
+use super::{containment, ItemLike, ItemType};
 
 #[derive(
-    Identifiable, Associations, Insertable, Queryable, Copy, Clone, Serialize,
+    Identifiable, Associations, Insertable, Queryable, Clone, Serialize,
 )]
 #[belongs_to(User, foreign_key = "owner_id")]
 pub struct Item {
@@ -27,6 +36,9 @@ pub struct Item {
     pub(crate) updated_at: DateTime<Utc>,
     pub(crate) owner_id: Uuid,
     pub(crate) due_date: Option<DateTime<Utc>>,
+    pub(crate) deleted_at: Option<DateTime<Utc>>,
+    pub(crate) version: i32,
+    pub(crate) position: String,
 }
 
 impl ItemLike for Item {
@@ -47,7 +59,7 @@ impl ItemLike for Item {
     }
 
     fn as_item(&self) -> Item {
-        *self
+        self.clone()
     }
 }
 
@@ -62,39 +74,38 @@ impl Default for Item {
             updated_at: Utc::now(),
             owner_id: Uuid::default(),
             due_date: None,
+            deleted_at: None,
+            version: 1,
+            position: String::new(),
         }
     }
 }
 
 impl Item {
-    pub fn has_owner<T: super::TypeMarker>(
-        id: Uuid,
-        owner: Uuid,
-        conn: &PgConnection,
-    ) -> bool {
-        use diesel::dsl::{exists, select};
-
-        select(exists(
-            items::table
-                .filter(items::owner_id.eq(owner))
-                .filter(items::id.eq(id))
-                .filter(items::item_type.eq(T::TYPE as i16)),
-        ))
-        .get_result(conn)
-        .unwrap_or(false)
-    }
-
+    /// Moves the item to the [`Trash`](../trash/struct.Trash.html)
     pub(super) fn delete<T>(id: Uuid, conn: &PgConnection) -> QueryResult<()>
     where
         T: super::TypeMarker,
     {
-        diesel::delete(
-            items::table
-                .filter(items::columns::id.eq(id))
-                .filter(items::item_type.eq(T::TYPE as i16)),
-        )
-        .get_result::<Item>(conn)
-        .map(drop)
+        Trash::delete(id, T::TYPE as ItemType, conn)
+    }
+
+    /// The version of the item, which is its ETag
+    pub(super) fn version(id: Uuid, conn: &PgConnection) -> QueryResult<i32> {
+        items::table.filter(items::id.eq(id)).select(items::version).first(conn)
+    }
+
+    /// Like [`version`](#method.version), and keeps the item from being
+    /// changed by others until the transaction ends.
+    pub(super) fn lock_version(
+        id: Uuid,
+        conn: &PgConnection,
+    ) -> QueryResult<i32> {
+        items::table
+            .filter(items::id.eq(id))
+            .select(items::version)
+            .for_update()
+            .first(conn)
     }
 
     pub(super) fn create(&self, conn: &PgConnection) -> QueryResult<Self> {
@@ -102,122 +113,405 @@ impl Item {
     }
 
     pub(super) fn update(
-        id: &Uuid,
-        form: &UpdateItemRequest,
+        id: Uuid,
+        patch: Patch,
+        conditions: &Conditions,
+        user: &User,
         conn: &PgConnection,
-    ) -> QueryResult<Self> {
-        diesel::update(items::table.filter(items::id.eq(id)))
-            .set(form)
-            .get_result(conn)
+    ) -> Result<Self, ApiError> {
+        Item::authorize(id, user, conn)?;
+
+        conn.transaction(|| {
+            conditions.check(Item::lock_version(id, conn)?)?;
+            let current =
+                items::table.filter(items::id.eq(id)).first::<Item>(conn)?;
+            let mut form = patch.into_form::<UpdateItemRequest>(&current)?;
+
+            match form.parent_id {
+                Some(parent_id) => {
+                    let parent_type = containment::reparent(
+                        id,
+                        current.item_type,
+                        parent_id,
+                        user,
+                        conn,
+                    )?;
+                    if form.parent_type.is_some()
+                        && form.parent_type != Some(parent_type)
+                    {
+                        let mut errors = FieldErrors::default();
+                        errors.add(
+                            "parent_type",
+                            "doesn't match the type of the parent.",
+                        );
+                        return Err(ApiError::Invalid(errors));
+                    }
+
+                    form.parent_type = Some(parent_type);
+                    if parent_id != current.parent_id {
+                        form.position = Some(ordering::position(
+                            parent_id,
+                            Placement::default(),
+                            Some(id),
+                            user,
+                            conn,
+                        )?);
+                    }
+                }
+                None if form.parent_type.is_some() => {
+                    let mut errors = FieldErrors::default();
+                    errors
+                        .add("parent_type", "can only be set with parent_id.");
+                    return Err(ApiError::Invalid(errors));
+                }
+                None => {}
+            }
+
+            if form.is_empty() {
+                return Ok(current);
+            }
+
+            diesel::update(items::table.filter(items::id.eq(id)))
+                .set(&form)
+                .get_result(conn)
+                .map_err(ApiError::from)
+        })
     }
 
     pub(super) fn find(
-        pid: &Option<Uuid>,
+        filter: &ItemsQuery,
         user: User,
         conn: &PgConnection,
-    ) -> QueryResult<Vec<ViewItem>> {
-        let mut query =
-            items::table.into_boxed().filter(items::owner_id.eq(user.id));
-        if pid.is_some() {
-            query = query.filter(items::parent_id.eq(pid.unwrap()));
+    ) -> Result<Paginated<ViewItem>, ApiError> {
+        let mut query = items::table
+            .into_boxed()
+            .filter(items::owner_id.eq(user.id))
+            .filter(items::deleted_at.is_null());
+        if !filter.templates {
+            query =
+                query.filter(diesel::dsl::not(template::on_templates(&user)));
         }
-        query.load::<Item>(conn).map(|items| {
-            items
-                .into_iter()
-                .map(|item| match item.item_type {
-                    200 => ViewItem::make(
-                        item,
-                        Items::Todo(
-                            Todo::find(item.id, &conn)
-                                .expect("Failed to load todo"),
-                        ),
-                    ),
-                    210 => ViewItem::make(
-                        item,
-                        Items::TodoItem(
-                            TodoItem::find(item.id, &conn)
-                                .expect("Failed to load todo item"),
-                        ),
-                    ),
-                    100 => ViewItem::make(
-                        item,
-                        Items::Page(
-                            Page::find(item.id, &conn)
-                                .expect("Failed to load todo item"),
-                        ),
-                    ),
-                    300 => ViewItem::make(
-                        item,
-                        Items::TextField(
-                            TextField::find(item.id, &conn)
-                                .expect("Failed to load todo item"),
-                        ),
-                    ),
-                    _ => unreachable!("Please report an error"),
-                })
-                .rev()
-                .collect()
-        })
+        let (query, limit) = filter.apply(query)?;
+        let (items, next_cursor) =
+            filter.paginate(query.load::<Item>(conn)?, limit);
+
+        Ok(Paginated { items: Item::view_all(items, conn)?, next_cursor })
+    }
+
+    /// Loads the subtypes of the items, with one query per type of item
+    /// instead of one per item.
+    pub(super) fn view_all(
+        items: Vec<Item>,
+        conn: &PgConnection,
+    ) -> Result<Vec<ViewItem>, ApiError> {
+        let mut ids_by_type = BTreeMap::<ItemType, Vec<Uuid>>::new();
+        for item in &items {
+            ids_by_type.entry(item.item_type).or_default().push(item.id);
+        }
+        let mut subtypes = HashMap::new();
+        for (item_type, ids) in ids_by_type {
+            let registration =
+                registry::lookup(item_type).ok_or_else(|| {
+                    log::error!("items have unknown type {}", item_type);
+                    ApiError::Internal
+                })?;
+            subtypes.extend(registration.find_all(&ids, conn)?);
+        }
+
+        items
+            .into_iter()
+            .map(|item| {
+                let subtype = subtypes.remove(&item.id).ok_or_else(|| {
+                    log::error!("item {} is missing its subtype", item.id);
+                    ApiError::Internal
+                })?;
+
+                Ok(ViewItem::make(item, subtype))
+            })
+            .collect()
     }
 }
 
 #[derive(AsChangeset, Deserialize)]
 #[table_name = "items"]
 pub struct UpdateItemRequest {
-    pub(crate) parent_id: Option<Uuid>,
-    pub(crate) parent_type: Option<ItemType>,
-    pub(crate) due_date: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) parent_id: Option<Option<Uuid>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) parent_type: Option<Option<ItemType>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) due_date: Option<Option<DateTime<Utc>>>,
+    /// Items moved to another parent go to its end
+    #[serde(skip)]
+    pub(crate) position: Option<String>,
+}
+
+impl Changes for UpdateItemRequest {
+    const NULLABLE: &'static [&'static str] =
+        &["parent_id", "parent_type", "due_date"];
+
+    fn is_empty(&self) -> bool {
+        self.parent_id.is_none()
+            && self.parent_type.is_none()
+            && self.due_date.is_none()
+            && self.position.is_none()
+    }
 }
 
 impl Item {
     pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
-        cfg.service(routes::update).service(routes::get_items);
+        cfg.service(routes::update)
+            .service(routes::move_item)
+            .service(routes::get_items)
+            .service(routes::search_items);
     }
 }
 
 mod routes {
-    use actix_web::{get, patch, web, Error, HttpRequest, HttpResponse};
-    use serde::Deserialize;
+    use actix_web::{get, patch, post, web, Error, HttpRequest, HttpResponse};
     use uuid::Uuid;
 
     use crate::{
-        database::exec_on_pool, items::item::UpdateItemRequest,
-        utils::responsable::Responsable, DbPool,
+        database::exec_on_pool,
+        items::{
+            listing::ItemsQuery,
+            ordering::{self, MoveRequest},
+            search::{self, SearchQuery},
+        },
+        users::access_token::Scope,
+        utils::{
+            authorize,
+            etag::{self, Conditions, Versioned},
+            patch::Patch,
+            responsable::Responsable,
+        },
+        DbPool,
     };
 
     use super::Item;
 
-    #[derive(Deserialize)]
-    pub struct ItemsByParentRequest {
-        parent_id: Option<Uuid>,
-    }
-
     #[get("/items")]
     pub async fn get_items(
         pool: web::Data<DbPool>,
         req: HttpRequest,
-        query: web::Query<ItemsByParentRequest>,
+        query: web::Query<ItemsQuery>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsRead)?;
 
-        exec_on_pool(&pool, move |conn| {
-            Item::find(&query.parent_id, user, &conn)
-        })
-        .await
-        .map(|item| HttpResponse::Ok().json(item))
-        .map_err(|_| HttpResponse::InternalServerError().finish().into())
+        exec_on_pool(&pool, move |conn| Item::find(&query, user, conn))
+            .await
+            .into_response()
+    }
+
+    #[get("/search")]
+    pub async fn search_items(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        query: web::Query<SearchQuery>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsRead)?;
+
+        exec_on_pool(&pool, move |conn| search::search(&query, user, conn))
+            .await
+            .into_response()
     }
 
     #[patch("/items/{id}")]
     pub async fn update(
         pool: web::Data<DbPool>,
+        req: HttpRequest,
         id: web::Path<Uuid>,
-        form: web::Json<UpdateItemRequest>,
+        body: web::Json<serde_json::Value>,
     ) -> Result<HttpResponse, Error> {
-        exec_on_pool(&pool, move |conn| {
-            Item::update(&id.into_inner(), &form, &conn)
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        let conditions = Conditions::from_request(&req);
+        let patch = Patch::from_request(&req, body.into_inner())?;
+
+        let result = exec_on_pool(&pool, move |conn| {
+            Item::update(id.into_inner(), patch, &conditions, &user, conn)
+        })
+        .await;
+
+        etag::respond(
+            result.map(|item| Versioned { version: item.version, value: item }),
+        )
+    }
+
+    #[post("/items/{id}/move")]
+    pub async fn move_item(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+        form: web::Json<MoveRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        let conditions = Conditions::from_request(&req);
+
+        let result = exec_on_pool(&pool, move |conn| {
+            ordering::move_item(
+                id.into_inner(),
+                form.into_inner(),
+                &conditions,
+                &user,
+                conn,
+            )
         })
-        .await
-        .into_response()
+        .await;
+
+        etag::respond(
+            result.map(|item| Versioned { version: item.version, value: item }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use super::Item;
+    use crate::items::registry;
+    use crate::testing;
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[actix_rt::test]
+    async fn test_list_items() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(registry::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (user, bearer) = login!(app, "lister");
+                testing::remove_templates(&user);
+
+                let page: Value = read_response_json(
+                    &mut app,
+                    TestRequest::post()
+                        .uri("/pages")
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .set_json(&json!({ "title": "diary" }))
+                        .to_request(),
+                )
+                .await;
+                let page_id = page["item"]["id"].as_str().unwrap();
+
+                let on_page = json!({
+                    "title": "chores",
+                    "text": "notes",
+                    "page_id": page_id,
+                    "coord_x": 0,
+                    "coord_y": 0,
+                });
+                for uri in &["/todos", "/todos", "/text_fields"] {
+                    let request = TestRequest::post()
+                        .uri(uri)
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .set_json(&on_page)
+                        .to_request();
+                    assert!(call_service(&mut app, request).await.status().is_success());
+                }
+                let todo: Value = read_response_json(
+                    &mut app,
+                    TestRequest::post()
+                        .uri("/todos")
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .set_json(&on_page)
+                        .to_request(),
+                )
+                .await;
+                let item: Value = read_response_json(
+                    &mut app,
+                    TestRequest::post()
+                        .uri("/todo_items")
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .set_json(&json!({
+                            "title": "dishes",
+                            "todo_id": todo["item"]["id"],
+                            "is_checked": false,
+                        }))
+                        .to_request(),
+                )
+                .await;
+                assert_eq!(item["subtype"]["TodoItem"]["title"], "dishes");
+
+                macro_rules! list {
+                    ($uri:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            TestRequest::get()
+                                .uri(&$uri)
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+
+                let all = list!("/items");
+                assert_eq!(all["next_cursor"], Value::Null);
+                let mut tags = all["items"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|item| {
+                        let subtype = item["subtype"].as_object().unwrap();
+                        let (tag, value) = subtype.iter().next().unwrap();
+                        assert_eq!(value["id"], item["item"]["id"]);
+                        tag.clone()
+                    })
+                    .collect::<Vec<_>>();
+                tags.sort();
+                assert_eq!(
+                    tags,
+                    ["Page", "TextField", "Todo", "Todo", "Todo", "TodoItem"]
+                );
+
+                // Pages continue where the previous one ended
+                let uri = format!(
+                    "/items?parent_id={}&sort=created_at&order=asc&limit=3",
+                    page_id
+                );
+                let first = list!(uri);
+                assert_eq!(first["items"].as_array().unwrap().len(), 3);
+                let cursor = first["next_cursor"].as_str().unwrap();
+                let second = list!(format!("{}&cursor={}", uri, cursor));
+                assert_eq!(second["items"].as_array().unwrap().len(), 1);
+                assert_eq!(second["next_cursor"], Value::Null);
+                assert!(first["items"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .all(|item| item["item"]["id"] != second["items"][0]["item"]["id"]));
+
+                let todos = list!("/items?item_type=200");
+                assert_eq!(todos["items"].as_array().unwrap().len(), 3);
+                let unchecked = list!("/items?checked=false");
+                assert_eq!(unchecked["items"][0]["subtype"]["TodoItem"]["title"], "dishes");
+
+                let request = TestRequest::get()
+                    .uri(&format!("/items?limit=0&cursor={}", "garbage"))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                Ok(())
+            }
+        }
     }
 }
@@ -0,0 +1,418 @@
+//! Listing items page by page.
+//!
+//! `GET /api/items` uses keyset pagination: every page comes with an opaque
+//! `next_cursor`, which is passed back as `cursor` to get the next page. The
+//! cursor holds the sort value and id of the last item on the page, so pages
+//! stay stable while items are added or removed.
+//!
+//! Items in a parent are listed in their order within it, everything else
+//! newest first.
+
+use chrono::{DateTime, Utc};
+use diesel::pg::Pg;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::{items, tags_items, todo_items};
+use crate::utils::error::{ApiError, FieldErrors};
+
+use super::item::Item;
+use super::reex_diesel::*;
+use super::ItemType;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+pub(super) type BoxedItems = items::BoxedQuery<'static, Pg>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    CreatedAt,
+    UpdatedAt,
+    DueDate,
+    /// The order of the items within their parent
+    Position,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// The query string of `GET /api/items`, every filter is optional
+#[derive(Deserialize, Default)]
+pub struct ItemsQuery {
+    pub parent_id: Option<Uuid>,
+    pub item_type: Option<ItemType>,
+    /// Comma separated tag ids, matches items with any of the tags
+    pub tags: Option<String>,
+    pub due_after: Option<DateTime<Utc>>,
+    pub due_before: Option<DateTime<Utc>>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    /// Only matches todo items that are, or aren't, checked
+    pub checked: Option<bool>,
+    /// Includes the templates and what's on them, they're left out otherwise
+    #[serde(default)]
+    pub templates: bool,
+    pub sort: Option<Sort>,
+    pub order: Option<Order>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+/// Where the previous page ended
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: Sort,
+    order: Order,
+    value: Option<DateTime<Utc>>,
+    #[serde(default)]
+    position: Option<String>,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn after(item: &Item, sort: Sort, order: Order) -> Self {
+        let (value, position) = match sort {
+            Sort::CreatedAt => (Some(item.created_at), None),
+            Sort::UpdatedAt => (Some(item.updated_at), None),
+            Sort::DueDate => (item.due_date, None),
+            Sort::Position => (None, Some(item.position.clone())),
+        };
+
+        Cursor { sort, order, value, position, id: item.id }
+    }
+
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("Failed to encode cursor");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+    }
+}
+
+/// A page of results, and the cursor of the next page if there is one
+#[derive(Serialize)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+/// Parses a comma separated list of tag ids
+pub(super) fn tag_ids(
+    tags: Option<&str>,
+    errors: &mut FieldErrors,
+) -> Option<Vec<Uuid>> {
+    let ids = tags?
+        .split(',')
+        .map(|id| Uuid::parse_str(id.trim()))
+        .collect::<Result<Vec<_>, _>>();
+
+    match ids {
+        Ok(ids) => Some(ids),
+        Err(_) => {
+            errors.add("tags", "must be comma separated ids.");
+            None
+        }
+    }
+}
+
+impl ItemsQuery {
+    /// Items in a parent are sorted by their position by default
+    fn sort(&self) -> Sort {
+        match (self.sort, self.parent_id) {
+            (Some(sort), _) => sort,
+            (None, Some(_)) => Sort::Position,
+            (None, None) => Sort::CreatedAt,
+        }
+    }
+
+    /// Positions go up by default, dates down
+    fn order(&self) -> Order {
+        match (self.order, self.sort()) {
+            (Some(order), _) => order,
+            (None, Sort::Position) => Order::Asc,
+            (None, _) => Order::Desc,
+        }
+    }
+
+    fn limit(&self, errors: &mut FieldErrors) -> i64 {
+        match self.limit {
+            Some(limit) if (1..=MAX_LIMIT).contains(&limit) => limit,
+            Some(_) => {
+                errors.add(
+                    "limit",
+                    format!("must be between 1 and {}.", MAX_LIMIT),
+                );
+                DEFAULT_LIMIT
+            }
+            None => DEFAULT_LIMIT,
+        }
+    }
+
+    fn cursor(&self, errors: &mut FieldErrors) -> Option<Cursor> {
+        let cursor = self.cursor.as_ref()?;
+
+        match Cursor::decode(cursor) {
+            Some(cursor)
+                if cursor.sort == self.sort()
+                    && cursor.order == self.order() =>
+            {
+                let valid = match cursor.sort {
+                    Sort::CreatedAt | Sort::UpdatedAt => cursor.value.is_some(),
+                    Sort::DueDate => true,
+                    Sort::Position => cursor.position.is_some(),
+                };
+                if !valid {
+                    errors.add("cursor", "is invalid.");
+                    return None;
+                }
+                Some(cursor)
+            }
+            Some(_) => {
+                errors.add("cursor", "was made for another sort order.");
+                None
+            }
+            None => {
+                errors.add("cursor", "is invalid.");
+                None
+            }
+        }
+    }
+
+    /// Narrows the query down to the matching items of the requested page,
+    /// and returns it with the amount of items to load.
+    pub(super) fn apply(
+        &self,
+        mut query: BoxedItems,
+    ) -> Result<(BoxedItems, i64), ApiError> {
+        let mut errors = FieldErrors::default();
+        let limit = self.limit(&mut errors);
+        let tag_ids = tag_ids(self.tags.as_deref(), &mut errors);
+        let cursor = self.cursor(&mut errors);
+        errors.into_result()?;
+
+        if let Some(parent_id) = self.parent_id {
+            query = query.filter(items::parent_id.eq(parent_id));
+        }
+        if let Some(item_type) = self.item_type {
+            query = query.filter(items::item_type.eq(item_type));
+        }
+        if let Some(tag_ids) = tag_ids {
+            query = query.filter(
+                items::id.eq_any(
+                    tags_items::table
+                        .filter(tags_items::tag_id.eq_any(tag_ids))
+                        .select(tags_items::item_id),
+                ),
+            );
+        }
+        if let Some(checked) = self.checked {
+            query = query.filter(
+                items::id.eq_any(
+                    todo_items::table
+                        .filter(todo_items::is_checked.eq(checked))
+                        .select(todo_items::id),
+                ),
+            );
+        }
+        if let Some(due_after) = self.due_after {
+            query = query.filter(items::due_date.gt(due_after));
+        }
+        if let Some(due_before) = self.due_before {
+            query = query.filter(items::due_date.lt(due_before));
+        }
+        if let Some(created_after) = self.created_after {
+            query = query.filter(items::created_at.gt(created_after));
+        }
+        if let Some(created_before) = self.created_before {
+            query = query.filter(items::created_at.lt(created_before));
+        }
+        if let Some(updated_after) = self.updated_after {
+            query = query.filter(items::updated_at.gt(updated_after));
+        }
+        if let Some(updated_before) = self.updated_before {
+            query = query.filter(items::updated_at.lt(updated_before));
+        }
+
+        // One more than the limit is loaded, to know if there's a next page
+        let query = self.sort_after(query, cursor).limit(limit + 1);
+        Ok((query, limit))
+    }
+
+    /// Sorts the query, and skips everything up to and including the cursor.
+    /// The id breaks ties, items without a due date come last.
+    fn sort_after(
+        &self,
+        query: BoxedItems,
+        cursor: Option<Cursor>,
+    ) -> BoxedItems {
+        let order = self.order();
+
+        macro_rules! sort_by {
+            ($column:expr, $value:ident) => {{
+                let query = match order {
+                    Order::Asc => {
+                        query.order_by(($column.asc(), items::id.asc()))
+                    }
+                    Order::Desc => {
+                        query.order_by(($column.desc(), items::id.desc()))
+                    }
+                };
+
+                match (cursor, order) {
+                    (None, _) => query,
+                    (
+                        Some(Cursor { $value: Some(value), id, .. }),
+                        Order::Asc,
+                    ) => query.filter(
+                        $column
+                            .gt(value.clone())
+                            .or($column.eq(value).and(items::id.gt(id))),
+                    ),
+                    (
+                        Some(Cursor { $value: Some(value), id, .. }),
+                        Order::Desc,
+                    ) => query.filter(
+                        $column
+                            .lt(value.clone())
+                            .or($column.eq(value).and(items::id.lt(id))),
+                    ),
+                    (Some(Cursor { $value: None, .. }), _) => {
+                        unreachable!("Only due dates can be missing")
+                    }
+                }
+            }};
+        }
+
+        match self.sort() {
+            Sort::CreatedAt => sort_by!(items::created_at, value),
+            Sort::UpdatedAt => sort_by!(items::updated_at, value),
+            Sort::Position => sort_by!(items::position, position),
+            Sort::DueDate => {
+                let missing = items::due_date.is_null();
+                let query = match order {
+                    Order::Asc => query.order_by((
+                        missing,
+                        items::due_date.asc(),
+                        items::id.asc(),
+                    )),
+                    Order::Desc => query.order_by((
+                        missing,
+                        items::due_date.desc(),
+                        items::id.desc(),
+                    )),
+                };
+
+                match (cursor, order) {
+                    (None, _) => query,
+                    (
+                        Some(Cursor { value: Some(value), id, .. }),
+                        Order::Asc,
+                    ) => query.filter(
+                        items::due_date
+                            .gt(value)
+                            .or(items::due_date.eq(value).and(items::id.gt(id)))
+                            .or(missing),
+                    ),
+                    (
+                        Some(Cursor { value: Some(value), id, .. }),
+                        Order::Desc,
+                    ) => query.filter(
+                        items::due_date
+                            .lt(value)
+                            .or(items::due_date.eq(value).and(items::id.lt(id)))
+                            .or(missing),
+                    ),
+                    (Some(Cursor { value: None, id, .. }), Order::Asc) => {
+                        query.filter(missing.and(items::id.gt(id)))
+                    }
+                    (Some(Cursor { value: None, id, .. }), Order::Desc) => {
+                        query.filter(missing.and(items::id.lt(id)))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Cuts the loaded items down to the limit, and makes the cursor
+    /// of the next page when there are more items.
+    pub(super) fn paginate(
+        &self,
+        mut items: Vec<Item>,
+        limit: i64,
+    ) -> (Vec<Item>, Option<String>) {
+        if items.len() as i64 <= limit {
+            return (items, None);
+        }
+
+        items.truncate(limit as usize);
+        let cursor = items.last().map(|item| {
+            Cursor::after(item, self.sort(), self.order()).encode()
+        });
+        (items, cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cursor, ItemsQuery, Order, Sort};
+    use crate::utils::error::FieldErrors;
+
+    #[test]
+    fn test_cursor_belongs_to_its_sort_order() {
+        let cursor = Cursor {
+            sort: Sort::DueDate,
+            order: Order::Asc,
+            value: None,
+            position: None,
+            id: uuid::Uuid::new_v4(),
+        }
+        .encode();
+
+        let mut query = ItemsQuery {
+            sort: Some(Sort::DueDate),
+            order: Some(Order::Asc),
+            cursor: Some(cursor),
+            ..Default::default()
+        };
+        let mut errors = FieldErrors::default();
+        assert!(query.cursor(&mut errors).is_some());
+        assert!(errors.into_result().is_ok());
+
+        query.order = Some(Order::Desc);
+        let mut errors = FieldErrors::default();
+        assert!(query.cursor(&mut errors).is_none());
+        assert!(errors.get("cursor").is_some());
+
+        query.cursor = Some("garbage".into());
+        let mut errors = FieldErrors::default();
+        assert!(query.cursor(&mut errors).is_none());
+        assert_eq!(errors.get("cursor").unwrap(), ["is invalid."]);
+    }
+
+    #[test]
+    fn test_items_in_a_parent_are_sorted_by_position() {
+        let mut query = ItemsQuery::default();
+        assert_eq!(
+            (query.sort(), query.order()),
+            (Sort::CreatedAt, Order::Desc)
+        );
+
+        query.parent_id = Some(uuid::Uuid::new_v4());
+        assert_eq!((query.sort(), query.order()), (Sort::Position, Order::Asc));
+
+        query.sort = Some(Sort::DueDate);
+        assert_eq!((query.sort(), query.order()), (Sort::DueDate, Order::Desc));
+    }
+}
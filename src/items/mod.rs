@@ -3,16 +3,15 @@
 //! The several items used are listed below:
 //! - [`Item`](item/struct.Item.html)
 //! - [`Page`](page/struct.Page.html)
+//!
+//! Every type of item is listed in the [`registry`](registry/index.html).
 
 use serde::Serialize;
 use uuid::Uuid;
 
 use item::Item;
-
-use crate::items::page::Page;
-use crate::items::text_field::TextField;
-use crate::items::todo::Todo;
-use crate::items::todo_item::TodoItem;
+use ordering::Placement;
+use registry::Subtype;
 
 /// Reexport commonly used diesel
 /// namespaces
@@ -20,13 +19,24 @@ mod reex_diesel {
     pub use diesel::{pg::PgConnection, prelude::*, QueryResult};
 }
 
+pub mod containment;
 pub mod crud;
+#[macro_use]
 pub mod crud2;
+pub mod duplicate;
+pub mod hierarchy;
 pub mod item;
+pub mod listing;
+pub mod ordering;
 pub mod page;
+pub mod registry;
+pub mod revision;
+pub mod search;
+pub mod template;
 pub mod text_field;
 pub mod todo;
 pub mod todo_item;
+pub mod trash;
 
 pub type ItemType = i16;
 
@@ -36,6 +46,11 @@ pub trait ItemLike {
     fn parent_id(&self) -> Option<Uuid>;
     fn parent_type(&self) -> Option<ItemType>;
 
+    /// Where a new item is placed among its siblings
+    fn placement(&self) -> Placement {
+        Placement::default()
+    }
+
     fn as_item(&self) -> Item {
         Item {
             id: self.id(),
@@ -56,6 +71,7 @@ pub trait TypeMarker {
 }
 
 #[repr(i16)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ItemTypeNames {
     Page = 100,
     Todo = 200,
@@ -63,22 +79,34 @@ pub enum ItemTypeNames {
     TextField = 300,
 }
 
-#[derive(Serialize)]
-pub enum Items {
-    Page(Page),
-    Todo(Todo),
-    TodoItem(TodoItem),
-    TextField(TextField),
+impl ItemTypeNames {
+    pub fn from_type(item_type: ItemType) -> Option<Self> {
+        registry::lookup(item_type).map(|registration| registration.item_type)
+    }
+
+    /// How the type is called in messages to the client
+    pub fn name(self) -> &'static str {
+        registry::lookup(self as ItemType)
+            .map_or("item", |registration| registration.name)
+    }
 }
 
 #[derive(Serialize)]
 pub struct ViewItem {
     item: Item,
-    subtype: Items,
+    subtype: Subtype,
 }
 
 impl ViewItem {
-    pub fn make(item: Item, subtype: Items) -> Self {
+    pub fn make(item: Item, subtype: Subtype) -> Self {
         ViewItem { item, subtype }
     }
+
+    pub fn id(&self) -> Uuid {
+        self.item.id
+    }
+
+    pub fn parent_id(&self) -> Option<Uuid> {
+        self.item.parent_id
+    }
 }
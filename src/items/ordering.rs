@@ -0,0 +1,387 @@
+//! The order of items within their parent.
+//!
+//! Every item has a position, a string of base 36 digits that's compared
+//! byte by byte. A position can always be made between two others, so
+//! placing an item between its siblings only changes the item itself. To
+//! keep that possible, positions never end in `0`.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::items;
+use crate::users::User;
+use crate::utils::{
+    error::{ApiError, FieldErrors},
+    etag::Conditions,
+    patch::nullable,
+    policy::Policy,
+};
+
+use super::containment;
+use super::item::Item;
+use super::listing::BoxedItems;
+use super::reex_diesel::*;
+
+const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+const BASE: u8 = DIGITS.len() as u8;
+
+/// Where an item is placed among its siblings, at the end by default
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+pub struct Placement {
+    /// The sibling the item is placed right before
+    pub before: Option<Uuid>,
+    /// The sibling the item is placed right after
+    pub after: Option<Uuid>,
+}
+
+/// The body of `POST /api/items/{id}/move`
+#[derive(Deserialize)]
+pub struct MoveRequest {
+    /// The new parent, the item stays in its parent when it's left out
+    #[serde(default, deserialize_with = "nullable")]
+    pub parent_id: Option<Option<Uuid>>,
+    #[serde(flatten)]
+    pub placement: Placement,
+}
+
+fn digit(byte: u8) -> u8 {
+    DIGITS.iter().position(|digit| *digit == byte).unwrap_or(0) as u8
+}
+
+/// A position after `before` and before `after`, an empty `before` being
+/// the start and a missing `after` the end
+pub fn between(before: &str, after: Option<&str>) -> String {
+    let low = before.bytes().map(digit).collect::<Vec<_>>();
+    let mut high =
+        after.map(|after| after.bytes().map(digit).collect::<Vec<_>>());
+    let mut position = Vec::new();
+
+    for i in 0.. {
+        let a = low.get(i).copied().unwrap_or(0);
+        let b = match &high {
+            Some(high) if i < high.len() && high[i] >= a => high[i],
+            // There's no room left below `after`, which only happens when
+            // it isn't after `before`. The item goes right after `before`.
+            _ => {
+                high = None;
+                BASE
+            }
+        };
+
+        if b - a > 1 {
+            position.push(DIGITS[((a + b) / 2) as usize]);
+            break;
+        }
+        position.push(DIGITS[a as usize]);
+        if b > a {
+            // Everything that starts like this is below `after`
+            high = None;
+        }
+    }
+
+    String::from_utf8(position).expect("Digits are ASCII")
+}
+
+fn invalid(field: &'static str, message: &'static str) -> ApiError {
+    let mut errors = FieldErrors::default();
+    errors.add(field, message);
+    ApiError::Invalid(errors)
+}
+
+/// The items in the parent, except the excluded one
+fn siblings(
+    parent_id: Option<Uuid>,
+    exclude: Option<Uuid>,
+    user: &User,
+) -> BoxedItems {
+    let mut query = items::table
+        .into_boxed()
+        .filter(items::owner_id.eq(user.id))
+        .filter(items::deleted_at.is_null());
+    query = match parent_id {
+        Some(parent_id) => query.filter(items::parent_id.eq(parent_id)),
+        None => query.filter(items::parent_id.is_null()),
+    };
+    if let Some(exclude) = exclude {
+        query = query.filter(items::id.ne(exclude));
+    }
+    query
+}
+
+/// The position of an item placed in the parent. The `exclude`d item is
+/// the one being moved, if it's already in the parent.
+pub fn position(
+    parent_id: Option<Uuid>,
+    placement: Placement,
+    exclude: Option<Uuid>,
+    user: &User,
+    conn: &PgConnection,
+) -> Result<String, ApiError> {
+    let neighbour = |id: Uuid, field| {
+        siblings(parent_id, exclude, user)
+            .filter(items::id.eq(id))
+            .select(items::position)
+            .first::<String>(conn)
+            .optional()?
+            .ok_or_else(|| {
+                invalid(field, "must be an item in the same parent.")
+            })
+    };
+
+    match placement {
+        Placement { before: Some(_), after: Some(_) } => {
+            Err(invalid("before", "can't be used together with after."))
+        }
+        Placement { before: Some(before), after: None } => {
+            let next = neighbour(before, "before")?;
+            let previous = siblings(parent_id, exclude, user)
+                .filter(items::position.lt(&next))
+                .order(items::position.desc())
+                .select(items::position)
+                .first::<String>(conn)
+                .optional()?;
+
+            Ok(between(previous.as_deref().unwrap_or(""), Some(&next)))
+        }
+        Placement { before: None, after: Some(after) } => {
+            let previous = neighbour(after, "after")?;
+            let next = siblings(parent_id, exclude, user)
+                .filter(items::position.gt(&previous))
+                .order(items::position.asc())
+                .select(items::position)
+                .first::<String>(conn)
+                .optional()?;
+
+            Ok(between(&previous, next.as_deref()))
+        }
+        Placement { before: None, after: None } => {
+            let last = siblings(parent_id, exclude, user)
+                .order(items::position.desc())
+                .select(items::position)
+                .first::<String>(conn)
+                .optional()?;
+
+            Ok(between(last.as_deref().unwrap_or(""), None))
+        }
+    }
+}
+
+/// Moves the item to its place in the parent. Only the item itself
+/// changes, its siblings keep their positions.
+pub fn move_item(
+    id: Uuid,
+    request: MoveRequest,
+    conditions: &Conditions,
+    user: &User,
+    conn: &PgConnection,
+) -> Result<Item, ApiError> {
+    Item::authorize(id, user, conn)?;
+
+    conn.transaction(|| {
+        conditions.check(Item::lock_version(id, conn)?)?;
+        let current =
+            items::table.filter(items::id.eq(id)).first::<Item>(conn)?;
+
+        let (parent_id, parent_type) = match request.parent_id {
+            Some(parent_id) => (
+                parent_id,
+                containment::reparent(
+                    id,
+                    current.item_type,
+                    parent_id,
+                    user,
+                    conn,
+                )?,
+            ),
+            None => (current.parent_id, current.parent_type),
+        };
+        let position =
+            position(parent_id, request.placement, Some(id), user, conn)?;
+
+        diesel::update(items::table.filter(items::id.eq(id)))
+            .set((
+                items::parent_id.eq(parent_id),
+                items::parent_type.eq(parent_type),
+                items::position.eq(position),
+            ))
+            .get_result(conn)
+            .map_err(ApiError::from)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use super::between;
+    use crate::items::{item::Item, registry};
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[test]
+    fn test_positions_between() {
+        assert_eq!(between("", None), "i");
+        assert_eq!(between("a", Some("b")), "ai");
+        assert_eq!(between("", Some("1")), "0i");
+        assert_eq!(between("a", Some("a05")), "a02");
+        assert_eq!(between("az", Some("b")), "azi");
+        assert_eq!(between("z", None), "zi");
+        // Positions that are out of order don't loop forever
+        assert_eq!(between("b", Some("b")), "bi");
+        assert_eq!(between("c", Some("b")), "o");
+    }
+
+    #[test]
+    fn test_positions_stay_ordered() {
+        let mut positions = vec![between("", None)];
+
+        // Keep inserting at the front, at the back and in between
+        for round in 0..200 {
+            let index = match round % 3 {
+                0 => 0,
+                1 => positions.len(),
+                _ => positions.len() / 2,
+            };
+            let before = if index == 0 { "" } else { &positions[index - 1] };
+            let after = positions.get(index).map(String::as_str);
+            let position = between(before, after);
+
+            assert!(before < position.as_str());
+            if let Some(after) = after {
+                assert!(position.as_str() < after);
+            }
+            assert!(!position.ends_with('0'));
+            positions.insert(index, position);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_order_items() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(registry::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "orderer");
+
+                macro_rules! send {
+                    ($request:expr, $body:expr) => {
+                        call_service(
+                            &mut app,
+                            $request
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .set_json(&$body)
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+                macro_rules! create {
+                    ($uri:expr, $body:expr) => {{
+                        let item: Value = read_response_json(
+                            &mut app,
+                            TestRequest::post()
+                                .uri($uri)
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .set_json(&$body)
+                                .to_request(),
+                        )
+                        .await;
+                        item["item"]["id"].as_str().unwrap().to_owned()
+                    }};
+                }
+                macro_rules! titles {
+                    ($parent:expr) => {{
+                        let list: Value = read_response_json(
+                            &mut app,
+                            TestRequest::get()
+                                .uri(&format!("/items?parent_id={}", $parent))
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .to_request(),
+                        )
+                        .await;
+                        list["items"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|item| {
+                                item["subtype"]["Todo"]["title"]
+                                    .as_str()
+                                    .unwrap()
+                                    .to_owned()
+                            })
+                            .collect::<Vec<_>>()
+                    }};
+                }
+
+                let page = create!("/pages", json!({ "title": "board" }));
+                let other = create!("/pages", json!({ "title": "other" }));
+                let todo = |title: &str| {
+                    json!({
+                        "title": title,
+                        "page_id": page,
+                        "coord_x": 0,
+                        "coord_y": 0,
+                    })
+                };
+
+                let a = create!("/todos", todo("a"));
+                let c = create!("/todos", todo("c"));
+                let mut b = todo("b");
+                b["before"] = json!(c);
+                let b = create!("/todos", b);
+                let mut first = todo("first");
+                first["before"] = json!(a);
+                let _first = create!("/todos", first);
+                assert_eq!(titles!(page), ["first", "a", "b", "c"]);
+
+                // Moving within the parent
+                let resp = send!(
+                    TestRequest::post().uri(&format!("/items/{}/move", a)),
+                    json!({ "after": c })
+                );
+                assert_eq!(resp.status(), StatusCode::OK);
+                assert_eq!(titles!(page), ["first", "b", "c", "a"]);
+
+                // Neighbours have to be in the target parent
+                let resp = send!(
+                    TestRequest::post().uri(&format!("/items/{}/move", b)),
+                    json!({ "parent_id": other, "before": c })
+                );
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                let resp = send!(
+                    TestRequest::post().uri(&format!("/items/{}/move", b)),
+                    json!({ "parent_id": other })
+                );
+                assert_eq!(resp.status(), StatusCode::OK);
+                assert_eq!(titles!(page), ["first", "c", "a"]);
+                assert_eq!(titles!(other), ["b"]);
+
+                // Todos only live on pages
+                let resp = send!(
+                    TestRequest::post().uri(&format!("/items/{}/move", c)),
+                    json!({ "parent_id": b })
+                );
+                assert!(resp.status().is_client_error());
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,14 +1,15 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::items::Items;
 use crate::{
     items::{ItemTypeNames, TypeMarker},
     schema::pages,
+    utils::patch::Changes,
 };
 
 use super::{
     crud2::{raw_crud, ModelFromPartial},
+    ordering::Placement,
     reex_diesel::*,
     ItemLike, ItemType,
 };
@@ -18,17 +19,33 @@ pub struct Page {
     pub id: Uuid,
     pub item_type: ItemType,
     pub title: String,
+    /// Templates are copied to make new pages from
+    pub is_template: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct NewPage {
     pub title: String,
+    /// The page this one is a sub-page of
+    #[serde(default)]
+    pub parent_id: Option<Uuid>,
+    #[serde(default)]
+    pub is_template: bool,
+    #[serde(flatten)]
+    pub placement: Placement,
 }
 
 #[derive(AsChangeset, Deserialize)]
 #[table_name = "pages"]
 pub struct UpdatePage {
-    pub title: String,
+    pub title: Option<String>,
+    pub is_template: Option<bool>,
+}
+
+impl Changes for UpdatePage {
+    fn is_empty(&self) -> bool {
+        self.title.is_none() && self.is_template.is_none()
+    }
 }
 
 impl TypeMarker for Page {
@@ -45,17 +62,15 @@ impl ItemLike for NewPage {
     }
 
     fn parent_id(&self) -> Option<Uuid> {
-        None
+        self.parent_id
     }
 
     fn parent_type(&self) -> Option<i16> {
-        None
+        self.parent_id.map(|_| Page::TYPE as i16)
     }
-}
 
-impl From<Page> for Items {
-    fn from(page: Page) -> Self {
-        Self::Page(page)
+    fn placement(&self) -> Placement {
+        self.placement
     }
 }
 
@@ -67,7 +82,12 @@ impl raw_crud::Create for Page {
 
 impl ModelFromPartial<NewPage> for Page {
     fn from_partial(partial: NewPage, item: &crate::items::item::Item) -> Self {
-        Self { id: item.id, item_type: item.item_type, title: partial.title }
+        Self {
+            id: item.id,
+            item_type: item.item_type,
+            title: partial.title,
+            is_template: partial.is_template,
+        }
     }
 }
 
@@ -94,6 +114,12 @@ impl raw_crud::Find for Page {
             .filter(pages::item_type.eq(Self::TYPE as i16))
             .get_result(conn)
     }
+
+    find_all!(pages);
+}
+
+impl raw_crud::Duplicate for Page {
+    duplicate!(pages);
 }
 
 impl raw_crud::Delete for Page {
@@ -108,6 +134,11 @@ impl Page {
         cfg.service(routes::find_page);
         cfg.service(routes::update_page);
         cfg.service(routes::delete_page);
+        cfg.service(routes::duplicate_page);
+        cfg.service(routes::page_tree);
+        cfg.service(routes::breadcrumbs);
+        cfg.service(routes::find_templates);
+        cfg.service(routes::create_from_template);
     }
 }
 
@@ -117,7 +148,15 @@ mod routes {
     };
     use uuid::Uuid;
 
-    use crate::{items::crud2::crud2http, DbPool};
+    use crate::{
+        database::exec_on_pool,
+        items::{crud2::crud2http, duplicate, hierarchy, template},
+        users::access_token::Scope,
+        utils::{
+            authorize, etag::Conditions, patch::Patch, responsable::Responsable,
+        },
+        DbPool,
+    };
 
     use super::{NewPage, Page, UpdatePage};
 
@@ -127,7 +166,7 @@ mod routes {
         req: HttpRequest,
         form: web::Json<NewPage>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsWrite)?;
         crud2http::create::<Page, _>(form.into_inner(), user, &pool).await
     }
 
@@ -137,8 +176,14 @@ mod routes {
         req: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
-        crud2http::find::<Page>(id.into_inner(), user, &pool).await
+        let user = authorize(&req, Scope::ItemsRead)?;
+        crud2http::find::<Page>(
+            id.into_inner(),
+            Conditions::from_request(&req),
+            user,
+            &pool,
+        )
+        .await
     }
 
     #[patch("/pages/{id}")]
@@ -146,13 +191,15 @@ mod routes {
         pool: web::Data<DbPool>,
         req: HttpRequest,
         id: web::Path<Uuid>,
-        form: web::Json<UpdatePage>,
+        body: web::Json<serde_json::Value>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        let patch = Patch::from_request(&req, body.into_inner())?;
 
-        crud2http::update::<Page, _>(
+        crud2http::update::<Page, UpdatePage>(
             id.into_inner(),
-            form.into_inner(),
+            patch,
+            Conditions::from_request(&req),
             user,
             &pool,
         )
@@ -165,8 +212,86 @@ mod routes {
         req: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
-        crud2http::delete::<Page>(id.into_inner(), user, &pool).await
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        crud2http::delete::<Page>(
+            id.into_inner(),
+            Conditions::from_request(&req),
+            user,
+            &pool,
+        )
+        .await
+    }
+
+    #[post("/pages/{id}/duplicate")]
+    pub async fn duplicate_page(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsWrite)?;
+
+        exec_on_pool(&pool, move |conn| {
+            duplicate::duplicate_page(id.into_inner(), &user, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[get("/pages/{id}/tree")]
+    pub async fn page_tree(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsRead)?;
+
+        exec_on_pool(&pool, move |conn| {
+            hierarchy::tree(id.into_inner(), &user, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[get("/pages/{id}/breadcrumbs")]
+    pub async fn breadcrumbs(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsRead)?;
+
+        exec_on_pool(&pool, move |conn| {
+            hierarchy::breadcrumbs(id.into_inner(), &user, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[get("/templates")]
+    pub async fn find_templates(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsRead)?;
+
+        exec_on_pool(&pool, move |conn| template::find_all(&user, conn))
+            .await
+            .into_response()
+    }
+
+    #[post("/pages/from-template/{id}")]
+    pub async fn create_from_template(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsWrite)?;
+
+        exec_on_pool(&pool, move |conn| {
+            template::instantiate(id.into_inner(), &user, conn)
+        })
+        .await
+        .into_response()
     }
 }
 
@@ -179,7 +304,12 @@ mod tests {
     async fn test_create_page() -> Result<(), Box<dyn std::error::Error>> {
         testing::create::<_, NewPage>(
             Page::routes,
-            NewPage { title: "testpage".into() },
+            NewPage {
+                title: "testpage".into(),
+                parent_id: None,
+                is_template: false,
+                placement: Default::default(),
+            },
             "/api/pages",
         )
         .await;
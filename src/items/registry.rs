@@ -0,0 +1,338 @@
+//! Every type of item the application knows about.
+//!
+//! A type of item is added by giving it a code in
+//! [`ItemTypeNames`](../enum.ItemTypeNames.html) and registering its model
+//! in [`REGISTRY`](static.REGISTRY.html). Loading items, showing them and
+//! routing requests to them is then taken care of, as is changing them
+//! through routes that work on any type, like `POST /api/batch`.
+
+use std::collections::HashMap;
+
+use diesel::{pg::PgConnection, QueryResult};
+use serde::{de::DeserializeOwned, ser::SerializeMap, Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::users::User;
+use crate::utils::{
+    error::ApiError,
+    etag::Conditions,
+    patch::{Changes, Patch},
+    policy::Policy,
+};
+
+use super::{
+    crud2::{intermediate, raw_crud, IntoModel},
+    page::{NewPage, Page, UpdatePage},
+    text_field::{NewTextField, TextField, UpdateTextField},
+    todo::{NewTodo, Todo, UpdateTodo},
+    todo_item::{NewTodoItem, TodoItem, UpdateTodoItem},
+    ItemLike, ItemType, ItemTypeNames, TypeMarker, ViewItem,
+};
+
+type FindAll =
+    fn(&[Uuid], &PgConnection) -> QueryResult<Vec<(Uuid, serde_json::Value)>>;
+type Duplicate = fn(&HashMap<Uuid, Uuid>, &PgConnection) -> QueryResult<()>;
+type Create =
+    fn(serde_json::Value, User, &PgConnection) -> Result<ViewItem, ApiError>;
+type Update =
+    fn(Uuid, Patch, User, &PgConnection) -> Result<serde_json::Value, ApiError>;
+type Delete = fn(Uuid, User, &PgConnection) -> Result<(), ApiError>;
+
+/// A model of a type of item
+pub struct Registration {
+    pub item_type: ItemTypeNames,
+    /// How the subtype is tagged in a [`ViewItem`](../struct.ViewItem.html)
+    pub tag: &'static str,
+    /// How the type is called in messages to the client
+    pub name: &'static str,
+    /// Where the text of the type is searched in
+    pub search: Option<Searchable>,
+    find_all: FindAll,
+    duplicate: Duplicate,
+    create: Create,
+    update: Update,
+    delete: Delete,
+    routes: fn(&mut actix_web::web::ServiceConfig),
+}
+
+/// A table with a generated `search` tsvector column
+pub struct Searchable {
+    pub table: &'static str,
+    /// The column the vector is generated from, which is also where the
+    /// placeholders of [templates](../template/index.html) are filled in
+    pub text: &'static str,
+}
+
+impl Registration {
+    /// Registers the model `M`, which is created from an `N` and updated
+    /// with a `U`
+    pub const fn of<M, N, U>(
+        tag: &'static str,
+        name: &'static str,
+        routes: fn(&mut actix_web::web::ServiceConfig),
+    ) -> Self
+    where
+        M: TypeMarker
+            + raw_crud::Create
+            + raw_crud::Find
+            + raw_crud::Update<U>
+            + raw_crud::Delete
+            + raw_crud::Duplicate
+            + Serialize
+            + Policy,
+        N: DeserializeOwned + IntoModel<M> + ItemLike,
+        U: Changes,
+    {
+        Registration {
+            item_type: M::TYPE,
+            tag,
+            name,
+            search: None,
+            find_all: find_values::<M>,
+            duplicate: M::duplicate,
+            create: create_value::<M, N>,
+            update: update_value::<M, U>,
+            delete: delete_model::<M>,
+            routes,
+        }
+    }
+
+    pub const fn searchable(
+        self,
+        table: &'static str,
+        text: &'static str,
+    ) -> Self {
+        Registration { search: Some(Searchable { table, text }), ..self }
+    }
+
+    /// Loads the subtypes of the items with the ids in a single query
+    pub fn find_all(
+        &self,
+        ids: &[Uuid],
+        conn: &PgConnection,
+    ) -> QueryResult<HashMap<Uuid, Subtype>> {
+        (self.find_all)(ids, conn).map(|values| {
+            values
+                .into_iter()
+                .map(|(id, value)| (id, Subtype { tag: self.tag, value }))
+                .collect()
+        })
+    }
+
+    /// Copies the subtypes of the items, each under the id its id is mapped
+    /// to
+    pub fn duplicate(
+        &self,
+        ids: &HashMap<Uuid, Uuid>,
+        conn: &PgConnection,
+    ) -> QueryResult<()> {
+        (self.duplicate)(ids, conn)
+    }
+
+    /// Creates an item of the type from the body of a create request
+    pub fn create(
+        &self,
+        body: serde_json::Value,
+        user: User,
+        conn: &PgConnection,
+    ) -> Result<ViewItem, ApiError> {
+        (self.create)(body, user, conn)
+    }
+
+    /// Patches the subtype of the item
+    pub fn update(
+        &self,
+        id: Uuid,
+        patch: Patch,
+        user: User,
+        conn: &PgConnection,
+    ) -> Result<Subtype, ApiError> {
+        (self.update)(id, patch, user, conn)
+            .map(|value| Subtype { tag: self.tag, value })
+    }
+
+    /// Updates the subtype of the item to an earlier version of it
+    pub fn restore(
+        &self,
+        id: Uuid,
+        payload: serde_json::Value,
+        user: User,
+        conn: &PgConnection,
+    ) -> Result<Subtype, ApiError> {
+        self.update(id, Patch::Merge(payload), user, conn)
+    }
+
+    /// Moves the item to the trash
+    pub fn delete(
+        &self,
+        id: Uuid,
+        user: User,
+        conn: &PgConnection,
+    ) -> Result<(), ApiError> {
+        (self.delete)(id, user, conn)
+    }
+}
+
+fn find_values<M>(
+    ids: &[Uuid],
+    conn: &PgConnection,
+) -> QueryResult<Vec<(Uuid, serde_json::Value)>>
+where
+    M: raw_crud::Find + Serialize,
+{
+    M::find_all(ids, conn).map(|models| {
+        models.into_iter().map(|(id, model)| (id, to_value(model))).collect()
+    })
+}
+
+fn create_value<M, N>(
+    body: serde_json::Value,
+    user: User,
+    conn: &PgConnection,
+) -> Result<ViewItem, ApiError>
+where
+    M: raw_crud::Create + TypeMarker + Serialize,
+    N: DeserializeOwned + IntoModel<M> + ItemLike,
+{
+    let create = serde_json::from_value::<N>(body)
+        .map_err(|err| ApiError::Unprocessable(err.to_string()))?;
+
+    intermediate::create::<M>(create, user, conn)
+}
+
+fn update_value<M, U>(
+    id: Uuid,
+    patch: Patch,
+    user: User,
+    conn: &PgConnection,
+) -> Result<serde_json::Value, ApiError>
+where
+    M: TypeMarker + raw_crud::Find + raw_crud::Update<U> + Serialize + Policy,
+    U: Changes,
+{
+    intermediate::update::<M, U>(id, patch, &Conditions::default(), user, conn)
+        .map(|versioned| to_value(versioned.value))
+}
+
+fn delete_model<M>(
+    id: Uuid,
+    user: User,
+    conn: &PgConnection,
+) -> Result<(), ApiError>
+where
+    M: raw_crud::Delete + Policy,
+{
+    intermediate::delete::<M>(id, &Conditions::default(), user, conn)
+}
+
+fn to_value(model: impl Serialize) -> serde_json::Value {
+    serde_json::to_value(model).expect("Failed to serialize item")
+}
+
+pub static REGISTRY: [Registration; 4] = [
+    Registration::of::<Page, NewPage, UpdatePage>("Page", "page", Page::routes)
+        .searchable("pages", "title"),
+    Registration::of::<Todo, NewTodo, UpdateTodo>("Todo", "todo", Todo::routes)
+        .searchable("todos", "title"),
+    Registration::of::<TodoItem, NewTodoItem, UpdateTodoItem>(
+        "TodoItem",
+        "todo item",
+        TodoItem::routes,
+    )
+    .searchable("todo_items", "title"),
+    Registration::of::<TextField, NewTextField, UpdateTextField>(
+        "TextField",
+        "text field",
+        TextField::routes,
+    )
+    .searchable("text_fields", "text"),
+];
+
+/// The registration of the type with the code, if there is one
+pub fn lookup(item_type: ItemType) -> Option<&'static Registration> {
+    REGISTRY
+        .iter()
+        .find(|registration| registration.item_type as ItemType == item_type)
+}
+
+/// Configures the routes of every registered type of item
+pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
+    for registration in REGISTRY.iter() {
+        (registration.routes)(cfg);
+    }
+}
+
+/// The model of an item, tagged with its type
+pub struct Subtype {
+    tag: &'static str,
+    value: serde_json::Value,
+}
+
+impl Subtype {
+    pub fn of<M: TypeMarker + Serialize>(model: M) -> Self {
+        let tag = lookup(M::TYPE as ItemType)
+            .expect("Item type isn't registered")
+            .tag;
+
+        Subtype { tag, value: to_value(model) }
+    }
+}
+
+impl Serialize for Subtype {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(self.tag, &self.value)?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{lookup, Subtype, REGISTRY};
+    use crate::items::{page::Page, ItemTypeNames};
+
+    #[test]
+    fn test_every_type_is_registered_once() {
+        for registration in REGISTRY.iter() {
+            let code = registration.item_type as i16;
+            assert_eq!(
+                REGISTRY.iter().filter(|r| r.item_type as i16 == code).count(),
+                1,
+                "{} is registered twice",
+                registration.tag
+            );
+        }
+
+        assert_eq!(lookup(210).unwrap().item_type, ItemTypeNames::TodoItem);
+        assert!(lookup(0).is_none());
+    }
+
+    #[test]
+    fn test_subtype_is_tagged_with_its_type() {
+        let id = Uuid::new_v4();
+        let page = Page {
+            id,
+            item_type: 100,
+            title: "diary".into(),
+            is_template: false,
+        };
+
+        assert_eq!(
+            serde_json::to_value(Subtype::of(page)).unwrap(),
+            json!({
+                "Page": {
+                    "id": id,
+                    "item_type": 100,
+                    "title": "diary",
+                    "is_template": false,
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,430 @@
+//! The history of items.
+//!
+//! Whenever the subtype of an item is updated, the version it replaces is
+//! kept as a revision. Revisions are numbered per item, starting at 1, and
+//! restoring one is an update too, so it's undone by restoring the revision
+//! made just before. Every user keeps at most `REVISIONS_PER_USER` (default
+//! 500) revisions, the oldest ones are dropped first.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::schema::{item_revisions, items};
+use crate::users::User;
+use crate::utils::{config::env_or, error::ApiError, policy::Policy};
+
+use super::item::Item;
+use super::reex_diesel::*;
+use super::{registry, ItemType, TypeMarker, ViewItem};
+
+const DEFAULT_REVISIONS_PER_USER: u32 = 500;
+
+/// Set at startup by [`Revision::configure_from_env`]
+static REVISIONS_PER_USER: OnceCell<i64> = OnceCell::new();
+
+#[derive(Queryable, Serialize)]
+pub struct Revision {
+    item_id: Uuid,
+    item_type: ItemType,
+    revision: i32,
+    /// The subtype as it was
+    payload: Value,
+    /// When the subtype was changed after this revision
+    created_at: DateTime<Utc>,
+}
+
+const COLUMNS: (
+    item_revisions::item_id,
+    item_revisions::item_type,
+    item_revisions::revision,
+    item_revisions::payload,
+    item_revisions::created_at,
+) = (
+    item_revisions::item_id,
+    item_revisions::item_type,
+    item_revisions::revision,
+    item_revisions::payload,
+    item_revisions::created_at,
+);
+
+#[derive(Insertable)]
+#[table_name = "item_revisions"]
+struct NewRevision {
+    item_id: Uuid,
+    item_type: ItemType,
+    owner_id: Uuid,
+    revision: i32,
+    payload: Value,
+}
+
+/// The query string of `GET /api/items/{id}/revisions/diff`
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    pub from: i32,
+    pub to: i32,
+}
+
+/// A field that differs between two revisions. A field missing from one of
+/// them is `null` there.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Change {
+    field: String,
+    from: Value,
+    to: Value,
+}
+
+impl Revision {
+    /// Reads how many revisions every user keeps, from `REVISIONS_PER_USER`
+    pub fn configure_from_env() -> Result<(), Box<dyn Error>> {
+        let limit = env_or("REVISIONS_PER_USER", DEFAULT_REVISIONS_PER_USER)?;
+        // The first configuration wins
+        let _ = REVISIONS_PER_USER.set(limit.into());
+        Ok(())
+    }
+
+    fn limit() -> i64 {
+        REVISIONS_PER_USER
+            .get()
+            .copied()
+            .unwrap_or_else(|| DEFAULT_REVISIONS_PER_USER.into())
+    }
+
+    /// Keeps the model as the newest revision of the item
+    pub(crate) fn save<M>(
+        id: Uuid,
+        model: &M,
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<(), ApiError>
+    where
+        M: TypeMarker + Serialize,
+    {
+        let last = item_revisions::table
+            .filter(item_revisions::item_id.eq(id))
+            .select(diesel::dsl::max(item_revisions::revision))
+            .first::<Option<i32>>(conn)?;
+        let revision = NewRevision {
+            item_id: id,
+            item_type: M::TYPE as ItemType,
+            owner_id: user.id,
+            revision: last.unwrap_or(0) + 1,
+            payload: serde_json::to_value(model).map_err(|err| {
+                log::error!("couldn't serialize a revision: {}", err);
+                ApiError::Internal
+            })?,
+        };
+
+        diesel::insert_into(item_revisions::table)
+            .values(&revision)
+            .execute(conn)?;
+        Ok(Self::prune(user, conn)?)
+    }
+
+    /// Drops the oldest revisions of the user that are over the limit
+    fn prune(user: &User, conn: &PgConnection) -> QueryResult<()> {
+        let expired = item_revisions::table
+            .filter(item_revisions::owner_id.eq(user.id))
+            .order((
+                item_revisions::created_at.desc(),
+                item_revisions::revision.desc(),
+            ))
+            .offset(Self::limit())
+            .select(item_revisions::id)
+            .load::<Uuid>(conn)?;
+
+        if !expired.is_empty() {
+            diesel::delete(
+                item_revisions::table
+                    .filter(item_revisions::id.eq_any(expired)),
+            )
+            .execute(conn)?;
+        }
+        Ok(())
+    }
+
+    /// The revisions of the item, newest first
+    fn find_all(
+        id: Uuid,
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<Vec<Self>, ApiError> {
+        Item::authorize(id, user, conn)?;
+
+        item_revisions::table
+            .filter(item_revisions::item_id.eq(id))
+            .order(item_revisions::revision.desc())
+            .select(COLUMNS)
+            .load(conn)
+            .map_err(ApiError::from)
+    }
+
+    fn find(
+        id: Uuid,
+        revision: i32,
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<Self, ApiError> {
+        Item::authorize(id, user, conn)?;
+
+        item_revisions::table
+            .filter(item_revisions::item_id.eq(id))
+            .filter(item_revisions::revision.eq(revision))
+            .select(COLUMNS)
+            .first(conn)
+            .map_err(ApiError::from)
+    }
+
+    fn diff(
+        id: Uuid,
+        query: &DiffQuery,
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<Vec<Change>, ApiError> {
+        let from = Self::find(id, query.from, user, conn)?;
+        let to = Self::find(id, query.to, user, conn)?;
+
+        Ok(diff(&from.payload, &to.payload))
+    }
+
+    /// Puts the subtype of the item back the way it was in the revision
+    fn restore(
+        id: Uuid,
+        revision: i32,
+        user: User,
+        conn: &PgConnection,
+    ) -> Result<ViewItem, ApiError> {
+        conn.transaction(|| {
+            let revision = Self::find(id, revision, &user, conn)?;
+            let registration = registry::lookup(revision.item_type)
+                .ok_or_else(|| {
+                    log::error!(
+                        "revision of unknown type {}",
+                        revision.item_type
+                    );
+                    ApiError::Internal
+                })?;
+
+            let subtype =
+                registration.restore(id, revision.payload, user, conn)?;
+            let item = items::table.filter(items::id.eq(id)).first(conn)?;
+            Ok(ViewItem::make(item, subtype))
+        })
+    }
+}
+
+/// The fields of two subtypes that differ, by name
+fn diff(from: &Value, to: &Value) -> Vec<Change> {
+    let mut fields = BTreeMap::<&str, (Value, Value)>::new();
+    if let Value::Object(from) = from {
+        for (field, value) in from {
+            fields.entry(field).or_default().0 = value.clone();
+        }
+    }
+    if let Value::Object(to) = to {
+        for (field, value) in to {
+            fields.entry(field).or_default().1 = value.clone();
+        }
+    }
+
+    fields
+        .into_iter()
+        .filter(|(_, (from, to))| from != to)
+        .map(|(field, (from, to))| Change { field: field.into(), from, to })
+        .collect()
+}
+
+impl Revision {
+    pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::find_all);
+        cfg.service(routes::diff);
+        cfg.service(routes::restore);
+    }
+}
+
+mod routes {
+    use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+    use uuid::Uuid;
+
+    use crate::{
+        database::exec_on_pool,
+        users::access_token::Scope,
+        utils::{authorize, responsable::Responsable},
+        DbPool,
+    };
+
+    use super::{DiffQuery, Revision};
+
+    #[get("/items/{id}/revisions")]
+    pub async fn find_all(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsRead)?;
+
+        exec_on_pool(&pool, move |conn| {
+            Revision::find_all(id.into_inner(), &user, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[get("/items/{id}/revisions/diff")]
+    pub async fn diff(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+        query: web::Query<DiffQuery>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsRead)?;
+
+        exec_on_pool(&pool, move |conn| {
+            Revision::diff(id.into_inner(), &query, &user, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[post("/items/{id}/revisions/{revision}/restore")]
+    pub async fn restore(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        path: web::Path<(Uuid, i32)>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        let (id, revision) = path.into_inner();
+
+        exec_on_pool(&pool, move |conn| {
+            Revision::restore(id, revision, user, conn)
+        })
+        .await
+        .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, Method, StatusCode},
+        test::{read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use super::{diff, Change, Revision};
+    use crate::items::registry;
+    use crate::testing::call_status;
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[test]
+    fn test_diff_fields() {
+        let from = json!({ "title": "chores", "is_checked": false, "x": 1 });
+        let to = json!({ "title": "chores", "is_checked": true, "y": 2 });
+
+        assert_eq!(
+            diff(&from, &to),
+            [
+                Change {
+                    field: "is_checked".into(),
+                    from: json!(false),
+                    to: json!(true),
+                },
+                Change { field: "x".into(), from: json!(1), to: Value::Null },
+                Change { field: "y".into(), from: Value::Null, to: json!(2) },
+            ]
+        );
+        assert!(diff(&from, &from).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_revisions() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(registry::routes)
+                            .configure(Revision::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "reviser");
+                let (_, other) = login!(app, "snooper");
+
+                macro_rules! send {
+                    ($method:expr, $uri:expr, $body:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            TestRequest::default()
+                                .method($method)
+                                .uri(&$uri)
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .set_json(&$body)
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+
+                let page = send!(Method::POST, "/pages", json!({ "title": "draft" }));
+                let id = page["item"]["id"].as_str().unwrap().to_owned();
+                let page_uri = format!("/pages/{}", id);
+                send!(Method::PATCH, page_uri, json!({ "title": "second" }));
+                send!(Method::PATCH, page_uri, json!({ "title": "final" }));
+
+                let revisions_uri = format!("/items/{}/revisions", id);
+                let revisions = send!(Method::GET, revisions_uri, ());
+                assert_eq!(revisions.as_array().unwrap().len(), 2);
+                assert_eq!(revisions[0]["revision"], 2);
+                assert_eq!(revisions[0]["payload"]["title"], "second");
+                assert_eq!(revisions[1]["payload"]["title"], "draft");
+
+                let changes = send!(
+                    Method::GET,
+                    format!("{}/diff?from=1&to=2", revisions_uri),
+                    ()
+                );
+                assert_eq!(
+                    changes,
+                    json!([{ "field": "title", "from": "draft", "to": "second" }])
+                );
+
+                // Restoring keeps the version it replaces too
+                let restored = send!(
+                    Method::POST,
+                    format!("{}/1/restore", revisions_uri),
+                    ()
+                );
+                assert_eq!(restored["subtype"]["Page"]["title"], "draft");
+                let revisions = send!(Method::GET, revisions_uri, ());
+                assert_eq!(revisions[0]["revision"], 3);
+                assert_eq!(revisions[0]["payload"]["title"], "final");
+
+                let request = TestRequest::post()
+                    .uri(&format!("{}/9/restore", revisions_uri))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .to_request();
+                assert_eq!(call_status(&mut app, request).await, StatusCode::NOT_FOUND);
+                let request = TestRequest::get()
+                    .uri(&revisions_uri)
+                    .header(header::AUTHORIZATION, other)
+                    .to_request();
+                assert_eq!(call_status(&mut app, request).await, StatusCode::NOT_FOUND);
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Full-text search through every searchable type of item.
+//!
+//! The text of each type is indexed in a `search` tsvector column, which is
+//! generated by the database (see the `add_search_vectors` migration). The
+//! query uses the `websearch_to_tsquery` syntax, so quoted phrases, `or` and
+//! `-word` work like they do in a search engine.
+
+use std::collections::HashMap;
+
+use diesel::sql_types::{
+    Array, BigInt, Bool, Float4, Nullable, SmallInt, Text, Uuid as SqlUuid,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::items;
+use crate::users::User;
+use crate::utils::error::{ApiError, FieldErrors};
+
+use super::item::Item;
+use super::listing::tag_ids;
+use super::reex_diesel::*;
+use super::registry::REGISTRY;
+use super::template::TEMPLATE_SUBTREE;
+use super::{ItemType, ViewItem};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+/// Where `ts_headline` starts and stops a match. Control characters are
+/// used so the snippet can be escaped before they're turned into `<mark>`.
+const START_SEL: char = '\u{1}';
+const STOP_SEL: char = '\u{2}';
+
+/// The query string of `GET /api/search`
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub item_type: Option<ItemType>,
+    /// Comma separated tag ids, matches items with any of the tags
+    pub tags: Option<String>,
+    /// Includes the templates and what's on them, they're left out otherwise
+    #[serde(default)]
+    pub templates: bool,
+    pub limit: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct Hit {
+    #[sql_type = "SqlUuid"]
+    id: Uuid,
+    #[sql_type = "Float4"]
+    rank: f32,
+    #[sql_type = "Text"]
+    snippet: String,
+}
+
+/// An item that matched, best matches first. The snippet is HTML escaped,
+/// with the matched words wrapped in `<mark>` tags.
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    item: ViewItem,
+    rank: f32,
+    snippet: String,
+}
+
+/// The text of every searchable type, in one relation
+fn searchable() -> String {
+    REGISTRY
+        .iter()
+        .filter_map(|registration| registration.search.as_ref())
+        .map(|search| {
+            format!(
+                "SELECT id, item_type, {} AS text, search FROM {}",
+                search.text, search.table
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ")
+}
+
+/// Escapes the snippet and marks the words `ts_headline` selected
+fn highlight(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            START_SEL => html.push_str("<mark>"),
+            STOP_SEL => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+pub fn search(
+    query: &SearchQuery,
+    user: User,
+    conn: &PgConnection,
+) -> Result<Vec<SearchResult>, ApiError> {
+    let mut errors = FieldErrors::default();
+    if query.q.trim().is_empty() {
+        errors.add("q", "can't be empty.");
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        errors.add("limit", format!("must be between 1 and {}.", MAX_LIMIT));
+    }
+    let tag_ids = tag_ids(query.tags.as_deref(), &mut errors);
+    errors.into_result()?;
+
+    // The selection markers are removed from the text first, so only the
+    // ones ts_headline adds end up as <mark> tags
+    let hits = diesel::sql_query(format!(
+        "SELECT s.id, ts_rank(s.search, q) AS rank,
+                ts_headline('simple', translate(s.text, E'\\x01\\x02', ''),
+                            q, E'StartSel=\"\\x01\", StopSel=\"\\x02\", MaxWords=30')
+                    AS snippet
+         FROM ({}) AS s
+         JOIN items ON items.id = s.id AND items.item_type = s.item_type,
+              websearch_to_tsquery('simple', $1) AS q
+         WHERE items.owner_id = $2
+           AND items.deleted_at IS NULL
+           AND s.search @@ q
+           AND ($3::smallint IS NULL OR items.item_type = $3)
+           AND ($4::uuid[] IS NULL OR items.id IN (
+                SELECT item_id FROM tags_items WHERE tag_id = ANY($4)))
+           AND ($6 OR items.id NOT IN ({}$2{}))
+         ORDER BY rank DESC, s.id
+         LIMIT $5",
+        searchable(),
+        TEMPLATE_SUBTREE[0],
+        TEMPLATE_SUBTREE[1],
+    ))
+    .bind::<Text, _>(&query.q)
+    .bind::<SqlUuid, _>(user.id)
+    .bind::<Nullable<SmallInt>, _>(query.item_type)
+    .bind::<Nullable<Array<SqlUuid>>, _>(tag_ids)
+    .bind::<BigInt, _>(limit)
+    .bind::<Bool, _>(query.templates)
+    .load::<Hit>(conn)?;
+
+    let items = items::table
+        .filter(
+            items::id.eq_any(hits.iter().map(|hit| hit.id).collect::<Vec<_>>()),
+        )
+        .load::<Item>(conn)?;
+    let mut views = Item::view_all(items, conn)?
+        .into_iter()
+        .map(|view| (view.id(), view))
+        .collect::<HashMap<_, _>>();
+
+    Ok(hits
+        .into_iter()
+        .filter_map(|Hit { id, rank, snippet }| {
+            let item = views.remove(&id)?;
+            Some(SearchResult { item, rank, snippet: highlight(&snippet) })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use crate::items::{item::Item, registry};
+    use crate::tags::tag::Tag;
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[actix_rt::test]
+    async fn test_search() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(registry::routes)
+                            .configure(Tag::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "searcher");
+                let (_, other) = login!(app, "other");
+
+                macro_rules! post {
+                    ($bearer:expr, $uri:expr, $body:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            TestRequest::post()
+                                .uri($uri)
+                                .header(header::AUTHORIZATION, $bearer.clone())
+                                .set_json(&$body)
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+                macro_rules! search {
+                    ($query:expr) => {
+                        read_response_json::<_, _, Vec<Value>>(
+                            &mut app,
+                            TestRequest::get()
+                                .uri(&format!("/search?{}", $query))
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+
+                let page = post!(bearer, "/pages", json!({ "title": "Holiday in Norway" }));
+                let on_page = json!({
+                    "page_id": page["item"]["id"],
+                    "coord_x": 0,
+                    "coord_y": 0,
+                });
+                let mut todo = on_page.clone();
+                todo["title"] = json!("Book flights to Norway");
+                let todo = post!(bearer, "/todos", todo);
+                let mut text_field = on_page.clone();
+                text_field["text"] = json!("Norway has fjords, lots of fjords");
+                post!(bearer, "/text_fields", text_field);
+                let mut script = on_page.clone();
+                script["text"] = json!("<<script>alert('hi')//<</script> & more");
+                post!(bearer, "/text_fields", script);
+                post!(bearer, "/pages", json!({ "title": "Groceries" }));
+                post!(other, "/pages", json!({ "title": "Norway" }));
+
+                let results = search!("q=norway");
+                assert_eq!(results.len(), 3);
+                assert!(results
+                    .iter()
+                    .all(|result| result["snippet"].as_str().unwrap().contains("<mark>")));
+
+                let results = search!("q=fjords");
+                assert_eq!(results.len(), 1);
+                assert!(results[0]["subtype"]["TextField"].is_object());
+                assert_eq!(
+                    results[0]["snippet"],
+                    "Norway has <mark>fjords</mark>, lots of <mark>fjords</mark>"
+                );
+
+                let results = search!("q=more");
+                assert_eq!(results.len(), 1);
+                let snippet = results[0]["snippet"].as_str().unwrap();
+                assert!(snippet.contains("<mark>more</mark>"));
+                assert!(snippet.contains("&lt;") && snippet.contains("&amp;"));
+                assert!(!snippet
+                    .replace("<mark>", "")
+                    .replace("</mark>", "")
+                    .contains('<'));
+
+                let results = search!("q=norway&item_type=100");
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0]["subtype"]["Page"]["title"], "Holiday in Norway");
+
+                let tag = post!(bearer, "/tags", json!({ "name": "travel", "color": "0x00FF00" }));
+                let request = TestRequest::patch()
+                    .uri(&format!("/tags/{}/items", tag["id"].as_str().unwrap()))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!([{ "id": todo["item"]["id"], "item_type": 200 }]))
+                    .to_request();
+                call_service(&mut app, request).await;
+
+                let results = search!(format!("q=norway&tags={}", tag["id"].as_str().unwrap()));
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0]["item"]["id"], todo["item"]["id"]);
+
+                let request = TestRequest::get()
+                    .uri("/search?q=%20")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                Ok(())
+            }
+        }
+    }
+}
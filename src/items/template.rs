@@ -0,0 +1,420 @@
+//! Page templates.
+//!
+//! A template is a page that new pages are copied from, along with
+//! everything on it. The text of the copies can hold placeholders, which
+//! are filled in with the day it is for the user in their timezone:
+//!
+//! - `{{date}}`, e.g. `2020-06-24`
+//! - `{{weekday}}`, e.g. `Wednesday`
+//! - `{{week_number}}`, the ISO week, e.g. `26`
+//!
+//! Every user starts out with the built-in templates. Templates, and what's
+//! on them, are left out of listings and search unless they're asked for.
+
+use chrono::{Datelike, NaiveDate, Utc};
+use chrono_tz::Tz;
+use diesel::dsl::sql;
+use diesel::pg::Pg;
+use diesel::sql_types::{Array, Bool, Text, Uuid as SqlUuid};
+use uuid::Uuid;
+
+use crate::schema::{items, pages};
+use crate::users::User;
+use crate::utils::{error::ApiError, policy::Policy};
+
+use super::crud2::intermediate;
+use super::item::Item;
+use super::ordering::Placement;
+use super::page::{NewPage, Page};
+use super::reex_diesel::*;
+use super::registry::REGISTRY;
+use super::text_field::{NewTextField, TextField};
+use super::todo::{NewTodo, Todo};
+use super::todo_item::{NewTodoItem, TodoItem};
+use super::{duplicate, ordering, ViewItem};
+
+const PLACEHOLDERS: [&str; 3] = ["date", "weekday", "week_number"];
+
+/// How far apart things are placed on the built-in templates
+const SPACING: i32 = 200;
+
+/// A template every user starts out with
+struct Builtin {
+    title: &'static str,
+    /// The todos on the page, with their items
+    todos: &'static [(&'static str, &'static [&'static str])],
+    text_fields: &'static [&'static str],
+}
+
+const BUILTIN: [Builtin; 2] = [
+    Builtin {
+        title: "Daily log {{date}}",
+        todos: &[(
+            "{{weekday}}",
+            &["Review yesterday", "Pick three priorities"],
+        )],
+        text_fields: &["Notes"],
+    },
+    Builtin {
+        title: "Weekly review, week {{week_number}}",
+        todos: &[("Next week", &["Plan the big tasks"])],
+        text_fields: &["What went well?", "What could have gone better?"],
+    },
+];
+
+/// The values of the placeholders on the day, in the order of
+/// [`PLACEHOLDERS`](constant.PLACEHOLDERS.html)
+fn values(day: NaiveDate) -> Vec<String> {
+    vec![
+        day.format("%Y-%m-%d").to_string(),
+        day.format("%A").to_string(),
+        day.iso_week().week().to_string(),
+    ]
+}
+
+/// The day it is for the user
+fn today(user: &User) -> NaiveDate {
+    let timezone = user.timezone.parse::<Tz>().unwrap_or(Tz::UTC);
+
+    Utc::now().with_timezone(&timezone).naive_local().date()
+}
+
+/// Fills in the placeholders in the text of the items
+fn fill(ids: &[Uuid], day: NaiveDate, conn: &PgConnection) -> QueryResult<()> {
+    for search in REGISTRY.iter().filter_map(|r| r.search.as_ref()) {
+        let text = PLACEHOLDERS.iter().enumerate().fold(
+            search.text.to_owned(),
+            |text, (i, placeholder)| {
+                format!(
+                    "replace({}, '{{{{{}}}}}', $1[{}])",
+                    text,
+                    placeholder,
+                    i + 1
+                )
+            },
+        );
+
+        diesel::sql_query(format!(
+            "UPDATE {} SET {} = {} WHERE id = ANY($2)",
+            search.table, search.text, text
+        ))
+        .bind::<Array<Text>, _>(values(day))
+        .bind::<Array<SqlUuid>, _>(ids)
+        .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// The ids of the templates of an owner and everything on them, split
+/// where the id of the owner goes
+pub(super) const TEMPLATE_SUBTREE: [&str; 2] = [
+    "WITH RECURSIVE subtree AS (
+         SELECT pages.id FROM pages
+         JOIN items ON items.id = pages.id
+         WHERE pages.is_template AND items.owner_id = ",
+    "
+         UNION
+         SELECT items.id FROM items
+         JOIN subtree ON items.parent_id = subtree.id
+     )
+     SELECT id FROM subtree",
+];
+
+/// Matches the templates of the user and everything on them
+pub(super) fn on_templates(
+    user: &User,
+) -> Box<dyn BoxableExpression<items::table, Pg, SqlType = Bool>> {
+    Box::new(
+        sql::<Bool>(&format!("items.id IN ({}", TEMPLATE_SUBTREE[0]))
+            .bind::<SqlUuid, _>(user.id)
+            .sql(&format!("{})", TEMPLATE_SUBTREE[1])),
+    )
+}
+
+/// The templates of the user, in their order
+pub fn find_all(
+    user: &User,
+    conn: &PgConnection,
+) -> Result<Vec<ViewItem>, ApiError> {
+    let templates = items::table
+        .filter(items::owner_id.eq(user.id))
+        .filter(items::deleted_at.is_null())
+        .filter(items::id.eq_any(
+            pages::table.filter(pages::is_template.eq(true)).select(pages::id),
+        ))
+        .order((items::position, items::id))
+        .load::<Item>(conn)?;
+
+    Item::view_all(templates, conn)
+}
+
+/// Makes a new page from the template, next to the other pages in the
+/// parent of the template
+pub fn instantiate(
+    id: Uuid,
+    user: &User,
+    conn: &PgConnection,
+) -> Result<ViewItem, ApiError> {
+    Page::authorize(id, user, conn)?;
+
+    conn.transaction(|| {
+        let (template, is_template) = items::table
+            .inner_join(pages::table.on(pages::id.eq(items::id)))
+            .filter(items::id.eq(id))
+            .select((items::all_columns, pages::is_template))
+            .first::<(Item, bool)>(conn)?;
+        if !is_template {
+            return Err(ApiError::Unprocessable(
+                "The page isn't a template.".into(),
+            ));
+        }
+        let position = ordering::position(
+            template.parent_id,
+            Placement::default(),
+            None,
+            user,
+            conn,
+        )?;
+
+        let mut copies = duplicate::copy(template, position, conn)?;
+        let ids = copies.iter().map(|item| item.id).collect::<Vec<_>>();
+        fill(&ids, today(user), conn)?;
+
+        copies.truncate(1);
+        Item::view_all(copies, conn)?.pop().ok_or(ApiError::Internal)
+    })
+}
+
+/// Gives a new user the built-in templates
+pub fn seed(user: &User, conn: &PgConnection) -> Result<(), ApiError> {
+    for builtin in BUILTIN.iter() {
+        let page = intermediate::create::<Page>(
+            NewPage {
+                title: builtin.title.into(),
+                parent_id: None,
+                is_template: true,
+                placement: Placement::default(),
+            },
+            user.clone(),
+            conn,
+        )?;
+
+        let mut coord_y = 0;
+        for (title, todo_items) in builtin.todos {
+            let todo = intermediate::create::<Todo>(
+                NewTodo {
+                    title: (*title).into(),
+                    page_id: page.id(),
+                    coord_x: 0,
+                    coord_y,
+                    placement: Placement::default(),
+                },
+                user.clone(),
+                conn,
+            )?;
+            for title in todo_items.iter() {
+                intermediate::create::<TodoItem>(
+                    NewTodoItem {
+                        title: (*title).into(),
+                        todo_id: todo.id(),
+                        is_checked: false,
+                        placement: Placement::default(),
+                    },
+                    user.clone(),
+                    conn,
+                )?;
+            }
+            coord_y += SPACING;
+        }
+        for text in builtin.text_fields {
+            intermediate::create::<TextField>(
+                NewTextField {
+                    text: (*text).into(),
+                    page_id: page.id(),
+                    coord_x: 0,
+                    coord_y,
+                    placement: Placement::default(),
+                },
+                user.clone(),
+                conn,
+            )?;
+            coord_y += SPACING;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use chrono::NaiveDate;
+    use serde_json::{json, Value};
+
+    use super::{today, values};
+    use crate::items::{item::Item, registry};
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[test]
+    fn test_placeholder_values() {
+        assert_eq!(
+            values(NaiveDate::from_ymd(2020, 6, 24)),
+            ["2020-06-24", "Wednesday", "26"]
+        );
+        // The first days of January can be in the last week of the year
+        assert_eq!(values(NaiveDate::from_ymd(2021, 1, 2))[2], "53");
+    }
+
+    #[test]
+    fn test_today_is_in_the_timezone_of_the_user() {
+        let user = |timezone: &str| User {
+            id: uuid::Uuid::new_v4(),
+            username: "traveller".into(),
+            password: String::new(),
+            email: None,
+            email_verified_at: None,
+            timezone: timezone.into(),
+        };
+
+        // These are 25 hours apart, so they're never on the same day
+        assert_ne!(
+            today(&user("Pacific/Kiritimati")),
+            today(&user("Pacific/Pago_Pago"))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_templates() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(registry::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (user, bearer) = login!(app, "templater");
+                let values = values(today(&user));
+
+                macro_rules! get {
+                    ($uri:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            TestRequest::get()
+                                .uri(&$uri)
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+
+                // New users get the built-in templates
+                let templates = get!("/templates");
+                let titles = templates
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|template| template["subtype"]["Page"]["title"].clone())
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    titles,
+                    [
+                        "Daily log {{date}}",
+                        "Weekly review, week {{week_number}}"
+                    ]
+                );
+                let daily_log = templates[0]["item"]["id"].as_str().unwrap();
+
+                // They're kept out of the way until they're asked for
+                assert_eq!(get!("/items")["items"], json!([]));
+                assert_eq!(get!("/search?q=review"), json!([]));
+                let listed = get!("/items?templates=true&parent_id=".to_owned() + daily_log);
+                assert_eq!(listed["items"].as_array().unwrap().len(), 2);
+                assert_eq!(get!("/search?q=review&templates=true").as_array().unwrap().len(), 2);
+
+                let page: Value = read_response_json(
+                    &mut app,
+                    TestRequest::post()
+                        .uri(&format!("/pages/from-template/{}", daily_log))
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .to_request(),
+                )
+                .await;
+                assert_eq!(
+                    page["subtype"]["Page"]["title"],
+                    format!("Daily log {}", values[0])
+                );
+                assert_eq!(page["subtype"]["Page"]["is_template"], false);
+
+                let page_id = page["item"]["id"].as_str().unwrap();
+                let on_page = get!(format!("/items?parent_id={}", page_id));
+                assert_eq!(on_page["items"][0]["subtype"]["Todo"]["title"], values[1]);
+                assert_eq!(on_page["items"][1]["subtype"]["TextField"]["text"], "Notes");
+
+                // The template itself keeps its placeholders
+                let templates = get!("/templates");
+                assert_eq!(templates.as_array().unwrap().len(), 2);
+                assert_eq!(
+                    templates[0]["subtype"]["Page"]["title"],
+                    "Daily log {{date}}"
+                );
+
+                // Only templates can be made into pages
+                let resp = call_service(
+                    &mut app,
+                    TestRequest::post()
+                        .uri(&format!("/pages/from-template/{}", page_id))
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .to_request(),
+                )
+                .await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                // Duplicating a template makes a page, placeholders and all
+                let copy: Value = read_response_json(
+                    &mut app,
+                    TestRequest::post()
+                        .uri(&format!("/pages/{}/duplicate", daily_log))
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .to_request(),
+                )
+                .await;
+                assert_eq!(copy["subtype"]["Page"]["title"], "Daily log {{date}}");
+                assert_eq!(copy["subtype"]["Page"]["is_template"], false);
+                assert_eq!(get!("/templates").as_array().unwrap().len(), 2);
+
+                // Titles of pages get filled in too
+                let weekly_review = templates[1]["item"]["id"].as_str().unwrap();
+                let page: Value = read_response_json(
+                    &mut app,
+                    TestRequest::post()
+                        .uri(&format!("/pages/from-template/{}", weekly_review))
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .set_json(&json!({}))
+                        .to_request(),
+                )
+                .await;
+                assert_eq!(
+                    page["subtype"]["Page"]["title"],
+                    format!("Weekly review, week {}", values[2])
+                );
+
+                Ok(())
+            }
+        }
+    }
+}
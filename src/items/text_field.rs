@@ -1,14 +1,15 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::items::Items;
 use crate::{
     items::{ItemTypeNames, TypeMarker},
     schema::text_fields,
+    utils::patch::Changes,
 };
 
 use super::{
     crud2::{raw_crud, ModelFromPartial},
+    ordering::Placement,
     reex_diesel::*,
     ItemLike, ItemType,
 };
@@ -28,14 +29,22 @@ pub struct NewTextField {
     pub page_id: Uuid,
     pub coord_x: i32,
     pub coord_y: i32,
+    #[serde(flatten)]
+    pub placement: Placement,
 }
 
 #[derive(Deserialize, AsChangeset)]
 #[table_name = "text_fields"]
 pub struct UpdateTextField {
-    pub text: String,
-    pub coord_x: i32,
-    pub coord_y: i32,
+    pub text: Option<String>,
+    pub coord_x: Option<i32>,
+    pub coord_y: Option<i32>,
+}
+
+impl Changes for UpdateTextField {
+    fn is_empty(&self) -> bool {
+        self.text.is_none() && self.coord_x.is_none() && self.coord_y.is_none()
+    }
 }
 
 impl TypeMarker for TextField {
@@ -58,11 +67,9 @@ impl ItemLike for NewTextField {
     fn parent_type(&self) -> Option<i16> {
         Some(ItemTypeNames::Page as i16)
     }
-}
 
-impl From<TextField> for Items {
-    fn from(text_field: TextField) -> Self {
-        Self::TextField(text_field)
+    fn placement(&self) -> Placement {
+        self.placement
     }
 }
 
@@ -110,6 +117,12 @@ impl raw_crud::Find for TextField {
             .filter(text_fields::item_type.eq(Self::TYPE as i16))
             .get_result(conn)
     }
+
+    find_all!(text_fields);
+}
+
+impl raw_crud::Duplicate for TextField {
+    duplicate!(text_fields);
 }
 
 impl raw_crud::Delete for TextField {
@@ -133,7 +146,12 @@ mod routes {
     };
     use uuid::Uuid;
 
-    use crate::{items::crud2::crud2http, DbPool};
+    use crate::{
+        items::crud2::crud2http,
+        users::access_token::Scope,
+        utils::{authorize, etag::Conditions, patch::Patch},
+        DbPool,
+    };
 
     use super::{NewTextField, TextField, UpdateTextField};
 
@@ -143,7 +161,7 @@ mod routes {
         req: HttpRequest,
         form: web::Json<NewTextField>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsWrite)?;
         crud2http::create::<TextField, _>(form.into_inner(), user, &pool).await
     }
 
@@ -153,8 +171,14 @@ mod routes {
         req: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
-        crud2http::find::<TextField>(id.into_inner(), user, &pool).await
+        let user = authorize(&req, Scope::ItemsRead)?;
+        crud2http::find::<TextField>(
+            id.into_inner(),
+            Conditions::from_request(&req),
+            user,
+            &pool,
+        )
+        .await
     }
 
     #[patch("/text_fields/{id}")]
@@ -162,13 +186,15 @@ mod routes {
         pool: web::Data<DbPool>,
         req: HttpRequest,
         id: web::Path<Uuid>,
-        form: web::Json<UpdateTextField>,
+        body: web::Json<serde_json::Value>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        let patch = Patch::from_request(&req, body.into_inner())?;
 
-        crud2http::update::<TextField, _>(
+        crud2http::update::<TextField, UpdateTextField>(
             id.into_inner(),
-            form.into_inner(),
+            patch,
+            Conditions::from_request(&req),
             user,
             &pool,
         )
@@ -181,7 +207,13 @@ mod routes {
         req: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
-        crud2http::delete::<TextField>(id.into_inner(), user, &pool).await
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        crud2http::delete::<TextField>(
+            id.into_inner(),
+            Conditions::from_request(&req),
+            user,
+            &pool,
+        )
+        .await
     }
 }
@@ -1,14 +1,15 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::items::Items;
 use crate::{
     items::{ItemTypeNames, TypeMarker},
     schema::todos,
+    utils::patch::Changes,
 };
 
 use super::{
     crud2::{raw_crud, ModelFromPartial},
+    ordering::Placement,
     reex_diesel::*,
     ItemLike, ItemType,
 };
@@ -28,14 +29,22 @@ pub struct NewTodo {
     pub page_id: Uuid,
     pub coord_x: i32,
     pub coord_y: i32,
+    #[serde(flatten)]
+    pub placement: Placement,
 }
 
 #[derive(Deserialize, AsChangeset)]
 #[table_name = "todos"]
 pub struct UpdateTodo {
-    title: String,
-    pub coord_x: i32,
-    pub coord_y: i32,
+    title: Option<String>,
+    pub coord_x: Option<i32>,
+    pub coord_y: Option<i32>,
+}
+
+impl Changes for UpdateTodo {
+    fn is_empty(&self) -> bool {
+        self.title.is_none() && self.coord_x.is_none() && self.coord_y.is_none()
+    }
 }
 
 impl TypeMarker for Todo {
@@ -58,11 +67,9 @@ impl ItemLike for NewTodo {
     fn parent_type(&self) -> Option<i16> {
         Some(ItemTypeNames::Page as i16)
     }
-}
 
-impl From<Todo> for Items {
-    fn from(todo: Todo) -> Self {
-        Self::Todo(todo)
+    fn placement(&self) -> Placement {
+        self.placement
     }
 }
 
@@ -107,6 +114,12 @@ impl raw_crud::Find for Todo {
             .filter(todos::item_type.eq(Self::TYPE as i16))
             .get_result(conn)
     }
+
+    find_all!(todos);
+}
+
+impl raw_crud::Duplicate for Todo {
+    duplicate!(todos);
 }
 
 impl raw_crud::Delete for Todo {
@@ -130,7 +143,12 @@ mod routes {
     };
     use uuid::Uuid;
 
-    use crate::{items::crud2::crud2http, DbPool};
+    use crate::{
+        items::crud2::crud2http,
+        users::access_token::Scope,
+        utils::{authorize, etag::Conditions, patch::Patch},
+        DbPool,
+    };
 
     use super::{NewTodo, Todo, UpdateTodo};
 
@@ -140,7 +158,7 @@ mod routes {
         req: HttpRequest,
         form: web::Json<NewTodo>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsWrite)?;
         crud2http::create::<Todo, _>(form.into_inner(), user, &pool).await
     }
 
@@ -150,8 +168,14 @@ mod routes {
         req: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
-        crud2http::find::<Todo>(id.into_inner(), user, &pool).await
+        let user = authorize(&req, Scope::ItemsRead)?;
+        crud2http::find::<Todo>(
+            id.into_inner(),
+            Conditions::from_request(&req),
+            user,
+            &pool,
+        )
+        .await
     }
 
     #[patch("/todos/{id}")]
@@ -159,13 +183,15 @@ mod routes {
         pool: web::Data<DbPool>,
         req: HttpRequest,
         id: web::Path<Uuid>,
-        form: web::Json<UpdateTodo>,
+        body: web::Json<serde_json::Value>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        let patch = Patch::from_request(&req, body.into_inner())?;
 
-        crud2http::update::<Todo, _>(
+        crud2http::update::<Todo, UpdateTodo>(
             id.into_inner(),
-            form.into_inner(),
+            patch,
+            Conditions::from_request(&req),
             user,
             &pool,
         )
@@ -178,7 +204,13 @@ mod routes {
         req: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
-        crud2http::delete::<Todo>(id.into_inner(), user, &pool).await
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        crud2http::delete::<Todo>(
+            id.into_inner(),
+            Conditions::from_request(&req),
+            user,
+            &pool,
+        )
+        .await
     }
 }
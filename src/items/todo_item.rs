@@ -1,14 +1,15 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::items::Items;
 use crate::{
     items::{ItemTypeNames, TypeMarker},
     schema::todo_items,
+    utils::patch::Changes,
 };
 
 use super::{
     crud2::{raw_crud, ModelFromPartial},
+    ordering::Placement,
     reex_diesel::*,
     ItemLike, ItemType,
 };
@@ -26,13 +27,21 @@ pub struct NewTodoItem {
     pub title: String,
     pub todo_id: Uuid,
     pub is_checked: bool,
+    #[serde(flatten)]
+    pub placement: Placement,
 }
 
 #[derive(Deserialize, AsChangeset)]
 #[table_name = "todo_items"]
 pub struct UpdateTodoItem {
-    pub title: String,
-    pub is_checked: bool,
+    pub title: Option<String>,
+    pub is_checked: Option<bool>,
+}
+
+impl Changes for UpdateTodoItem {
+    fn is_empty(&self) -> bool {
+        self.title.is_none() && self.is_checked.is_none()
+    }
 }
 
 impl TypeMarker for TodoItem {
@@ -55,11 +64,9 @@ impl ItemLike for NewTodoItem {
     fn parent_type(&self) -> Option<i16> {
         Some(ItemTypeNames::Todo as i16)
     }
-}
 
-impl From<TodoItem> for Items {
-    fn from(todo_item: TodoItem) -> Self {
-        Self::TodoItem(todo_item)
+    fn placement(&self) -> Placement {
+        self.placement
     }
 }
 
@@ -106,6 +113,12 @@ impl raw_crud::Find for TodoItem {
             .filter(todo_items::item_type.eq(Self::TYPE as i16))
             .get_result(conn)
     }
+
+    find_all!(todo_items);
+}
+
+impl raw_crud::Duplicate for TodoItem {
+    duplicate!(todo_items);
 }
 
 impl raw_crud::Delete for TodoItem {
@@ -129,7 +142,12 @@ mod routes {
     };
     use uuid::Uuid;
 
-    use crate::{items::crud2::crud2http, DbPool};
+    use crate::{
+        items::crud2::crud2http,
+        users::access_token::Scope,
+        utils::{authorize, etag::Conditions, patch::Patch},
+        DbPool,
+    };
 
     use super::{NewTodoItem, TodoItem, UpdateTodoItem};
 
@@ -139,7 +157,7 @@ mod routes {
         req: HttpRequest,
         form: web::Json<NewTodoItem>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsWrite)?;
         crud2http::create::<TodoItem, _>(form.into_inner(), user, &pool).await
     }
 
@@ -149,8 +167,14 @@ mod routes {
         req: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
-        crud2http::find::<TodoItem>(id.into_inner(), user, &pool).await
+        let user = authorize(&req, Scope::ItemsRead)?;
+        crud2http::find::<TodoItem>(
+            id.into_inner(),
+            Conditions::from_request(&req),
+            user,
+            &pool,
+        )
+        .await
     }
 
     #[patch("/todo_items/{id}")]
@@ -158,13 +182,15 @@ mod routes {
         pool: web::Data<DbPool>,
         req: HttpRequest,
         id: web::Path<Uuid>,
-        form: web::Json<UpdateTodoItem>,
+        body: web::Json<serde_json::Value>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        let patch = Patch::from_request(&req, body.into_inner())?;
 
-        crud2http::update::<TodoItem, _>(
+        crud2http::update::<TodoItem, UpdateTodoItem>(
             id.into_inner(),
-            form.into_inner(),
+            patch,
+            Conditions::from_request(&req),
             user,
             &pool,
         )
@@ -177,7 +203,13 @@ mod routes {
         req: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
-        crud2http::delete::<TodoItem>(id.into_inner(), user, &pool).await
+        let user = authorize(&req, Scope::ItemsWrite)?;
+        crud2http::delete::<TodoItem>(
+            id.into_inner(),
+            Conditions::from_request(&req),
+            user,
+            &pool,
+        )
+        .await
     }
 }
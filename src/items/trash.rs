@@ -0,0 +1,454 @@
+//! The trash bin.
+//!
+//! Deleting an item moves it to the trash, along with everything inside it.
+//! Trashed items are hidden everywhere else until they are restored, or until
+//! they are purged. Items are purged automatically once they've been in the
+//! trash for `TRASH_RETENTION_DAYS` (default 30).
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::sql_types::{SmallInt, Uuid as SqlUuid};
+use uuid::Uuid;
+
+use crate::schema::items;
+use crate::users::User;
+use crate::utils::{config::env_or, error::ApiError};
+use crate::DbPool;
+
+use super::item::Item;
+use super::reex_diesel::*;
+use super::{ItemType, ViewItem};
+
+/// How often trashed items are checked for expiry
+const PURGE_INTERVAL_SECONDS: u64 = 60 * 60;
+
+/// How long items stay in the trash when `TRASH_RETENTION_DAYS` isn't set
+const DEFAULT_RETENTION_DAYS: u32 = 30;
+
+pub struct Trash;
+
+impl Trash {
+    /// Reads how long items stay in the trash, from `TRASH_RETENTION_DAYS`
+    pub fn retention_from_env() -> Result<Duration, Box<dyn Error>> {
+        let days = env_or("TRASH_RETENTION_DAYS", DEFAULT_RETENTION_DAYS)?;
+
+        Ok(Duration::days(days.into()))
+    }
+
+    /// Moves the item and everything inside it to the trash. Items inside
+    /// it that were already trashed keep their own deletion time.
+    pub(crate) fn delete(
+        id: Uuid,
+        item_type: ItemType,
+        conn: &PgConnection,
+    ) -> QueryResult<()> {
+        let deleted = diesel::sql_query(
+            "WITH RECURSIVE subtree AS (
+                 SELECT id FROM items
+                 WHERE id = $1 AND item_type = $2 AND deleted_at IS NULL
+                 UNION
+                 SELECT items.id FROM items
+                 JOIN subtree ON items.parent_id = subtree.id
+                 WHERE items.deleted_at IS NULL
+             )
+             UPDATE items SET deleted_at = now()
+             WHERE id IN (SELECT id FROM subtree)",
+        )
+        .bind::<SqlUuid, _>(id)
+        .bind::<SmallInt, _>(item_type)
+        .execute(conn)?;
+
+        match deleted {
+            0 => Err(diesel::result::Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// The trashed items that weren't trashed along with their parent,
+    /// most recently deleted first.
+    fn find_all(
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<Vec<ViewItem>, ApiError> {
+        let trashed = items::table
+            .filter(items::owner_id.eq(user.id))
+            .filter(items::deleted_at.is_not_null())
+            .order((items::deleted_at.desc(), items::id))
+            .load::<Item>(conn)?;
+
+        let deleted_at = trashed
+            .iter()
+            .map(|item| (item.id, item.deleted_at))
+            .collect::<HashMap<_, _>>();
+        let roots = trashed
+            .into_iter()
+            .filter(|item| {
+                item.parent_id.and_then(|parent_id| deleted_at.get(&parent_id))
+                    != Some(&item.deleted_at)
+            })
+            .collect();
+
+        Item::view_all(roots, conn)
+    }
+
+    fn find_trashed(
+        id: Uuid,
+        user: &User,
+        conn: &PgConnection,
+    ) -> QueryResult<Item> {
+        items::table
+            .filter(items::id.eq(id))
+            .filter(items::owner_id.eq(user.id))
+            .filter(items::deleted_at.is_not_null())
+            .first(conn)
+    }
+
+    /// Restores the item, with everything that was trashed along with it
+    fn restore(
+        id: Uuid,
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<ViewItem, ApiError> {
+        conn.transaction(|| {
+            let item = Self::find_trashed(id, user, conn)?;
+
+            if let Some(parent_id) = item.parent_id {
+                let parent_deleted_at = items::table
+                    .filter(items::id.eq(parent_id))
+                    .select(items::deleted_at)
+                    .first::<Option<DateTime<Utc>>>(conn)?;
+                if parent_deleted_at.is_some() {
+                    return Err(ApiError::Conflict(
+                        "The item is inside a trashed item, restore that \
+                         one first."
+                            .into(),
+                    ));
+                }
+            }
+
+            diesel::sql_query(
+                "WITH RECURSIVE subtree AS (
+                     SELECT id, deleted_at FROM items WHERE id = $1
+                     UNION
+                     SELECT items.id, items.deleted_at FROM items
+                     JOIN subtree ON items.parent_id = subtree.id
+                         AND items.deleted_at = subtree.deleted_at
+                 )
+                 UPDATE items SET deleted_at = NULL
+                 WHERE id IN (SELECT id FROM subtree)",
+            )
+            .bind::<SqlUuid, _>(id)
+            .execute(conn)?;
+
+            let item = items::table.filter(items::id.eq(id)).first(conn)?;
+            Item::view_all(vec![item], conn)?.pop().ok_or(ApiError::NotFound)
+        })
+    }
+
+    /// Deletes a trashed item for good, with everything inside it
+    fn purge(id: Uuid, user: &User, conn: &PgConnection) -> QueryResult<()> {
+        let item = Self::find_trashed(id, user, conn)?;
+
+        diesel::delete(items::table.find((item.id, item.item_type)))
+            .execute(conn)
+            .map(drop)
+    }
+
+    fn empty(user: &User, conn: &PgConnection) -> QueryResult<usize> {
+        diesel::delete(
+            items::table
+                .filter(items::owner_id.eq(user.id))
+                .filter(items::deleted_at.is_not_null()),
+        )
+        .execute(conn)
+    }
+
+    /// Deletes the items that have been in the trash for too long
+    pub fn purge_expired(
+        retention: Duration,
+        conn: &PgConnection,
+    ) -> QueryResult<usize> {
+        diesel::delete(
+            items::table.filter(items::deleted_at.lt(Utc::now() - retention)),
+        )
+        .execute(conn)
+    }
+
+    /// Purges expired items every hour, for as long as the server runs
+    pub async fn purge_periodically(pool: DbPool, retention: Duration) {
+        let mut interval = actix_rt::time::interval(StdDuration::from_secs(
+            PURGE_INTERVAL_SECONDS,
+        ));
+
+        loop {
+            interval.tick().await;
+
+            let purged = crate::database::exec_on_pool(&pool, move |conn| {
+                Self::purge_expired(retention, conn)
+            })
+            .await;
+            match purged {
+                Ok(0) => {}
+                Ok(purged) => log::info!("purged {} trashed items", purged),
+                Err(err) => log::error!("failed to purge the trash: {}", err),
+            }
+        }
+    }
+}
+
+impl Trash {
+    pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::find_all);
+        cfg.service(routes::restore);
+        cfg.service(routes::purge);
+        cfg.service(routes::empty);
+    }
+}
+
+mod routes {
+    use actix_web::{delete, get, post, web, Error, HttpRequest, HttpResponse};
+    use uuid::Uuid;
+
+    use crate::{
+        database::exec_on_pool,
+        users::access_token::Scope,
+        utils::{authorize, responsable::Responsable},
+        DbPool,
+    };
+
+    use super::Trash;
+
+    #[get("/trash")]
+    pub async fn find_all(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsRead)?;
+
+        exec_on_pool(&pool, move |conn| Trash::find_all(&user, conn))
+            .await
+            .into_response()
+    }
+
+    #[post("/trash/{id}/restore")]
+    pub async fn restore(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsWrite)?;
+
+        exec_on_pool(&pool, move |conn| {
+            Trash::restore(id.into_inner(), &user, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[delete("/trash/{id}")]
+    pub async fn purge(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsWrite)?;
+
+        exec_on_pool(&pool, move |conn| {
+            Trash::purge(id.into_inner(), &user, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[delete("/trash")]
+    pub async fn empty(
+        pool: web::Data<DbPool>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let user = authorize(&req, Scope::ItemsWrite)?;
+
+        exec_on_pool(&pool, move |conn| Trash::empty(&user, conn))
+            .await
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, Method, StatusCode},
+        test::{read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::Trash;
+    use crate::items::{item::Item, registry};
+    use crate::schema::items;
+    use crate::testing::{self, call_status};
+    use crate::users::User;
+    use crate::utils::validator;
+
+    #[actix_rt::test]
+    async fn test_trash() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(registry::routes)
+                            .configure(Trash::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (user, bearer) = login!(app, "trasher");
+                testing::remove_templates(&user);
+
+                macro_rules! request {
+                    ($method:expr, $uri:expr) => {
+                        TestRequest::default()
+                            .method($method)
+                            .uri(&$uri)
+                            .header(header::AUTHORIZATION, bearer.clone())
+                    };
+                }
+                macro_rules! status {
+                    ($method:expr, $uri:expr) => {
+                        call_status(&mut app, request!($method, $uri).to_request()).await
+                    };
+                }
+                macro_rules! send {
+                    ($method:expr, $uri:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            request!($method, $uri).to_request(),
+                        )
+                        .await
+                    };
+                    ($method:expr, $uri:expr, $body:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            request!($method, $uri).set_json(&$body).to_request(),
+                        )
+                        .await
+                    };
+                }
+
+                let page = send!(Method::POST, "/pages", json!({ "title": "old" }));
+                let page_id = page["item"]["id"].as_str().unwrap().to_owned();
+                let todo = send!(Method::POST, "/todos", json!({
+                    "title": "chores",
+                    "page_id": page_id,
+                    "coord_x": 0,
+                    "coord_y": 0,
+                }));
+                let todo_id = todo["item"]["id"].as_str().unwrap().to_owned();
+
+                let page_uri = format!("/pages/{}", page_id);
+                assert_eq!(status!(Method::DELETE, page_uri), StatusCode::OK);
+                assert_eq!(status!(Method::GET, page_uri), StatusCode::NOT_FOUND);
+                assert_eq!(status!(Method::DELETE, page_uri), StatusCode::NOT_FOUND);
+                assert_eq!(
+                    status!(Method::GET, format!("/todos/{}", todo_id)),
+                    StatusCode::NOT_FOUND
+                );
+                let items = send!(Method::GET, "/items");
+                assert_eq!(items["items"], json!([]));
+
+                // Only the page is listed, the todo went along with it
+                let trash = send!(Method::GET, "/trash");
+                assert_eq!(trash.as_array().unwrap().len(), 1);
+                assert_eq!(trash[0]["item"]["id"], page_id.as_str());
+
+                // The todo can't be restored without its page
+                let restore = format!("/trash/{}/restore", todo_id);
+                assert_eq!(status!(Method::POST, restore), StatusCode::CONFLICT);
+
+                let restored =
+                    send!(Method::POST, format!("/trash/{}/restore", page_id));
+                assert_eq!(restored["subtype"]["Page"]["title"], "old");
+                assert_eq!(status!(Method::GET, page_uri), StatusCode::OK);
+                assert_eq!(
+                    status!(Method::GET, format!("/todos/{}", todo_id)),
+                    StatusCode::OK
+                );
+                assert_eq!(send!(Method::GET, "/trash"), json!([]));
+                assert_eq!(status!(Method::POST, restore), StatusCode::NOT_FOUND);
+
+                // Purging only works on trashed items
+                let purge = format!("/trash/{}", page_id);
+                assert_eq!(status!(Method::DELETE, purge), StatusCode::NOT_FOUND);
+                status!(Method::DELETE, page_uri);
+                assert_eq!(status!(Method::DELETE, purge), StatusCode::OK);
+                assert_eq!(send!(Method::GET, "/trash"), json!([]));
+                assert_eq!(
+                    status!(Method::POST, format!("/trash/{}/restore", page_id)),
+                    StatusCode::NOT_FOUND
+                );
+
+                Ok(())
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_purge_expired() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(registry::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "expirer");
+                let mut ids = vec![];
+                for title in &["recent", "expired"] {
+                    let request = TestRequest::post()
+                        .uri("/pages")
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .set_json(&json!({ "title": title }))
+                        .to_request();
+                    let page: Value = read_response_json(&mut app, request).await;
+                    let id = page["item"]["id"].as_str().unwrap().parse()?;
+                    let request = TestRequest::delete()
+                        .uri(&format!("/pages/{}", id))
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .to_request();
+                    call_status(&mut app, request).await;
+                    ids.push(id);
+                }
+
+                let conn = crate::create_pool().get()?;
+                diesel::update(items::table.filter(items::id.eq(ids[1])))
+                    .set(items::deleted_at.eq(Utc::now() - Duration::days(31)))
+                    .execute(&conn)?;
+
+                Trash::purge_expired(Duration::days(30), &conn)?;
+                let left = items::table
+                    .filter(items::id.eq_any(&ids))
+                    .select(items::id)
+                    .load::<uuid::Uuid>(&conn)?;
+                assert_eq!(left, [ids[0]]);
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -11,18 +11,22 @@ extern crate diesel;
 use actix_web::{get, HttpResponse, Responder};
 
 pub use database::{create_pool, DbPool};
+pub use mailer::create_mailer;
+pub use utils::error::ApiError;
+
+#[cfg(test)]
+#[macro_use]
+pub(crate) mod testing;
 
 pub mod utils;
 
 //#[allow(clippy::single_component_path_imports)]
 pub mod schema;
 
-#[cfg(test)]
-#[macro_use]
-pub(crate) mod testing;
-
+pub mod batch;
 mod database;
 pub mod items;
+pub mod mailer;
 pub mod tags;
 pub mod users;
 /// The sole purpose of this module is to be
@@ -0,0 +1,139 @@
+//! Sending of emails.
+//!
+//! The backend is selected with the `MAILER` environment variable:
+//! - `smtp` sends mail through the server in `SMTP_HOST` over TLS,
+//!   authenticating with `SMTP_USERNAME` and `SMTP_PASSWORD`.
+//! - `file` appends every mail to the file in `MAIL_FILE`.
+//! - `stdout` (the default) prints every mail.
+//!
+//! Mail is sent from the address in `MAIL_FROM`.
+
+use core::fmt;
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials,
+    SmtpTransport, Transport,
+};
+
+pub type MailerData = Box<dyn Mailer>;
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub struct MailError(String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to send mail: {}", self.0)
+    }
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: Mail) -> Result<(), MailError>;
+}
+
+pub fn create_mailer() -> MailerData {
+    let from = std::env::var("MAIL_FROM")
+        .unwrap_or_else(|_| "Journali <no-reply@journali.nl>".into());
+
+    match std::env::var("MAILER").as_deref() {
+        Ok("smtp") => {
+            let host = std::env::var("SMTP_HOST").expect("SMTP_HOST");
+            let credentials = Credentials::new(
+                std::env::var("SMTP_USERNAME").expect("SMTP_USERNAME"),
+                std::env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD"),
+            );
+            Box::new(
+                SmtpMailer::new(&host, credentials, &from)
+                    .expect("Invalid SMTP configuration"),
+            )
+        }
+        Ok("file") => {
+            let path = std::env::var("MAIL_FILE").expect("MAIL_FILE");
+            Box::new(FileMailer::new(from, Some(path.into())))
+        }
+        _ => Box::new(FileMailer::new(from, None)),
+    }
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        credentials: Credentials,
+        from: &str,
+    ) -> Result<Self, MailError> {
+        Ok(Self {
+            from: from.parse().map_err(|err| MailError(format!("{}", err)))?,
+            transport: SmtpTransport::relay(host)
+                .map_err(|err| MailError(format!("{}", err)))?
+                .credentials(credentials)
+                .build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: Mail) -> Result<(), MailError> {
+        let message = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.parse().map_err(|err| MailError(format!("{}", err)))?)
+            .subject(mail.subject)
+            .body(mail.body)
+            .map_err(|err| MailError(format!("{}", err)))?;
+
+        self.transport
+            .send(&message)
+            .map(drop)
+            .map_err(|err| MailError(format!("{}", err)))
+    }
+}
+
+/// Writes mail to a file, or to stdout when no file is given.
+/// Meant for tests and local development.
+pub struct FileMailer {
+    from: String,
+    path: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(from: String, path: Option<PathBuf>) -> Self {
+        Self { from, path }
+    }
+
+    fn write(&self, mut out: impl Write, mail: &Mail) -> io::Result<()> {
+        writeln!(out, "From: {}", self.from)?;
+        writeln!(out, "To: {}", mail.to)?;
+        writeln!(out, "Subject: {}", mail.subject)?;
+        writeln!(out)?;
+        writeln!(out, "{}", mail.body)?;
+        writeln!(out, "----")
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: Mail) -> Result<(), MailError> {
+        match &self.path {
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|file| self.write(file, &mail)),
+            None => self.write(io::stdout().lock(), &mail),
+        }
+        .map_err(|err| MailError(format!("{}", err)))
+    }
+}
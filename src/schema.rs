@@ -1,3 +1,28 @@
+table! {
+    access_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Text,
+        scopes -> Array<Text>,
+        token_hash -> Text,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    item_revisions (id) {
+        id -> Uuid,
+        item_id -> Uuid,
+        item_type -> Int2,
+        owner_id -> Uuid,
+        revision -> Int4,
+        payload -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
 table! {
     items (id, item_type) {
         id -> Uuid,
@@ -8,6 +33,9 @@ table! {
         updated_at -> Timestamptz,
         owner_id -> Uuid,
         due_date -> Nullable<Timestamptz>,
+        deleted_at -> Nullable<Timestamptz>,
+        version -> Int4,
+        position -> Text,
     }
 }
 
@@ -16,6 +44,47 @@ table! {
         id -> Uuid,
         item_type -> Int2,
         title -> Text,
+        is_template -> Bool,
+    }
+}
+
+table! {
+    rate_limits (key) {
+        key -> Text,
+        hits -> Int4,
+        resets_at -> Timestamptz,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Text,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+        access_jti -> Uuid,
+        last_seen_at -> Timestamptz,
+        user_agent -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
+    }
+}
+
+table! {
+    rotated_refresh_tokens (token_hash) {
+        token_hash -> Text,
+        refresh_token_id -> Uuid,
+        rotated_at -> Timestamptz,
     }
 }
 
@@ -65,25 +134,79 @@ table! {
     }
 }
 
+table! {
+    two_factor (user_id) {
+        user_id -> Uuid,
+        secret -> Text,
+        enabled_at -> Nullable<Timestamptz>,
+        last_used_step -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    two_factor_challenges (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Text,
+        attempts -> Int4,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    user_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        purpose -> Text,
+        token_hash -> Text,
+        email -> Text,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     users (id) {
         id -> Uuid,
         username -> Text,
         password -> Text,
+        email -> Nullable<Text>,
+        email_verified_at -> Nullable<Timestamptz>,
+        timezone -> Text,
     }
 }
 
+joinable!(access_tokens -> users (user_id));
+joinable!(item_revisions -> users (owner_id));
 joinable!(items -> users (owner_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(refresh_tokens -> users (user_id));
+joinable!(rotated_refresh_tokens -> refresh_tokens (refresh_token_id));
 joinable!(tags -> users (owner_id));
 joinable!(tags_items -> tags (tag_id));
+joinable!(two_factor -> users (user_id));
+joinable!(two_factor_challenges -> users (user_id));
+joinable!(user_tokens -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    access_tokens,
+    item_revisions,
     items,
     pages,
+    rate_limits,
+    recovery_codes,
+    refresh_tokens,
+    rotated_refresh_tokens,
     tags,
     tags_items,
     text_fields,
     todo_items,
     todos,
+    two_factor,
+    two_factor_challenges,
+    user_tokens,
     users,
 );
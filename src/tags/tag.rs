@@ -1,6 +1,11 @@
 use super::tags_items::TagsItem;
 use crate::schema::tags;
 use crate::users::user::User;
+use crate::utils::{
+    error::ApiError,
+    patch::{Changes, Patch},
+    policy::Policy,
+};
 use diesel::pg::PgConnection;
 use diesel::ExpressionMethods;
 use diesel::QueryDsl;
@@ -58,7 +63,7 @@ impl Tag {
         })
     }
 
-    fn create(
+    pub(crate) fn create(
         new_tag: NewTag,
         user: User,
         conn: &PgConnection,
@@ -68,33 +73,38 @@ impl Tag {
         diesel::insert_into(tags::table).values(&tag).get_result(conn)
     }
 
-    fn update(
+    pub(crate) fn update(
         id: Uuid,
-        update_tag: UpdateTag,
+        patch: Patch,
         user: User,
         conn: &PgConnection,
-    ) -> QueryResult<Self> {
-        diesel::update(
-            tags::table
-                .filter(tags::columns::id.eq(id))
-                .filter(tags::owner_id.eq(user.id)),
-        )
-        .set(update_tag)
-        .get_result(conn)
+    ) -> Result<Self, ApiError> {
+        Self::authorize(id, &user, conn)?;
+
+        let current = tags::table.find(id).first::<Tag>(conn)?;
+        let update_tag = patch.into_form::<UpdateTag>(&current)?;
+
+        if update_tag.is_empty() {
+            return Ok(current);
+        }
+
+        diesel::update(tags::table.filter(tags::columns::id.eq(id)))
+            .set(update_tag)
+            .get_result(conn)
+            .map_err(ApiError::from)
     }
 
-    fn delete(
+    pub(crate) fn delete(
         id: Uuid,
         user: User,
         connection: &PgConnection,
-    ) -> QueryResult<()> {
-        diesel::delete(
-            tags::table
-                .filter(tags::owner_id.eq(user.id))
-                .filter(tags::id.eq(id)),
-        )
-        .get_result::<Tag>(connection)
-        .map(drop)
+    ) -> Result<(), ApiError> {
+        Self::authorize(id, &user, connection)?;
+
+        diesel::delete(tags::table.filter(tags::id.eq(id)))
+            .get_result::<Tag>(connection)
+            .map(drop)
+            .map_err(ApiError::from)
     }
 }
 
@@ -107,8 +117,14 @@ pub struct NewTag {
 #[derive(AsChangeset, Deserialize)]
 #[table_name = "tags"]
 pub struct UpdateTag {
-    name: String,
-    color: String,
+    name: Option<String>,
+    color: Option<String>,
+}
+
+impl Changes for UpdateTag {
+    fn is_empty(&self) -> bool {
+        self.name.is_none() && self.color.is_none()
+    }
 }
 
 impl Tag {
@@ -127,10 +143,11 @@ mod routes {
         delete, get, patch, post, web, Error, HttpRequest, HttpResponse,
     };
 
-    use super::{NewTag, Tag, UpdateTag};
+    use super::{NewTag, Tag};
     use crate::database::exec_on_pool;
     use crate::tags::tags_items::{TagsItem, TagsItemRequest};
-    use crate::utils::responsable::Responsable;
+    use crate::users::access_token::Scope;
+    use crate::utils::{authorize, patch::Patch, responsable::Responsable};
     use crate::DbPool;
     use uuid::Uuid;
 
@@ -141,7 +158,7 @@ mod routes {
         id: web::Path<Uuid>,
         items: web::Json<Vec<TagsItemRequest>>,
     ) -> Result<HttpResponse, Error> {
-        let user = request.extensions().get().cloned().unwrap();
+        let user = authorize(&request, Scope::TagsWrite)?;
 
         exec_on_pool(&pool, |conn| {
             TagsItem::add_items(id.into_inner(), items.into_inner(), user, conn)
@@ -157,7 +174,7 @@ mod routes {
         id: web::Path<Uuid>,
         items: web::Json<Vec<TagsItemRequest>>,
     ) -> Result<HttpResponse, Error> {
-        let user = request.extensions().get().cloned().unwrap();
+        let user = authorize(&request, Scope::TagsWrite)?;
 
         exec_on_pool(&pool, |conn| {
             TagsItem::delete_items(
@@ -176,7 +193,7 @@ mod routes {
         pool: web::Data<DbPool>,
         request: HttpRequest,
     ) -> Result<HttpResponse, Error> {
-        let user = request.extensions().get().cloned().unwrap();
+        let user = authorize(&request, Scope::TagsRead)?;
 
         exec_on_pool(&pool, |conn| Tag::find_all(user, conn))
             .await
@@ -189,7 +206,7 @@ mod routes {
         req: HttpRequest,
         form: web::Json<NewTag>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::TagsWrite)?;
 
         exec_on_pool(&pool, |conn| Tag::create(form.into_inner(), user, conn))
             .await
@@ -201,12 +218,13 @@ mod routes {
         pool: web::Data<DbPool>,
         req: HttpRequest,
         id: web::Path<Uuid>,
-        form: web::Json<UpdateTag>,
+        body: web::Json<serde_json::Value>,
     ) -> Result<HttpResponse, Error> {
-        let user = req.extensions().get().cloned().unwrap();
+        let user = authorize(&req, Scope::TagsWrite)?;
+        let patch = Patch::from_request(&req, body.into_inner())?;
 
         exec_on_pool(&pool, move |conn| {
-            Tag::update(id.into_inner(), form.into_inner(), user, conn)
+            Tag::update(id.into_inner(), patch, user, conn)
         })
         .await
         .into_response()
@@ -218,7 +236,7 @@ mod routes {
         request: HttpRequest,
         id: web::Path<Uuid>,
     ) -> Result<HttpResponse, Error> {
-        let user = request.extensions().get().cloned().unwrap();
+        let user = authorize(&request, Scope::TagsWrite)?;
         exec_on_pool(&pool, |conn| Tag::delete(id.into_inner(), user, &conn))
             .await
             .into_response()
@@ -1,5 +1,8 @@
-use crate::schema::tags_items;
+use crate::items::item::Item;
+use crate::schema::{items, tags_items};
+use crate::tags::tag::Tag;
 use crate::users::user::User;
+use crate::utils::{error::ApiError, policy::Policy};
 use diesel::prelude::*;
 use diesel::QueryResult;
 use serde::{Deserialize, Serialize};
@@ -25,6 +28,13 @@ impl TagsItem {
     ) -> QueryResult<Vec<Self>> {
         tags_items::table
             .filter(tags_items::columns::tag_id.eq(tag_id))
+            .filter(
+                tags_items::item_id.eq_any(
+                    items::table
+                        .filter(items::deleted_at.is_null())
+                        .select(items::id),
+                ),
+            )
             .load(conn)
     }
 
@@ -33,9 +43,12 @@ impl TagsItem {
         item_ids: Vec<TagsItemRequest>,
         user: User,
         connection: &PgConnection,
-    ) -> QueryResult<usize> {
-        // VALIDATION!
-        let _ = user;
+    ) -> Result<usize, ApiError> {
+        Tag::authorize(tag_id, &user, connection)?;
+        for item in &item_ids {
+            Item::authorize(item.id, &user, connection)?;
+        }
+
         let insert_data = item_ids
             .into_iter()
             .map(|tagsitem_request| TagsItem {
@@ -48,6 +61,7 @@ impl TagsItem {
         diesel::insert_into(tags_items::table)
             .values(&insert_data)
             .execute(connection)
+            .map_err(ApiError::from)
     }
 
     pub fn delete_items(
@@ -55,8 +69,9 @@ impl TagsItem {
         item_ids: Vec<TagsItemRequest>,
         user: User,
         connection: &PgConnection,
-    ) -> QueryResult<()> {
-        let _ = user;
+    ) -> Result<(), ApiError> {
+        Tag::authorize(tag_id, &user, connection)?;
+
         let records_to_be_deleted =
             item_ids.into_iter().map(|tagsitem_request| TagsItem {
                 tag_id,
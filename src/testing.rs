@@ -4,6 +4,7 @@ macro_rules! test {
         let app = actix_web::test::init_service(
             actix_web::App::new()
                 .data(crate::database::create_pool())
+                .data(crate::utils::rate_limit::RateLimiter::memory())
                 .configure($setup)
         ).await;
 
@@ -12,13 +13,42 @@ macro_rules! test {
     }
 }
 
+/// Registers a new user and logs it in, evaluates to the user and the
+/// value of the authorization header its requests should be made with.
+#[macro_export]
+macro_rules! login {
+    ($app:ident, $prefix:expr) => {{
+        let user = serde_json::json!({
+            "username": $crate::testing::username($prefix),
+            "password": $crate::testing::PASSWORD,
+        });
+
+        let request = actix_web::test::TestRequest::post()
+            .uri("/register")
+            .set_json(&user)
+            .to_request();
+        let registered: $crate::users::User =
+            actix_web::test::read_response_json(&mut $app, request).await;
+
+        let request = actix_web::test::TestRequest::post()
+            .uri("/login")
+            .set_json(&user)
+            .to_request();
+        let token: $crate::utils::jwt::Token =
+            actix_web::test::read_response_json(&mut $app, request).await;
+
+        (registered, format!("Bearer {}", token.token))
+    }};
+}
+
 use crate::{
     create_pool,
     users::user::User,
-    utils::{jwt::Token, validator},
+    utils::{jwt::Token, rate_limit::RateLimiter, validator},
 };
 
 use actix_web::{
+    dev::{Service, ServiceResponse},
     http::{header, StatusCode},
     test,
     web::{self, ServiceConfig},
@@ -26,6 +56,15 @@ use actix_web::{
 
 use actix_web_httpauth::middleware::HttpAuthentication;
 
+/// A password that passes the registration checks
+pub const PASSWORD: &str = "Simple enough";
+
+/// Usernames are unique, so every test needs a new one
+pub fn username(prefix: &str) -> String {
+    let suffix = uuid::Uuid::new_v4().to_simple().to_string();
+    format!("{}_{}", prefix, &suffix[..8])
+}
+
 pub async fn create<Configure, Create>(
     configure: Configure,
     create: Create,
@@ -37,22 +76,29 @@ pub async fn create<Configure, Create>(
     let auth = HttpAuthentication::bearer(validator);
 
     let mut app = actix_web::test::init_service(
-        actix_web::App::new().data(create_pool()).service(
-            web::scope("/api")
-                .configure(User::routes)
-                .service(web::scope("").wrap(auth).configure(configure)),
-        ),
+        actix_web::App::new()
+            .data(create_pool())
+            .data(RateLimiter::memory())
+            .service(
+                web::scope("/api")
+                    .configure(User::routes)
+                    .service(web::scope("").wrap(auth).configure(configure)),
+            ),
     )
     .await;
 
-    let user = r#"{"username":"tester","password": "simple"}"#.as_bytes();
+    let user = serde_json::json!({
+        "username": username("tester"),
+        "password": PASSWORD,
+    })
+    .to_string();
 
     // REGISTER USER
     {
         let request = test::TestRequest::post()
             .uri("/api/register")
             .header(header::CONTENT_TYPE, "application/json")
-            .set_payload(user)
+            .set_payload(user.clone())
             .to_request();
 
         let resp = test::call_service(&mut app, request).await;
@@ -64,7 +110,7 @@ pub async fn create<Configure, Create>(
         let request = test::TestRequest::post()
             .uri("/api/login")
             .header(header::CONTENT_TYPE, "application/json")
-            .set_payload(user)
+            .set_payload(user.clone())
             .to_request();
 
         let token: Token = test::read_response_json(&mut app, request).await;
@@ -84,3 +130,37 @@ pub async fn create<Configure, Create>(
         assert_eq!(res.status(), StatusCode::OK);
     }
 }
+
+/// Removes the built-in templates every user starts out with, along with
+/// everything on them, for tests that look at everything the user has
+pub fn remove_templates(user: &User) {
+    use crate::schema::{items, pages};
+    use diesel::prelude::*;
+
+    let templates =
+        pages::table.filter(pages::is_template.eq(true)).select(pages::id);
+    let conn = create_pool().get().expect("Failed to connect");
+    diesel::delete(
+        items::table
+            .filter(items::owner_id.eq(user.id))
+            .filter(items::id.eq_any(templates)),
+    )
+    .execute(&conn)
+    .expect("Failed to remove templates");
+}
+
+/// Calls the service and returns the status code of the response,
+/// including responses for requests that were rejected by a middleware.
+pub async fn call_status<S, R, B>(app: &mut S, request: R) -> StatusCode
+where
+    S: Service<
+        Request = R,
+        Response = ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+{
+    match app.call(request).await {
+        Ok(response) => response.status(),
+        Err(err) => err.as_response_error().error_response().status(),
+    }
+}
@@ -0,0 +1,388 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::{pg::PgConnection, prelude::*, QueryResult};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::{access_tokens, users};
+use crate::users::User;
+use crate::utils::{error::ApiError, token};
+
+/// Every access token starts with this, so the validator
+/// can tell them apart from JWTs.
+pub const PREFIX: &str = "jpat_";
+
+/// Tokens are only marked as used once every
+/// interval, so not every request results in a write.
+const LAST_USED_INTERVAL_SECONDS: i64 = 60;
+
+/// What an access token is allowed to do. Sessions
+/// of a user that logged in are allowed to do everything.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Scope {
+    #[serde(rename = "items:read")]
+    ItemsRead,
+    #[serde(rename = "items:write")]
+    ItemsWrite,
+    #[serde(rename = "tags:read")]
+    TagsRead,
+    #[serde(rename = "tags:write")]
+    TagsWrite,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ItemsRead => "items:read",
+            Scope::ItemsWrite => "items:write",
+            Scope::TagsRead => "tags:read",
+            Scope::TagsWrite => "tags:write",
+        }
+    }
+}
+
+/// A named token a user hands to a script or integration.
+/// Only a hash of the token is stored, which is never loaded.
+#[derive(Identifiable, Queryable, Associations, Serialize, Clone, Debug)]
+#[belongs_to(User)]
+pub struct AccessToken {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// The columns an [`AccessToken`](struct.AccessToken.html) is loaded from
+pub const COLUMNS: (
+    access_tokens::id,
+    access_tokens::user_id,
+    access_tokens::name,
+    access_tokens::scopes,
+    access_tokens::created_at,
+    access_tokens::expires_at,
+    access_tokens::last_used_at,
+) = (
+    access_tokens::id,
+    access_tokens::user_id,
+    access_tokens::name,
+    access_tokens::scopes,
+    access_tokens::created_at,
+    access_tokens::expires_at,
+    access_tokens::last_used_at,
+);
+
+#[derive(Insertable)]
+#[table_name = "access_tokens"]
+struct NewAccessToken<'a> {
+    user_id: Uuid,
+    name: &'a str,
+    scopes: Vec<&'static str>,
+    token_hash: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateAccessToken {
+    name: String,
+    scopes: Vec<Scope>,
+    /// Tokens without an expiry stay valid until they are revoked
+    expires_in_days: Option<i64>,
+}
+
+/// A newly created token, the only time the plain token is shown
+#[derive(Serialize)]
+pub struct CreatedAccessToken {
+    #[serde(flatten)]
+    access_token: AccessToken,
+    token: String,
+}
+
+impl AccessToken {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|granted| granted == scope.as_str())
+    }
+
+    pub fn create(
+        user_id: Uuid,
+        form: &CreateAccessToken,
+        conn: &PgConnection,
+    ) -> Result<CreatedAccessToken, ApiError> {
+        let name = form.name.trim();
+        if name.is_empty() {
+            return Err(ApiError::Unprocessable(
+                "An access token needs a name.".into(),
+            ));
+        }
+        if form.scopes.is_empty() {
+            return Err(ApiError::Unprocessable(
+                "An access token needs at least one scope.".into(),
+            ));
+        }
+        let expires_at = match form.expires_in_days {
+            Some(days) if days <= 0 => {
+                return Err(ApiError::Unprocessable(
+                    "expires_in_days must be positive.".into(),
+                ))
+            }
+            Some(days) => Some(Utc::now() + Duration::days(days)),
+            None => None,
+        };
+
+        let mut scopes: Vec<_> =
+            form.scopes.iter().map(|scope| scope.as_str()).collect();
+        scopes.sort_unstable();
+        scopes.dedup();
+
+        let token = format!("{}{}", PREFIX, token::generate());
+        let access_token = diesel::insert_into(access_tokens::table)
+            .values(NewAccessToken {
+                user_id,
+                name,
+                scopes,
+                token_hash: token::hash(&token),
+                expires_at,
+            })
+            .returning(COLUMNS)
+            .get_result(conn)?;
+
+        Ok(CreatedAccessToken { access_token, token })
+    }
+
+    pub fn find_all(
+        user_id: Uuid,
+        conn: &PgConnection,
+    ) -> QueryResult<Vec<Self>> {
+        access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .order(access_tokens::created_at.desc())
+            .select(COLUMNS)
+            .load(conn)
+    }
+
+    pub fn revoke(
+        id: Uuid,
+        user_id: Uuid,
+        conn: &PgConnection,
+    ) -> QueryResult<()> {
+        diesel::delete(
+            access_tokens::table
+                .filter(access_tokens::id.eq(id))
+                .filter(access_tokens::user_id.eq(user_id)),
+        )
+        .execute(conn)
+        .and_then(|deleted| match deleted {
+            0 => Err(diesel::result::Error::NotFound),
+            _ => Ok(()),
+        })
+    }
+
+    /// Finds the user an unexpired access token belongs to, and marks it used
+    pub fn find_user(
+        token: &str,
+        conn: &PgConnection,
+    ) -> QueryResult<(User, AccessToken)> {
+        let now = Utc::now();
+        let (user, access_token) = users::table
+            .inner_join(access_tokens::table)
+            .filter(access_tokens::token_hash.eq(token::hash(token)))
+            .filter(
+                access_tokens::expires_at
+                    .is_null()
+                    .or(access_tokens::expires_at.gt(now)),
+            )
+            .select((users::all_columns, COLUMNS))
+            .first::<(User, AccessToken)>(conn)?;
+
+        diesel::update(
+            access_tokens::table.find(access_token.id).filter(
+                access_tokens::last_used_at
+                    .is_null()
+                    .or(access_tokens::last_used_at
+                    .lt(now - Duration::seconds(LAST_USED_INTERVAL_SECONDS))),
+            ),
+        )
+        .set(access_tokens::last_used_at.eq(now))
+        .execute(conn)?;
+
+        Ok((user, access_token))
+    }
+}
+
+impl AccessToken {
+    pub fn route_me(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::find_all);
+        cfg.service(routes::create);
+        cfg.service(routes::revoke);
+    }
+}
+
+mod routes {
+    use actix_web::{delete, get, post, web, Error, HttpRequest, HttpResponse};
+    use uuid::Uuid;
+
+    use crate::utils::{responsable::Responsable, session};
+    use crate::{database::exec_on_pool, DbPool};
+
+    use super::{AccessToken, CreateAccessToken};
+
+    #[get("/user/me/tokens")]
+    pub(super) async fn find_all(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let (user, _) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| AccessToken::find_all(user.id, conn))
+            .await
+            .into_response()
+    }
+
+    #[post("/user/me/tokens")]
+    pub(super) async fn create(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+        form: web::Json<CreateAccessToken>,
+    ) -> Result<HttpResponse, Error> {
+        let (user, _) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            AccessToken::create(user.id, &form, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[delete("/user/me/tokens/{id}")]
+    pub(super) async fn revoke(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let (user, _) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            AccessToken::revoke(id.into_inner(), user.id, conn)
+        })
+        .await
+        .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use super::AccessToken;
+    use crate::items::{item::Item, page::Page};
+    use crate::testing::{self, call_status};
+    use crate::users::User;
+    use crate::utils::{jwt::Token, validator};
+
+    #[actix_rt::test]
+    async fn test_access_token_scopes() -> Result<(), Box<dyn std::error::Error>>
+    {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(Page::routes)
+                            .configure(AccessToken::route_me),
+                    );
+                }
+            }
+
+            test = |app| {
+                let user = serde_json::json!({
+                    "username": testing::username("scripter"),
+                    "password": testing::PASSWORD,
+                })
+                .to_string();
+
+                let request = TestRequest::post()
+                    .uri("/register")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                call_service(&mut app, request).await;
+
+                let request = TestRequest::post()
+                    .uri("/login")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                let login: Token = read_response_json(&mut app, request).await;
+                let bearer = format!("Bearer {}", login.token);
+
+                let request = TestRequest::post()
+                    .uri("/user/me/tokens")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({
+                        "name": "importer",
+                        "scopes": ["items:read"],
+                    }))
+                    .to_request();
+                let created: Value = read_response_json(&mut app, request).await;
+                assert_eq!(created["scopes"], json!(["items:read"]));
+                let access_token =
+                    format!("Bearer {}", created["token"].as_str().unwrap());
+
+                let request = TestRequest::get()
+                    .uri("/items")
+                    .header(header::AUTHORIZATION, access_token.clone())
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let request = TestRequest::post()
+                    .uri("/pages")
+                    .header(header::AUTHORIZATION, access_token.clone())
+                    .set_json(&json!({ "title": "imported" }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+                // Access tokens can't manage the account
+                let request = TestRequest::get()
+                    .uri("/user/me/tokens")
+                    .header(header::AUTHORIZATION, access_token.clone())
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+                let request = TestRequest::delete()
+                    .uri(&format!(
+                        "/user/me/tokens/{}",
+                        created["id"].as_str().unwrap()
+                    ))
+                    .header(header::AUTHORIZATION, bearer)
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let request = TestRequest::get()
+                    .uri("/items")
+                    .header(header::AUTHORIZATION, access_token)
+                    .to_request();
+                assert_eq!(
+                    call_status(&mut app, request).await,
+                    StatusCode::UNAUTHORIZED
+                );
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,2 +1,12 @@
+pub mod access_token;
+pub mod refresh_token;
+pub mod session;
+pub mod two_factor;
 pub mod user;
+pub mod user_token;
+pub use access_token::AccessToken;
+pub use refresh_token::RefreshToken;
+pub use session::Session;
+pub use two_factor::TwoFactor;
 pub use user::User;
+pub use user_token::UserToken;
@@ -0,0 +1,417 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::{pg::PgConnection, prelude::*, QueryResult};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::schema::{refresh_tokens, rotated_refresh_tokens, users};
+use crate::users::{session::ClientInfo, user::User};
+use crate::utils::{
+    error::ApiError,
+    jwt::{Jwt, Token},
+    token,
+};
+
+/// Lifetime of an access token, in minutes
+const ACCESS_TOKEN_MINUTES: i64 = 15;
+
+/// Lifetime of a refresh token, in days. Every
+/// rotation extends the session with this amount.
+const REFRESH_TOKEN_DAYS: i64 = 30;
+
+/// A login session of a user.
+///
+/// Only a hash of the refresh token is stored, the
+/// plain token is handed to the client exactly once.
+/// The hashes are never loaded, see [`COLUMNS`](constant.COLUMNS.html).
+#[derive(Identifiable, Queryable, Associations, Clone, Debug)]
+#[belongs_to(User)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub access_jti: Uuid,
+}
+
+/// The columns a [`RefreshToken`](struct.RefreshToken.html) is loaded from
+pub const COLUMNS: (
+    refresh_tokens::id,
+    refresh_tokens::user_id,
+    refresh_tokens::created_at,
+    refresh_tokens::expires_at,
+    refresh_tokens::revoked_at,
+    refresh_tokens::access_jti,
+) = (
+    refresh_tokens::id,
+    refresh_tokens::user_id,
+    refresh_tokens::created_at,
+    refresh_tokens::expires_at,
+    refresh_tokens::revoked_at,
+    refresh_tokens::access_jti,
+);
+
+#[derive(Insertable)]
+#[table_name = "refresh_tokens"]
+struct NewRefreshToken<'a> {
+    user_id: Uuid,
+    token_hash: String,
+    expires_at: DateTime<Utc>,
+    access_jti: Uuid,
+    user_agent: Option<&'a str>,
+    ip_address: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+impl RefreshToken {
+    /// Starts a new session for the user and returns its token pair
+    pub fn issue(
+        user: &User,
+        client: &ClientInfo,
+        conn: &PgConnection,
+    ) -> QueryResult<Token> {
+        let refresh_token = token::generate();
+
+        diesel::insert_into(refresh_tokens::table)
+            .values(NewRefreshToken {
+                user_id: user.id,
+                token_hash: token::hash(&refresh_token),
+                expires_at: Utc::now() + Duration::days(REFRESH_TOKEN_DAYS),
+                access_jti: Uuid::new_v4(),
+                user_agent: client.user_agent.as_deref(),
+                ip_address: client.ip_address.as_deref(),
+            })
+            .returning(COLUMNS)
+            .get_result::<RefreshToken>(conn)
+            .map(|session| session.into_token(refresh_token))
+    }
+
+    /// Exchanges a refresh token for a new token pair.
+    ///
+    /// The presented refresh token can not be used again, and neither can
+    /// any token the session was rotated away from before. When one of
+    /// them is presented anyway, it has probably leaked and the whole
+    /// session gets revoked.
+    pub fn rotate(
+        refresh_token: &str,
+        client: &ClientInfo,
+        conn: &PgConnection,
+    ) -> Result<Token, ApiError> {
+        let hash = token::hash(refresh_token);
+        let new_refresh_token = token::generate();
+
+        let session = conn.transaction(|| {
+            Self::exchange(&hash, &new_refresh_token, client, conn)
+        })?;
+
+        match session {
+            Some(session) => Ok(session.into_token(new_refresh_token)),
+            None => {
+                let reused = rotated_refresh_tokens::table
+                    .filter(rotated_refresh_tokens::token_hash.eq(&hash))
+                    .select(rotated_refresh_tokens::refresh_token_id);
+                diesel::update(
+                    refresh_tokens::table
+                        .filter(refresh_tokens::id.eq_any(reused))
+                        .filter(refresh_tokens::revoked_at.is_null()),
+                )
+                .set(refresh_tokens::revoked_at.eq(Utc::now()))
+                .execute(conn)?;
+
+                Err(ApiError::Unauthorized("Invalid refresh token.".into()))
+            }
+        }
+    }
+
+    /// Replaces the hash of the active session that has it, and keeps the
+    /// old hash to recognize it when it's reused
+    fn exchange(
+        hash: &str,
+        new_refresh_token: &str,
+        client: &ClientInfo,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<RefreshToken>> {
+        let session = diesel::update(
+            refresh_tokens::table
+                .filter(refresh_tokens::token_hash.eq(hash))
+                .filter(refresh_tokens::revoked_at.is_null())
+                .filter(refresh_tokens::expires_at.gt(Utc::now())),
+        )
+        .set((
+            refresh_tokens::token_hash.eq(token::hash(new_refresh_token)),
+            refresh_tokens::expires_at
+                .eq(Utc::now() + Duration::days(REFRESH_TOKEN_DAYS)),
+            refresh_tokens::access_jti.eq(Uuid::new_v4()),
+            refresh_tokens::last_seen_at.eq(Utc::now()),
+            refresh_tokens::user_agent.eq(&client.user_agent),
+            refresh_tokens::ip_address.eq(&client.ip_address),
+        ))
+        .returning(COLUMNS)
+        .get_result::<RefreshToken>(conn)
+        .optional()?;
+
+        if let Some(session) = &session {
+            diesel::insert_into(rotated_refresh_tokens::table)
+                .values((
+                    rotated_refresh_tokens::token_hash.eq(hash),
+                    rotated_refresh_tokens::refresh_token_id.eq(session.id),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(session)
+    }
+
+    pub fn revoke(
+        id: Uuid,
+        user_id: Uuid,
+        conn: &PgConnection,
+    ) -> QueryResult<()> {
+        diesel::update(
+            refresh_tokens::table
+                .filter(refresh_tokens::id.eq(id))
+                .filter(refresh_tokens::user_id.eq(user_id))
+                .filter(refresh_tokens::revoked_at.is_null()),
+        )
+        .set(refresh_tokens::revoked_at.eq(Utc::now()))
+        .execute(conn)
+        .and_then(|revoked| match revoked {
+            0 => Err(diesel::result::Error::NotFound),
+            _ => Ok(()),
+        })
+    }
+
+    pub fn revoke_all(user_id: Uuid, conn: &PgConnection) -> QueryResult<()> {
+        diesel::update(
+            refresh_tokens::table
+                .filter(refresh_tokens::user_id.eq(user_id))
+                .filter(refresh_tokens::revoked_at.is_null()),
+        )
+        .set(refresh_tokens::revoked_at.eq(Utc::now()))
+        .execute(conn)
+        .map(drop)
+    }
+
+    /// Finds the user an access token belongs to, as
+    /// long as the session it was issued for is active.
+    pub fn find_session(
+        jwt: &Jwt,
+        conn: &PgConnection,
+    ) -> QueryResult<(User, RefreshToken)> {
+        users::table
+            .inner_join(refresh_tokens::table)
+            .filter(users::id.eq(jwt.sub()))
+            .filter(refresh_tokens::id.eq(jwt.sid()))
+            .filter(refresh_tokens::access_jti.eq(jwt.jti()))
+            .filter(refresh_tokens::revoked_at.is_null())
+            .filter(refresh_tokens::expires_at.gt(Utc::now()))
+            .select((users::all_columns, COLUMNS))
+            .first(conn)
+    }
+
+    fn into_token(self, refresh_token: String) -> Token {
+        let lifetime = Duration::minutes(ACCESS_TOKEN_MINUTES);
+        let token = Jwt::new(
+            "journali.nl".into(),
+            lifetime,
+            self.user_id,
+            self.id,
+            self.access_jti,
+        )
+        .tokenize();
+
+        Token { token, refresh_token, expires_in: lifetime.num_seconds() }
+    }
+}
+
+impl RefreshToken {
+    pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::refresh);
+    }
+
+    pub fn route_me(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::logout);
+        cfg.service(routes::logout_all);
+    }
+}
+
+mod routes {
+    use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+
+    use crate::users::session::ClientInfo;
+    use crate::utils::{responsable::Responsable, session};
+    use crate::{database::exec_on_pool, DbPool};
+
+    use super::{RefreshRequest, RefreshToken};
+
+    #[post("/token/refresh")]
+    pub(super) async fn refresh(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+        form: web::Json<RefreshRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let client =
+            ClientInfo::new(&request.connection_info(), request.headers());
+
+        exec_on_pool(&pool, move |conn| {
+            RefreshToken::rotate(&form.refresh_token, &client, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[post("/logout")]
+    pub(super) async fn logout(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let (_, session) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            RefreshToken::revoke(session.id, session.user_id, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[post("/logout/all")]
+    pub(super) async fn logout_all(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let (_, session) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            RefreshToken::revoke_all(session.user_id, conn)
+        })
+        .await
+        .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+
+    use super::RefreshToken;
+    use crate::testing;
+    use crate::users::User;
+    use crate::utils::{jwt::Token, validator};
+
+    #[actix_rt::test]
+    async fn test_refresh_and_logout() -> Result<(), Box<dyn std::error::Error>>
+    {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    RefreshToken::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(User::route_me)
+                            .configure(RefreshToken::route_me),
+                    );
+                }
+            }
+
+            test = |app| {
+                let user = serde_json::json!({
+                    "username": testing::username("refresher"),
+                    "password": testing::PASSWORD,
+                })
+                .to_string();
+
+                let request = TestRequest::post()
+                    .uri("/register")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                call_service(&mut app, request).await;
+
+                let request = TestRequest::post()
+                    .uri("/login")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                let login: Token = read_response_json(&mut app, request).await;
+
+                let refresh = |refresh_token: &str| {
+                    TestRequest::post()
+                        .uri("/token/refresh")
+                        .set_json(&serde_json::json!({
+                            "refresh_token": refresh_token
+                        }))
+                        .to_request()
+                };
+
+                // A refresh token can be exchanged exactly once
+                let rotated: Token =
+                    read_response_json(&mut app, refresh(&login.refresh_token))
+                        .await;
+                let resp =
+                    call_service(&mut app, refresh(&login.refresh_token)).await;
+                assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+                // Reusing a refresh token revoked the whole session
+                let resp =
+                    call_service(&mut app, refresh(&rotated.refresh_token)).await;
+                assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+                // So does reusing one from further back in the session
+                let request = TestRequest::post()
+                    .uri("/login")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                let login: Token = read_response_json(&mut app, request).await;
+                let first: Token =
+                    read_response_json(&mut app, refresh(&login.refresh_token))
+                        .await;
+                let second: Token =
+                    read_response_json(&mut app, refresh(&first.refresh_token))
+                        .await;
+                let resp =
+                    call_service(&mut app, refresh(&login.refresh_token)).await;
+                assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+                let resp =
+                    call_service(&mut app, refresh(&second.refresh_token)).await;
+                assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+                // A fresh session can be logged out of
+                let request = TestRequest::post()
+                    .uri("/login")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                let login: Token = read_response_json(&mut app, request).await;
+                let bearer = format!("Bearer {}", login.token);
+
+                let request = TestRequest::post()
+                    .uri("/logout")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let request = TestRequest::get()
+                    .uri("/user/me")
+                    .header(header::AUTHORIZATION, bearer)
+                    .to_request();
+                let status = testing::call_status(&mut app, request).await;
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use actix_web::dev::ConnectionInfo;
+use actix_web::http::{header, HeaderMap};
+use chrono::{DateTime, Duration, Utc};
+use diesel::{pg::PgConnection, prelude::*, QueryResult};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::schema::refresh_tokens;
+
+/// Sessions are only marked as seen once every
+/// interval, so not every request results in a write.
+const LAST_SEEN_INTERVAL_SECONDS: i64 = 60;
+
+/// The client a session is used from
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(connection_info: &ConnectionInfo, headers: &HeaderMap) -> Self {
+        Self {
+            ip_address: connection_info.remote().map(str::to_owned),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(str::to_owned),
+        }
+    }
+}
+
+/// A read only view on a refresh token, as shown to its user
+#[derive(Queryable, Serialize)]
+pub struct Session {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    session: Session,
+
+    /// Whether this is the session the request was made with
+    current: bool,
+}
+
+impl Session {
+    pub fn find_all(
+        user_id: Uuid,
+        current: Uuid,
+        conn: &PgConnection,
+    ) -> QueryResult<Vec<SessionInfo>> {
+        refresh_tokens::table
+            .filter(refresh_tokens::user_id.eq(user_id))
+            .filter(refresh_tokens::revoked_at.is_null())
+            .filter(refresh_tokens::expires_at.gt(Utc::now()))
+            .order(refresh_tokens::last_seen_at.desc())
+            .select((
+                refresh_tokens::id,
+                refresh_tokens::created_at,
+                refresh_tokens::last_seen_at,
+                refresh_tokens::expires_at,
+                refresh_tokens::user_agent,
+                refresh_tokens::ip_address,
+            ))
+            .load::<Session>(conn)
+            .map(|sessions| {
+                sessions
+                    .into_iter()
+                    .map(|session| SessionInfo {
+                        current: session.id == current,
+                        session,
+                    })
+                    .collect()
+            })
+    }
+
+    /// Marks the session as seen from the given client
+    pub fn touch(
+        id: Uuid,
+        client: &ClientInfo,
+        conn: &PgConnection,
+    ) -> QueryResult<()> {
+        let now = Utc::now();
+
+        diesel::update(
+            refresh_tokens::table.filter(refresh_tokens::id.eq(id)).filter(
+                refresh_tokens::last_seen_at
+                    .lt(now - Duration::seconds(LAST_SEEN_INTERVAL_SECONDS)),
+            ),
+        )
+        .set((
+            refresh_tokens::last_seen_at.eq(now),
+            refresh_tokens::user_agent.eq(&client.user_agent),
+            refresh_tokens::ip_address.eq(&client.ip_address),
+        ))
+        .execute(conn)
+        .map(drop)
+    }
+}
+
+impl Session {
+    pub fn route_me(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::find_all);
+        cfg.service(routes::revoke);
+    }
+}
+
+mod routes {
+    use actix_web::{delete, get, web, Error, HttpRequest, HttpResponse};
+    use uuid::Uuid;
+
+    use crate::users::RefreshToken;
+    use crate::utils::{responsable::Responsable, session};
+    use crate::{database::exec_on_pool, DbPool};
+
+    use super::Session;
+
+    #[get("/user/me/sessions")]
+    pub(super) async fn find_all(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let (_, session) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            Session::find_all(session.user_id, session.id, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[delete("/user/me/sessions/{id}")]
+    pub(super) async fn revoke(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+        id: web::Path<Uuid>,
+    ) -> Result<HttpResponse, Error> {
+        let (_, session) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            RefreshToken::revoke(id.into_inner(), session.user_id, conn)
+        })
+        .await
+        .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    use super::Session;
+    use crate::testing;
+    use crate::users::User;
+    use crate::utils::{jwt::Token, validator};
+
+    #[actix_rt::test]
+    async fn test_list_and_revoke_sessions(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Session::route_me),
+                    );
+                }
+            }
+
+            test = |app| {
+                let user = serde_json::json!({
+                    "username": testing::username("sessions"),
+                    "password": testing::PASSWORD,
+                })
+                .to_string();
+
+                let request = TestRequest::post()
+                    .uri("/register")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                call_service(&mut app, request).await;
+
+                let mut logins = Vec::new();
+                for user_agent in &["phone", "laptop"] {
+                    let request = TestRequest::post()
+                        .uri("/login")
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .header(header::USER_AGENT, *user_agent)
+                        .set_payload(user.clone())
+                        .to_request();
+                    let login: Token =
+                        read_response_json(&mut app, request).await;
+                    logins.push(format!("Bearer {}", login.token));
+                }
+
+                let request = TestRequest::get()
+                    .uri("/user/me/sessions")
+                    .header(header::AUTHORIZATION, logins[1].clone())
+                    .to_request();
+                let sessions: Vec<Value> =
+                    read_response_json(&mut app, request).await;
+
+                let phone = sessions
+                    .iter()
+                    .find(|session| session["user_agent"] == "phone")
+                    .expect("phone session is listed");
+                assert_eq!(phone["current"], false);
+                assert!(sessions.iter().any(|session| {
+                    session["user_agent"] == "laptop" && session["current"] == true
+                }));
+
+                let request = TestRequest::delete()
+                    .uri(&format!(
+                        "/user/me/sessions/{}",
+                        phone["id"].as_str().unwrap()
+                    ))
+                    .header(header::AUTHORIZATION, logins[1].clone())
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let request = TestRequest::get()
+                    .uri("/user/me/sessions")
+                    .header(header::AUTHORIZATION, logins[0].clone())
+                    .to_request();
+                let status = testing::call_status(&mut app, request).await;
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+                // Sessions that are already revoked, that belong to someone
+                // else or that don't exist aren't found
+                let (_, intruder) = login!(app, "intruder");
+                let laptop = sessions
+                    .iter()
+                    .find(|session| session["user_agent"] == "laptop")
+                    .unwrap();
+                for (id, bearer) in &[
+                    (phone["id"].as_str().unwrap().to_owned(), &logins[1]),
+                    (laptop["id"].as_str().unwrap().to_owned(), &intruder),
+                    (Uuid::new_v4().to_string(), &logins[1]),
+                ] {
+                    let request = TestRequest::delete()
+                        .uri(&format!("/user/me/sessions/{}", id))
+                        .header(header::AUTHORIZATION, (*bearer).clone())
+                        .to_request();
+                    let status = testing::call_status(&mut app, request).await;
+                    assert_eq!(status, StatusCode::NOT_FOUND);
+                }
+
+                // The laptop session is still active
+                let request = TestRequest::get()
+                    .uri("/user/me/sessions")
+                    .header(header::AUTHORIZATION, logins[1].clone())
+                    .to_request();
+                let status = testing::call_status(&mut app, request).await;
+                assert_eq!(status, StatusCode::OK);
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,573 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::{pg::PgConnection, prelude::*, QueryResult};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::{recovery_codes, two_factor, two_factor_challenges};
+use crate::users::{session::ClientInfo, RefreshToken, User};
+use crate::utils::{error::ApiError, jwt::Token, token, totp};
+
+/// How long a user has to enter their code after logging in
+const CHALLENGE_MINUTES: i64 = 5;
+
+/// How many wrong codes may be tried per challenge
+const CHALLENGE_ATTEMPTS: i32 = 5;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// The TOTP secret of a user. Two-factor authentication
+/// is only enabled once the secret has been confirmed.
+#[derive(Identifiable, Queryable, Associations)]
+#[belongs_to(User)]
+#[table_name = "two_factor"]
+#[primary_key(user_id)]
+pub struct TwoFactor {
+    pub user_id: Uuid,
+    secret: String,
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub last_used_step: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "two_factor"]
+struct NewTwoFactor {
+    user_id: Uuid,
+    secret: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "recovery_codes"]
+struct NewRecoveryCode {
+    user_id: Uuid,
+    code_hash: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "two_factor_challenges"]
+struct NewChallenge {
+    user_id: Uuid,
+    token_hash: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// The secret to add to an authenticator app
+#[derive(Serialize)]
+pub struct Enrollment {
+    secret: String,
+    uri: String,
+}
+
+/// Codes that can be used once each, instead of a TOTP code
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Returned by a login when a second factor is required
+#[derive(Serialize, Deserialize)]
+pub struct Challenge {
+    pub challenge: String,
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChallengeRequest {
+    challenge: String,
+    code: String,
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 7];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("Failed to generate random bytes");
+
+    let code = totp::base32_encode(&bytes).to_lowercase();
+    format!("{}-{}", &code[..5], &code[5..10])
+}
+
+/// Recovery codes are compared without dashes and case
+fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    token::hash(&code)
+}
+
+fn invalid_code() -> ApiError {
+    ApiError::Unprocessable("Invalid code.".into())
+}
+
+impl TwoFactor {
+    pub fn is_enabled(user_id: Uuid, conn: &PgConnection) -> QueryResult<bool> {
+        diesel::select(diesel::dsl::exists(
+            two_factor::table
+                .find(user_id)
+                .filter(two_factor::enabled_at.is_not_null()),
+        ))
+        .get_result(conn)
+    }
+
+    /// Generates a new secret, which has to be confirmed with a code
+    /// before it is used. Any earlier unconfirmed secret is replaced.
+    pub fn enroll(
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<Enrollment, ApiError> {
+        if Self::is_enabled(user.id, conn)? {
+            return Err(ApiError::Conflict(
+                "Two-factor authentication is already enabled.".into(),
+            ));
+        }
+
+        let secret = totp::generate_secret();
+        diesel::insert_into(two_factor::table)
+            .values(NewTwoFactor { user_id: user.id, secret: secret.clone() })
+            .on_conflict(two_factor::user_id)
+            .do_update()
+            .set((
+                two_factor::secret.eq(&secret),
+                two_factor::last_used_step.eq(None::<i64>),
+                two_factor::created_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+
+        Ok(Enrollment {
+            uri: totp::provisioning_uri(&secret, &user.username),
+            secret,
+        })
+    }
+
+    /// Enables two-factor authentication, and
+    /// hands out a fresh set of recovery codes.
+    pub fn confirm(
+        user_id: Uuid,
+        code: &str,
+        conn: &PgConnection,
+    ) -> Result<RecoveryCodes, ApiError> {
+        conn.transaction(|| {
+            let two_factor = two_factor::table
+                .find(user_id)
+                .filter(two_factor::enabled_at.is_null())
+                .first::<TwoFactor>(conn)
+                .optional()?
+                .ok_or_else(|| {
+                    ApiError::Unprocessable(
+                        "Two-factor authentication is not being enrolled."
+                            .into(),
+                    )
+                })?;
+
+            if !two_factor.use_totp(code, conn)? {
+                return Err(invalid_code());
+            }
+
+            diesel::update(two_factor::table.find(user_id))
+                .set(two_factor::enabled_at.eq(Utc::now()))
+                .execute(conn)?;
+
+            diesel::delete(
+                recovery_codes::table
+                    .filter(recovery_codes::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+
+            let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+                .map(|_| generate_recovery_code())
+                .collect();
+            diesel::insert_into(recovery_codes::table)
+                .values(
+                    codes
+                        .iter()
+                        .map(|code| NewRecoveryCode {
+                            user_id,
+                            code_hash: hash_recovery_code(code),
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .execute(conn)?;
+
+            Ok(RecoveryCodes { recovery_codes: codes })
+        })
+    }
+
+    /// Turns two-factor authentication off, after
+    /// checking a TOTP- or recovery code once more.
+    pub fn disable(
+        user_id: Uuid,
+        code: &str,
+        conn: &PgConnection,
+    ) -> Result<(), ApiError> {
+        conn.transaction(|| {
+            let two_factor =
+                Self::find_enabled(user_id, conn)?.ok_or_else(|| {
+                    ApiError::Unprocessable(
+                        "Two-factor authentication is not enabled.".into(),
+                    )
+                })?;
+
+            if !two_factor.use_code(code, conn)? {
+                return Err(invalid_code());
+            }
+
+            diesel::delete(
+                recovery_codes::table
+                    .filter(recovery_codes::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                two_factor_challenges::table
+                    .filter(two_factor_challenges::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(two_factor::table.find(user_id)).execute(conn)?;
+
+            Ok(())
+        })
+    }
+
+    /// Starts the second step of a login
+    pub fn challenge(
+        user_id: Uuid,
+        conn: &PgConnection,
+    ) -> QueryResult<Challenge> {
+        let challenge = token::generate();
+        let lifetime = Duration::minutes(CHALLENGE_MINUTES);
+
+        diesel::insert_into(two_factor_challenges::table)
+            .values(NewChallenge {
+                user_id,
+                token_hash: token::hash(&challenge),
+                expires_at: Utc::now() + lifetime,
+            })
+            .execute(conn)?;
+
+        Ok(Challenge { challenge, expires_in: lifetime.num_seconds() })
+    }
+
+    /// Exchanges a challenge and a TOTP- or recovery code for a token pair
+    pub fn complete(
+        challenge: &str,
+        code: &str,
+        client: &ClientInfo,
+        conn: &PgConnection,
+    ) -> Result<Token, ApiError> {
+        let invalid_challenge =
+            || ApiError::Unauthorized("Invalid or expired challenge.".into());
+
+        let (challenge_id, user_id) = two_factor_challenges::table
+            .filter(
+                two_factor_challenges::token_hash.eq(token::hash(challenge)),
+            )
+            .filter(two_factor_challenges::expires_at.gt(Utc::now()))
+            .filter(two_factor_challenges::attempts.lt(CHALLENGE_ATTEMPTS))
+            .select((two_factor_challenges::id, two_factor_challenges::user_id))
+            .first::<(Uuid, Uuid)>(conn)
+            .optional()?
+            .ok_or_else(invalid_challenge)?;
+
+        let two_factor =
+            Self::find_enabled(user_id, conn)?.ok_or_else(invalid_challenge)?;
+
+        let token = conn.transaction(|| {
+            if !two_factor.use_code(code, conn)? {
+                return Ok(None);
+            }
+
+            diesel::delete(two_factor_challenges::table.find(challenge_id))
+                .execute(conn)?;
+
+            let user = User::find_by_id(conn, user_id)?;
+            RefreshToken::issue(&user, client, conn).map(Some)
+        })?;
+
+        match token {
+            Some(token) => Ok(token),
+            None => {
+                diesel::update(two_factor_challenges::table.find(challenge_id))
+                    .set(
+                        two_factor_challenges::attempts
+                            .eq(two_factor_challenges::attempts + 1),
+                    )
+                    .execute(conn)?;
+
+                Err(ApiError::Unauthorized("Invalid code.".into()))
+            }
+        }
+    }
+
+    fn find_enabled(
+        user_id: Uuid,
+        conn: &PgConnection,
+    ) -> QueryResult<Option<Self>> {
+        two_factor::table
+            .find(user_id)
+            .filter(two_factor::enabled_at.is_not_null())
+            .first(conn)
+            .optional()
+    }
+
+    /// Checks a TOTP code, or otherwise a recovery code
+    fn use_code(&self, code: &str, conn: &PgConnection) -> QueryResult<bool> {
+        if code.trim().chars().all(|c| c.is_ascii_digit()) {
+            self.use_totp(code, conn)
+        } else {
+            self.use_recovery_code(code, conn)
+        }
+    }
+
+    /// Checks a TOTP code, every code can only be used once
+    fn use_totp(&self, code: &str, conn: &PgConnection) -> QueryResult<bool> {
+        let step =
+            match totp::verify(&self.secret, code, Utc::now().timestamp()) {
+                Some(step) => step,
+                None => return Ok(false),
+            };
+
+        diesel::update(
+            two_factor::table.find(self.user_id).filter(
+                two_factor::last_used_step
+                    .is_null()
+                    .or(two_factor::last_used_step.lt(step)),
+            ),
+        )
+        .set(two_factor::last_used_step.eq(step))
+        .execute(conn)
+        .map(|updated| updated == 1)
+    }
+
+    fn use_recovery_code(
+        &self,
+        code: &str,
+        conn: &PgConnection,
+    ) -> QueryResult<bool> {
+        diesel::update(
+            recovery_codes::table
+                .filter(recovery_codes::user_id.eq(self.user_id))
+                .filter(recovery_codes::code_hash.eq(hash_recovery_code(code)))
+                .filter(recovery_codes::used_at.is_null()),
+        )
+        .set(recovery_codes::used_at.eq(Utc::now()))
+        .execute(conn)
+        .map(|updated| updated == 1)
+    }
+}
+
+impl TwoFactor {
+    pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::complete);
+    }
+
+    pub fn route_me(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::enroll);
+        cfg.service(routes::confirm);
+        cfg.service(routes::disable);
+    }
+}
+
+mod routes {
+    use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+
+    use crate::users::session::ClientInfo;
+    use crate::utils::{responsable::Responsable, session};
+    use crate::{database::exec_on_pool, DbPool};
+
+    use super::{ChallengeRequest, CodeRequest, TwoFactor};
+
+    #[post("/login/2fa")]
+    pub(super) async fn complete(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+        form: web::Json<ChallengeRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let client =
+            ClientInfo::new(&request.connection_info(), request.headers());
+
+        exec_on_pool(&pool, move |conn| {
+            TwoFactor::complete(&form.challenge, &form.code, &client, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[post("/user/me/2fa/enroll")]
+    pub(super) async fn enroll(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let (user, _) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| TwoFactor::enroll(&user, conn))
+            .await
+            .into_response()
+    }
+
+    #[post("/user/me/2fa/confirm")]
+    pub(super) async fn confirm(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+        form: web::Json<CodeRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let (user, _) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            TwoFactor::confirm(user.id, &form.code, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[post("/user/me/2fa/disable")]
+    pub(super) async fn disable(
+        pool: web::Data<DbPool>,
+        request: HttpRequest,
+        form: web::Json<CodeRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let (user, _) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            TwoFactor::disable(user.id, &form.code, conn)
+        })
+        .await
+        .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use chrono::Utc;
+    use serde_json::{json, Value};
+
+    use super::{Challenge, TwoFactor};
+    use crate::testing;
+    use crate::users::User;
+    use crate::utils::{jwt::Token, totp, validator};
+
+    /// The code an authenticator app would show, `offset` steps from now
+    fn code(secret: &str, offset: i64) -> String {
+        let step = totp::step(Utc::now().timestamp()) + offset;
+        totp::generate(&totp::base32_decode(secret).unwrap(), step as u64, 6)
+    }
+
+    #[actix_rt::test]
+    async fn test_two_factor_login() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    TwoFactor::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(TwoFactor::route_me),
+                    );
+                }
+            }
+
+            test = |app| {
+                let user = json!({
+                    "username": testing::username("2fa"),
+                    "password": testing::PASSWORD,
+                });
+
+                let request =
+                    TestRequest::post().uri("/register").set_json(&user).to_request();
+                call_service(&mut app, request).await;
+
+                let request =
+                    TestRequest::post().uri("/login").set_json(&user).to_request();
+                let login: Token = read_response_json(&mut app, request).await;
+                let bearer = format!("Bearer {}", login.token);
+
+                let request = TestRequest::post()
+                    .uri("/user/me/2fa/enroll")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .to_request();
+                let enrollment: Value =
+                    read_response_json(&mut app, request).await;
+                let secret = enrollment["secret"].as_str().unwrap().to_string();
+                assert!(enrollment["uri"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("otpauth://totp/"));
+
+                let request = TestRequest::post()
+                    .uri("/user/me/2fa/confirm")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "code": code(&secret, 0) }))
+                    .to_request();
+                let confirmed: Value = read_response_json(&mut app, request).await;
+                let recovery_codes = confirmed["recovery_codes"].as_array().unwrap();
+                assert_eq!(recovery_codes.len(), 10);
+
+                // The password alone is no longer enough
+                let request =
+                    TestRequest::post().uri("/login").set_json(&user).to_request();
+                let challenge: Challenge =
+                    read_response_json(&mut app, request).await;
+
+                // A code can't be used twice
+                let request = TestRequest::post()
+                    .uri("/login/2fa")
+                    .set_json(&json!({
+                        "challenge": challenge.challenge,
+                        "code": code(&secret, 0),
+                    }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+                let request = TestRequest::post()
+                    .uri("/login/2fa")
+                    .set_json(&json!({
+                        "challenge": challenge.challenge,
+                        "code": code(&secret, 1),
+                    }))
+                    .to_request();
+                let login: Token = read_response_json(&mut app, request).await;
+                let bearer = format!("Bearer {}", login.token);
+
+                // Challenges are single use
+                let request = TestRequest::post()
+                    .uri("/login/2fa")
+                    .set_json(&json!({
+                        "challenge": challenge.challenge,
+                        "code": recovery_codes[0],
+                    }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+                let request = TestRequest::post()
+                    .uri("/user/me/2fa/disable")
+                    .header(header::AUTHORIZATION, bearer)
+                    .set_json(&json!({ "code": recovery_codes[0] }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let request =
+                    TestRequest::post().uri("/login").set_json(&user).to_request();
+                let _: Token = read_response_json(&mut app, request).await;
+
+                Ok(())
+            }
+        }
+    }
+}
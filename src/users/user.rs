@@ -1,10 +1,21 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use diesel::{pg::PgConnection, prelude::*, QueryResult};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::database::lower;
 use crate::schema::users;
+use crate::users::two_factor::Challenge;
+use crate::utils::{
+    error::{ApiError, FieldErrors},
+    jwt::Token,
+    password,
+    policy::Policy,
+};
 
 use crate::items::crud::{Create, Find};
+use crate::items::template;
 
 #[derive(
     Identifiable, Queryable, Deserialize, Serialize, Insertable, Debug, Clone,
@@ -13,17 +24,21 @@ pub struct User {
     pub id: Uuid,
     pub username: String,
     pub password: String,
+    pub email: Option<String>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    /// The IANA name of the timezone of the user, e.g. `Europe/Amsterdam`
+    pub timezone: String,
 }
 
 impl User {
     pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
         cfg.service(routes::login);
         cfg.service(routes::register);
-        cfg.service(routes::update_user);
     }
 
     pub fn route_me(cfg: &mut actix_web::web::ServiceConfig) {
         cfg.service(routes::me);
+        cfg.service(routes::update_user);
     }
 }
 
@@ -32,13 +47,138 @@ impl User {
 pub struct NewUser {
     username: String,
     password: String,
+    timezone: Option<String>,
+}
+
+const USERNAME_MIN_LENGTH: usize = 3;
+const USERNAME_MAX_LENGTH: usize = 32;
+const PASSWORD_MIN_LENGTH: usize = 8;
+const PASSWORD_MAX_LENGTH: usize = 128;
+
+pub(crate) fn validate_username(username: &str, errors: &mut FieldErrors) {
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        errors.add(
+            "username",
+            format!(
+                "must be between {} and {} characters long.",
+                USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+            ),
+        );
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        errors.add(
+            "username",
+            "may only contain letters, digits, '_', '-' and '.'.",
+        );
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        errors.add("username", "must start with a letter or digit.");
+    }
+}
+
+pub(crate) fn validate_timezone(timezone: &str, errors: &mut FieldErrors) {
+    if timezone.parse::<Tz>().is_err() {
+        errors.add("timezone", "must be a timezone such as Europe/Amsterdam.");
+    }
+}
+
+pub(crate) fn validate_password(
+    password: &str,
+    username: &str,
+    errors: &mut FieldErrors,
+) {
+    let length = password.chars().count();
+    if length < PASSWORD_MIN_LENGTH {
+        errors.add(
+            "password",
+            format!(
+                "must be at least {} characters long.",
+                PASSWORD_MIN_LENGTH
+            ),
+        );
+    }
+    if length > PASSWORD_MAX_LENGTH {
+        errors.add(
+            "password",
+            format!("must be at most {} characters long.", PASSWORD_MAX_LENGTH),
+        );
+    }
+
+    let classes: [fn(char) -> bool; 4] = [
+        |c| c.is_lowercase(),
+        |c| c.is_uppercase(),
+        |c| c.is_ascii_digit(),
+        |c| !c.is_alphanumeric(),
+    ];
+    let used =
+        classes.iter().filter(|&&class| password.chars().any(class)).count();
+    if used < 2 {
+        errors.add(
+            "password",
+            "must mix at least two of lowercase letters, uppercase letters, \
+             digits and symbols.",
+        );
+    }
+
+    if !username.is_empty()
+        && password.to_lowercase().contains(&username.to_lowercase())
+    {
+        errors.add("password", "must not contain the username.");
+    }
+}
+
+/// Whether another user has the username, ignoring case
+fn username_taken(
+    username: &str,
+    except: Option<Uuid>,
+    conn: &PgConnection,
+) -> QueryResult<bool> {
+    let others = users::table
+        .filter(lower(users::username).eq(lower(username)))
+        .filter(users::id.ne(except.unwrap_or_else(Uuid::nil)));
+
+    diesel::select(diesel::dsl::exists(others)).get_result(conn)
+}
+
+/// Another registration might have taken the username since it was checked
+fn map_username_conflict(err: diesel::result::Error) -> ApiError {
+    match ApiError::from(err) {
+        ApiError::Conflict(_) => {
+            let mut errors = FieldErrors::default();
+            errors.add("username", "is already taken.");
+            ApiError::Invalid(errors)
+        }
+        err => err,
+    }
 }
 
 impl NewUser {
+    fn validate(&self, conn: &PgConnection) -> Result<(), ApiError> {
+        let mut errors = FieldErrors::default();
+
+        validate_username(&self.username, &mut errors);
+        validate_password(&self.password, &self.username, &mut errors);
+        if let Some(timezone) = &self.timezone {
+            validate_timezone(timezone, &mut errors);
+        }
+        if errors.get("username").is_none()
+            && username_taken(&self.username, None, conn)?
+        {
+            errors.add("username", "is already taken.");
+        }
+
+        errors.into_result()
+    }
+
     fn hash_password(&self) -> Self {
         Self {
-            password: crate::utils::hash_password(&self.password),
+            password: password::hash(&self.password),
             username: self.username.clone(),
+            timezone: self.timezone.clone(),
         }
     }
 }
@@ -49,11 +189,21 @@ pub struct LoginUser {
     password: String,
 }
 
+/// A login either results in a token, or in a challenge
+/// when the user has two-factor authentication enabled.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Token(Token),
+    Challenge(Challenge),
+}
+
 #[derive(AsChangeset, Deserialize)]
 #[table_name = "users"]
 pub struct UpdateUser {
     username: Option<String>,
     password: Option<String>,
+    timezone: Option<String>,
 }
 
 impl Create for User {
@@ -75,58 +225,113 @@ impl<'a> Find<&'a LoginUser> for User {
         conn: &PgConnection,
     ) -> QueryResult<Self> {
         users::table
-            .filter(users::username.eq(&loginuser.username))
-            .load::<User>(conn)?
-            .into_iter()
-            .find(move |user| user.verify_password(loginuser))
-            .ok_or_else(|| diesel::result::Error::NotFound)
+            .filter(lower(users::username).eq(lower(&loginuser.username)))
+            .first::<User>(conn)
+            .optional()?
+            .filter(|user| user.verify_password(loginuser))
+            .ok_or(diesel::result::Error::NotFound)
     }
 }
 
 impl UpdateUser {
+    fn validate(&self, id: Uuid, conn: &PgConnection) -> Result<(), ApiError> {
+        let mut errors = FieldErrors::default();
+
+        if let Some(username) = &self.username {
+            validate_username(username, &mut errors);
+            if errors.get("username").is_none()
+                && username_taken(username, Some(id), conn)?
+            {
+                errors.add("username", "is already taken.");
+            }
+        }
+        if let Some(password) = &self.password {
+            let username = match &self.username {
+                Some(username) => username.clone(),
+                None => User::find_by_id(conn, id)?.username,
+            };
+            validate_password(password, &username, &mut errors);
+        }
+        if let Some(timezone) = &self.timezone {
+            validate_timezone(timezone, &mut errors);
+        }
+
+        errors.into_result()
+    }
+
     fn hash_password(self) -> Self {
-        let password =
-            self.password.as_ref().map(|s| crate::utils::hash_password(s));
+        let password = self.password.as_ref().map(|s| password::hash(s));
 
-        Self { password, username: self.username }
+        Self { password, ..self }
     }
 }
 
 impl User {
+    fn register(
+        new_user: &NewUser,
+        conn: &PgConnection,
+    ) -> Result<Self, ApiError> {
+        new_user.validate(conn)?;
+
+        conn.transaction(|| {
+            let user =
+                User::create(new_user, conn).map_err(map_username_conflict)?;
+            template::seed(&user, conn)?;
+            Ok(user)
+        })
+    }
+
     fn update(
         id: Uuid,
+        user: &User,
         conn: &PgConnection,
         update_user: UpdateUser,
-    ) -> QueryResult<Self> {
+    ) -> Result<Self, ApiError> {
+        User::authorize(id, user, conn)?;
+        update_user.validate(id, conn)?;
         let update_user = update_user.hash_password();
 
         diesel::update(users::table.filter(users::id.eq(id)))
             .set(update_user)
             .get_result(conn)
+            .map_err(map_username_conflict)
     }
 
     pub fn find_by_id(conn: &PgConnection, id: Uuid) -> QueryResult<Self> {
         users::table.filter(users::id.eq(id)).first::<User>(conn)
     }
+
+    pub(crate) fn set_password(
+        id: Uuid,
+        password: &str,
+        conn: &PgConnection,
+    ) -> QueryResult<Self> {
+        diesel::update(users::table.filter(users::id.eq(id)))
+            .set(users::password.eq(password::hash(password)))
+            .get_result(conn)
+    }
 }
 
 #[derive(Debug)]
 struct InvalidPassword;
 
 impl User {
-    fn into_jwt(self) -> crate::utils::jwt::Token {
-        use crate::utils::jwt::Jwt;
-        use chrono::Duration;
-
-        Jwt::new("journali.nl".to_string(), Duration::days(30), self.id)
-            .tokenize()
+    fn verify_password(&self, user: &LoginUser) -> bool {
+        password::verify(&user.password, &self.password)
     }
 
-    fn verify_password(&self, user: &LoginUser) -> bool {
-        match bcrypt::verify(&user.password, &self.password) {
-            Ok(true) => true,
-            _ => false,
+    /// Rehashes a verified password when its hash is outdated,
+    /// so hashes get stronger as users log in.
+    fn upgrade_password(
+        &self,
+        user: &LoginUser,
+        conn: &PgConnection,
+    ) -> QueryResult<()> {
+        if password::needs_rehash(&self.password) {
+            User::set_password(self.id, &user.password, conn)?;
         }
+
+        Ok(())
     }
 }
 
@@ -137,25 +342,60 @@ mod routes {
         Error, HttpRequest, HttpResponse,
     };
 
-    use crate::utils::responsable::Responsable;
+    use crate::utils::{
+        error::ApiError, rate_limit::RateLimiter, responsable::Responsable,
+        session,
+    };
     use crate::{database::exec_on_pool, DbPool};
 
-    use super::{LoginUser, NewUser, UpdateUser, User};
+    use super::{LoginResponse, LoginUser, NewUser, UpdateUser, User};
+    use crate::users::{session::ClientInfo, RefreshToken, TwoFactor};
     use uuid::Uuid;
 
-    use crate::items::crud::{Crudder, Find};
+    use crate::items::crud::Find;
 
     #[post("/login")]
     pub(super) async fn login(
         pool: web::Data<DbPool>,
+        limiter: web::Data<RateLimiter>,
+        request: HttpRequest,
         user: web::Json<LoginUser>,
     ) -> Result<HttpResponse, Error> {
+        limiter.hit_username(&user.username).await?;
+
         let cloned_user = user.clone();
+        let client =
+            ClientInfo::new(&request.connection_info(), request.headers());
+
+        let result = exec_on_pool(&pool, move |conn| {
+            let user = User::find(&cloned_user, conn)?;
+            user.upgrade_password(&cloned_user, conn)?;
+
+            if TwoFactor::is_enabled(user.id, conn)? {
+                TwoFactor::challenge(user.id, conn)
+                    .map(LoginResponse::Challenge)
+            } else {
+                RefreshToken::issue(&user, &client, conn)
+                    .map(LoginResponse::Token)
+            }
+        })
+        .await
+        .map_err(|err| match err {
+            ApiError::NotFound => {
+                ApiError::Unauthorized("Invalid username or password.".into())
+            }
+            err => err,
+        });
 
-        exec_on_pool(&pool, move |conn| User::find(&cloned_user, conn))
-            .await
-            .map(User::into_jwt)
-            .into_response()
+        match result {
+            Ok(_) => limiter.login_succeeded(&user.username).await?,
+            Err(ApiError::Unauthorized(_)) => {
+                limiter.login_failed(&user.username).await?
+            }
+            Err(_) => {}
+        }
+
+        result.into_response()
     }
 
     #[post("/register")]
@@ -163,25 +403,30 @@ mod routes {
         pool: web::Data<DbPool>,
         new_user: web::Json<NewUser>,
     ) -> Result<HttpResponse, Error> {
-        Crudder::<User>::create(new_user.into_inner(), &pool).await
+        exec_on_pool(&pool, move |conn| User::register(&new_user, conn))
+            .await
+            .into_response()
     }
 
     #[get("/user/me")]
     pub(super) async fn me(
         request: HttpRequest,
     ) -> Result<HttpResponse, Error> {
-        let user: User = request.extensions().get().cloned().unwrap();
+        let (user, _) = session(&request)?;
         Ok(HttpResponse::Ok().json(user))
     }
 
     #[patch("/users/{id}")]
     pub async fn update_user(
         pool: web::Data<DbPool>,
+        request: HttpRequest,
         id: web::Path<Uuid>,
         update_user: web::Json<UpdateUser>,
     ) -> Result<HttpResponse, Error> {
+        let (user, _) = session(&request)?;
+
         exec_on_pool(&pool, move |conn| {
-            User::update(id.into_inner(), conn, update_user.into_inner())
+            User::update(id.into_inner(), &user, conn, update_user.into_inner())
         })
         .await
         .into_response()
@@ -192,11 +437,16 @@ mod routes {
 mod tests {
     use super::routes;
     use super::{LoginUser, NewUser, User};
+    use crate::schema::users;
+    use crate::testing;
+    use crate::utils::password;
     use actix_web::{
         http::StatusCode,
         test,
         test::{call_service, read_response_json, TestRequest},
     };
+    use diesel::prelude::*;
+    use serde_json::Value;
 
     fn build_request<T: serde::Serialize>(uri: &str, json: &T) -> TestRequest {
         test::TestRequest::post().uri(uri).set_json(json)
@@ -205,7 +455,11 @@ mod tests {
     fn register_request(username: &str, password: &str) -> TestRequest {
         build_request(
             "/register",
-            &NewUser { username: username.into(), password: password.into() },
+            &NewUser {
+                username: username.into(),
+                password: password.into(),
+                timezone: None,
+            },
         )
     }
 
@@ -217,15 +471,15 @@ mod tests {
             }
 
             test = |app| {
-                const USER_NAME: &str = "sailor jack";
+                let user_name = testing::username("sailor_jack");
                 const PASSWORD: &str = "black pearl";
 
-                let request = register_request(USER_NAME, PASSWORD).to_request();
+                let request = register_request(&user_name, PASSWORD).to_request();
 
                 let user: User = read_response_json(&mut app, request).await;
 
-                assert_eq!(user.username, USER_NAME);
-                let passwd_verify = bcrypt::verify(PASSWORD, &user.password)?;
+                assert_eq!(user.username, user_name);
+                let passwd_verify = password::verify(PASSWORD, &user.password);
 
                 assert!(passwd_verify);
 
@@ -245,12 +499,12 @@ mod tests {
             }
 
             test = |app| {
-                const USER_NAME: &str = "sailor2";
+                let user_name = testing::username("sailor2");
                 const PASSWORD: &str = "black pearl";
 
                 // Need to register before login
                 {
-                    let request = register_request(USER_NAME, PASSWORD).to_request();
+                    let request = register_request(&user_name, PASSWORD).to_request();
 
                     let resp = call_service(&mut app, request).await;
                     assert_eq!(resp.status(), StatusCode::OK);
@@ -261,7 +515,8 @@ mod tests {
                     let request = build_request(
                         "/login",
                         &LoginUser {
-                            username: USER_NAME.into(),
+                            // Usernames are case-insensitive
+                            username: user_name.to_uppercase(),
                             password: PASSWORD.into(),
                         },
                     )
@@ -274,4 +529,108 @@ mod tests {
             }
         }
     }
+
+    #[actix_rt::test]
+    async fn test_login_upgrades_bcrypt_hash(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    cfg.service(routes::register);
+                    cfg.service(routes::login);
+                }
+            }
+
+            test = |app| {
+                let user_name = testing::username("old_timer");
+
+                let request =
+                    register_request(&user_name, testing::PASSWORD).to_request();
+                let user: User = read_response_json(&mut app, request).await;
+
+                // As registered before passwords were hashed with Argon2id
+                let conn = crate::create_pool().get()?;
+                diesel::update(users::table.find(user.id))
+                    .set(users::password.eq(bcrypt::hash(testing::PASSWORD, 4)?))
+                    .execute(&conn)?;
+
+                let request = build_request(
+                    "/login",
+                    &LoginUser {
+                        username: user_name,
+                        password: testing::PASSWORD.into(),
+                    },
+                )
+                .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let hash: String = users::table
+                    .find(user.id)
+                    .select(users::password)
+                    .first(&conn)?;
+                assert!(hash.starts_with("$argon2id$"));
+                assert!(password::verify(testing::PASSWORD, &hash));
+
+                Ok(())
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_register_validation() -> Result<(), Box<dyn std::error::Error>>
+    {
+        test! {
+            setup {
+                |cfg| { cfg.service(routes::register); }
+            }
+
+            test = |app| {
+                let request = register_request("a!", "short").to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                let body: Value =
+                    serde_json::from_slice(&test::read_body(resp).await)?;
+                assert_eq!(body["code"], "validation_failed");
+                assert_eq!(body["errors"]["username"].as_array().unwrap().len(), 2);
+                assert_eq!(body["errors"]["password"].as_array().unwrap().len(), 2);
+
+                let user_name = testing::username("Taken");
+                let request =
+                    register_request(&user_name, testing::PASSWORD).to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let request = register_request(
+                    &user_name.to_lowercase(),
+                    testing::PASSWORD,
+                )
+                .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                let body: Value =
+                    serde_json::from_slice(&test::read_body(resp).await)?;
+                assert_eq!(body["errors"]["username"][0], "is already taken.");
+                assert!(body["errors"].get("password").is_none());
+
+                let request = build_request(
+                    "/register",
+                    &NewUser {
+                        username: testing::username("Traveller"),
+                        password: testing::PASSWORD.into(),
+                        timezone: Some("Mars/Olympus_Mons".into()),
+                    },
+                )
+                .to_request();
+                let resp = call_service(&mut app, request).await;
+                let body: Value =
+                    serde_json::from_slice(&test::read_body(resp).await)?;
+                assert_eq!(body["errors"]["timezone"].as_array().unwrap().len(), 1);
+
+                Ok(())
+            }
+        }
+    }
 }
@@ -0,0 +1,476 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::{pg::PgConnection, prelude::*, QueryResult};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::mailer::{Mail, Mailer};
+use crate::schema::{user_tokens, users};
+use crate::users::{user::validate_password, RefreshToken, User};
+use crate::utils::{
+    error::{ApiError, FieldErrors},
+    token,
+};
+
+/// What a token may be used for
+#[derive(Clone, Copy)]
+pub enum Purpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Purpose::VerifyEmail => "verify_email",
+            Purpose::ResetPassword => "reset_password",
+        }
+    }
+
+    fn lifetime(self) -> Duration {
+        match self {
+            Purpose::VerifyEmail => Duration::days(2),
+            Purpose::ResetPassword => Duration::hours(1),
+        }
+    }
+}
+
+/// A single use token that is mailed to a user.
+/// Only its hash is stored, which is never loaded.
+#[derive(Identifiable, Queryable, Associations)]
+#[belongs_to(User)]
+pub struct UserToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub purpose: String,
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_tokens"]
+struct NewUserToken<'a> {
+    user_id: Uuid,
+    purpose: &'static str,
+    token_hash: String,
+    email: &'a str,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct EmailRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    token: String,
+    password: String,
+}
+
+fn normalize_email(email: &str) -> Result<String, ApiError> {
+    let email = email.trim().to_lowercase();
+    match email.split('@').collect::<Vec<_>>().as_slice() {
+        [local, domain] if !local.is_empty() && domain.contains('.') => {
+            Ok(email)
+        }
+        _ => Err(ApiError::Unprocessable("Invalid email address.".into())),
+    }
+}
+
+fn app_url() -> String {
+    std::env::var("APP_URL").unwrap_or_else(|_| "https://journali.nl".into())
+}
+
+impl UserToken {
+    /// Creates a new token, any earlier unused
+    /// token with the same purpose is invalidated.
+    fn issue(
+        user_id: Uuid,
+        purpose: Purpose,
+        email: &str,
+        conn: &PgConnection,
+    ) -> QueryResult<String> {
+        diesel::update(
+            user_tokens::table
+                .filter(user_tokens::user_id.eq(user_id))
+                .filter(user_tokens::purpose.eq(purpose.as_str()))
+                .filter(user_tokens::used_at.is_null()),
+        )
+        .set(user_tokens::used_at.eq(Utc::now()))
+        .execute(conn)?;
+
+        let token = token::generate();
+        diesel::insert_into(user_tokens::table)
+            .values(NewUserToken {
+                user_id,
+                purpose: purpose.as_str(),
+                token_hash: token::hash(&token),
+                email,
+                expires_at: Utc::now() + purpose.lifetime(),
+            })
+            .execute(conn)?;
+
+        Ok(token)
+    }
+
+    /// Marks a token as used, it can only be used once
+    fn consume(
+        token: &str,
+        purpose: Purpose,
+        conn: &PgConnection,
+    ) -> Result<Self, ApiError> {
+        diesel::update(
+            user_tokens::table
+                .filter(user_tokens::token_hash.eq(token::hash(token)))
+                .filter(user_tokens::purpose.eq(purpose.as_str()))
+                .filter(user_tokens::used_at.is_null())
+                .filter(user_tokens::expires_at.gt(Utc::now())),
+        )
+        .set(user_tokens::used_at.eq(Utc::now()))
+        .returning((
+            user_tokens::id,
+            user_tokens::user_id,
+            user_tokens::purpose,
+            user_tokens::email,
+            user_tokens::created_at,
+            user_tokens::expires_at,
+            user_tokens::used_at,
+        ))
+        .get_result(conn)
+        .optional()?
+        .ok_or_else(|| {
+            ApiError::Unprocessable("Invalid or expired token.".into())
+        })
+    }
+
+    /// Sets a new, unverified, email address for the
+    /// user and mails a link to verify it.
+    pub fn change_email(
+        user: &User,
+        email: &str,
+        mailer: &dyn Mailer,
+        conn: &PgConnection,
+    ) -> Result<User, ApiError> {
+        let email = normalize_email(email)?;
+
+        conn.transaction(|| {
+            let user = diesel::update(users::table.find(user.id))
+                .set((
+                    users::email.eq(&email),
+                    users::email_verified_at.eq(None::<DateTime<Utc>>),
+                ))
+                .get_result::<User>(conn)?;
+
+            let token =
+                Self::issue(user.id, Purpose::VerifyEmail, &email, conn)?;
+            mailer.send(Mail {
+                to: email.clone(),
+                subject: "Verify your email address".into(),
+                body: format!(
+                    "Open the link below to verify your email address:\n\n\
+                     {}/verify-email?token={}",
+                    app_url(),
+                    token
+                ),
+            })?;
+
+            Ok(user)
+        })
+    }
+
+    pub fn verify_email(
+        token: &str,
+        conn: &PgConnection,
+    ) -> Result<User, ApiError> {
+        conn.transaction(|| {
+            let user_token = Self::consume(token, Purpose::VerifyEmail, conn)?;
+
+            // The email address might have changed since the token was sent
+            diesel::update(
+                users::table
+                    .find(user_token.user_id)
+                    .filter(users::email.eq(&user_token.email)),
+            )
+            .set(users::email_verified_at.eq(Utc::now()))
+            .get_result(conn)
+            .optional()?
+            .ok_or_else(|| {
+                ApiError::Unprocessable("Invalid or expired token.".into())
+            })
+        })
+    }
+
+    /// Mails a password reset link, when there is a
+    /// user with the given verified email address.
+    pub fn forgot_password(
+        email: &str,
+        mailer: &dyn Mailer,
+        conn: &PgConnection,
+    ) -> Result<(), ApiError> {
+        let email = normalize_email(email)?;
+
+        let user = users::table
+            .filter(users::email.eq(&email))
+            .filter(users::email_verified_at.is_not_null())
+            .first::<User>(conn)
+            .optional()?;
+
+        // Don't tell whether an account exists for this email
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+
+        let token = Self::issue(user.id, Purpose::ResetPassword, &email, conn)?;
+        mailer
+            .send(Mail {
+                to: email,
+                subject: "Reset your password".into(),
+                body: format!(
+                    "Hi {},\n\nOpen the link below to choose a new password:\n\n\
+                     {}/reset-password?token={}\n\n\
+                     If you did not request this, you can ignore this mail.",
+                    user.username,
+                    app_url(),
+                    token
+                ),
+            })
+            .map_err(ApiError::from)
+    }
+
+    /// Sets a new password and logs the user out everywhere
+    pub fn reset_password(
+        token: &str,
+        password: &str,
+        conn: &PgConnection,
+    ) -> Result<(), ApiError> {
+        conn.transaction(|| {
+            let user_token =
+                Self::consume(token, Purpose::ResetPassword, conn)?;
+
+            let user = User::find_by_id(conn, user_token.user_id)?;
+            let mut errors = FieldErrors::default();
+            validate_password(password, &user.username, &mut errors);
+            errors.into_result()?;
+
+            User::set_password(user.id, password, conn)?;
+            RefreshToken::revoke_all(user_token.user_id, conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl UserToken {
+    pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::verify_email);
+        cfg.service(routes::forgot_password);
+        cfg.service(routes::reset_password);
+    }
+
+    pub fn route_me(cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(routes::change_email);
+    }
+}
+
+mod routes {
+    use actix_web::{post, put, web, Error, HttpRequest, HttpResponse};
+
+    use crate::mailer::MailerData;
+    use crate::utils::{responsable::Responsable, session};
+    use crate::{database::exec_on_pool, DbPool};
+
+    use super::{
+        EmailRequest, ResetPasswordRequest, UserToken, VerifyEmailRequest,
+    };
+
+    #[put("/user/me/email")]
+    pub(super) async fn change_email(
+        pool: web::Data<DbPool>,
+        mailer: web::Data<MailerData>,
+        request: HttpRequest,
+        form: web::Json<EmailRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let (user, _) = session(&request)?;
+
+        exec_on_pool(&pool, move |conn| {
+            UserToken::change_email(
+                &user,
+                &form.email,
+                mailer.get_ref().as_ref(),
+                conn,
+            )
+        })
+        .await
+        .into_response()
+    }
+
+    #[post("/email/verify")]
+    pub(super) async fn verify_email(
+        pool: web::Data<DbPool>,
+        form: web::Json<VerifyEmailRequest>,
+    ) -> Result<HttpResponse, Error> {
+        exec_on_pool(&pool, move |conn| {
+            UserToken::verify_email(&form.token, conn)
+        })
+        .await
+        .into_response()
+    }
+
+    #[post("/password/forgot")]
+    pub(super) async fn forgot_password(
+        pool: web::Data<DbPool>,
+        mailer: web::Data<MailerData>,
+        form: web::Json<EmailRequest>,
+    ) -> Result<HttpResponse, Error> {
+        exec_on_pool(&pool, move |conn| {
+            UserToken::forgot_password(
+                &form.email,
+                mailer.get_ref().as_ref(),
+                conn,
+            )
+        })
+        .await
+        .into_response()
+    }
+
+    #[post("/password/reset")]
+    pub(super) async fn reset_password(
+        pool: web::Data<DbPool>,
+        form: web::Json<ResetPasswordRequest>,
+    ) -> Result<HttpResponse, Error> {
+        exec_on_pool(&pool, move |conn| {
+            UserToken::reset_password(&form.token, &form.password, conn)
+        })
+        .await
+        .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::json;
+
+    use super::UserToken;
+    use crate::mailer::{FileMailer, MailerData};
+    use crate::testing;
+    use crate::users::User;
+    use crate::utils::{jwt::Token, validator};
+
+    /// Reads the token from the last mail that was sent
+    fn last_mailed_token(path: &std::path::Path) -> String {
+        let mails = std::fs::read_to_string(path).unwrap();
+        let (_, token) = mails.rsplit_once("token=").unwrap();
+        token.lines().next().unwrap().to_string()
+    }
+
+    #[actix_rt::test]
+    async fn test_verify_email_and_reset_password(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mail_file = std::env::temp_dir().join("journali-test-mail.txt");
+        let _ = std::fs::remove_file(&mail_file);
+        let mailer: MailerData = Box::new(FileMailer::new(
+            "test@journali.nl".into(),
+            Some(mail_file.clone()),
+        ));
+        let email = format!("{}@journali.nl", uuid::Uuid::new_v4());
+
+        test! {
+            setup {
+                |cfg| {
+                    cfg.data(mailer);
+                    User::routes(cfg);
+                    UserToken::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(UserToken::route_me),
+                    );
+                }
+            }
+
+            test = |app| {
+                let username = testing::username("forgetful");
+                let user = serde_json::json!({
+                    "username": username,
+                    "password": testing::PASSWORD,
+                })
+                .to_string();
+
+                let request = TestRequest::post()
+                    .uri("/register")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                call_service(&mut app, request).await;
+
+                let request = TestRequest::post()
+                    .uri("/login")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .set_payload(user.clone())
+                    .to_request();
+                let login: Token = read_response_json(&mut app, request).await;
+
+                let request = TestRequest::put()
+                    .uri("/user/me/email")
+                    .header(header::AUTHORIZATION, format!("Bearer {}", login.token))
+                    .set_json(&json!({ "email": email }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let request = TestRequest::post()
+                    .uri("/email/verify")
+                    .set_json(&json!({ "token": last_mailed_token(&mail_file) }))
+                    .to_request();
+                let verified: User = read_response_json(&mut app, request).await;
+                assert!(verified.email_verified_at.is_some());
+
+                let request = TestRequest::post()
+                    .uri("/password/forgot")
+                    .set_json(&json!({ "email": email }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let reset = json!({
+                    "token": last_mailed_token(&mail_file),
+                    "password": "not so simple",
+                });
+                for expected in &[StatusCode::OK, StatusCode::UNPROCESSABLE_ENTITY] {
+                    let request = TestRequest::post()
+                        .uri("/password/reset")
+                        .set_json(&reset)
+                        .to_request();
+                    let resp = call_service(&mut app, request).await;
+                    assert_eq!(resp.status(), *expected);
+                }
+
+                let request = TestRequest::post()
+                    .uri("/login")
+                    .set_json(&json!({
+                        "username": username,
+                        "password": "not so simple",
+                    }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                Ok(())
+            }
+        }
+    }
+}
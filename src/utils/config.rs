@@ -0,0 +1,21 @@
+//! Settings read from the environment at startup.
+//!
+//! A malformed setting is reported as an error, so the server refuses to
+//! start instead of failing on the first request that needs it.
+
+use std::{error::Error, fmt::Display, str::FromStr};
+
+/// Parses the environment variable, the default is used when it isn't set
+pub fn env_or<T>(name: &str, default: T) -> Result<T, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|err| format!("{} is invalid: {}", name, err).into()),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,270 @@
+//! The error type that is returned by every endpoint.
+//!
+//! Every error gets rendered as a JSON [`ErrMsg`](struct.ErrMsg.html),
+//! containing the http status, a stable error code clients can match on,
+//! and a human readable message.
+
+use core::fmt::{self, Debug, Display};
+use std::collections::BTreeMap;
+
+use actix_web::{
+    error::{BlockingError, JsonPayloadError, QueryPayloadError},
+    http::{header, StatusCode},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::Serialize;
+
+use crate::mailer::MailError;
+
+#[derive(Serialize)]
+pub struct ErrMsg {
+    pub status: String,
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<FieldErrors>,
+}
+
+/// The problems with each field of a request body, by field name
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct FieldErrors(BTreeMap<&'static str, Vec<String>>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.entry(field).or_default().push(message.into());
+    }
+
+    pub fn get(&self, field: &str) -> Option<&[String]> {
+        self.0.get(field).map(Vec::as_slice)
+    }
+
+    /// Fails with all collected errors, if there are any
+    pub fn into_result(self) -> Result<(), ApiError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Invalid(self))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The resource does not exist, or is not owned by the current user
+    NotFound,
+    /// The resource conflicts with an already existing resource
+    Conflict(String),
+    /// The request was well-formed, but contained invalid data
+    Unprocessable(String),
+    /// Like `Unprocessable`, for specific fields of the request
+    Invalid(FieldErrors),
+    /// The request did not contain valid credentials
+    Unauthorized(String),
+    /// The credentials are valid, but don't grant access to the resource
+    Forbidden(String),
+    /// The resource was changed since the client loaded it
+    PreconditionFailed,
+    /// The client made too many requests, it may retry after the seconds
+    TooManyRequests(i64),
+    /// A backing service (e.g. the database) is not available
+    ServiceUnavailable,
+    /// Something went wrong that the client can't do anything about
+    Internal,
+}
+
+impl ApiError {
+    /// A stable, machine readable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Unprocessable(_) => "unprocessable_entity",
+            ApiError::Invalid(_) => "validation_failed",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::PreconditionFailed => "precondition_failed",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::ServiceUnavailable => "service_unavailable",
+            ApiError::Internal => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::NotFound => "Resource not found.".into(),
+            ApiError::Invalid(_) => {
+                "The request contains invalid fields.".into()
+            }
+            ApiError::Conflict(message)
+            | ApiError::Unprocessable(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message) => message.clone(),
+            ApiError::PreconditionFailed => {
+                "The resource was changed since it was loaded.".into()
+            }
+            ApiError::TooManyRequests(retry_after) => format!(
+                "Too many requests, try again in {} seconds.",
+                retry_after
+            ),
+            ApiError::ServiceUnavailable => {
+                "Service temporarily unavailable.".into()
+            }
+            ApiError::Internal => "Internal server error.".into(),
+        }
+    }
+
+    /// The body of the response to the error
+    pub fn body(&self) -> ErrMsg {
+        ErrMsg {
+            status: self.status_code().as_str().to_string(),
+            code: self.code(),
+            message: self.message(),
+            errors: match self {
+                ApiError::Invalid(errors) => Some(errors.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn json_error_handler(
+        err: JsonPayloadError,
+        _: &HttpRequest,
+    ) -> actix_web::Error {
+        ApiError::Unprocessable(err.to_string()).into()
+    }
+
+    pub fn query_error_handler(
+        err: QueryPayloadError,
+        _: &HttpRequest,
+    ) -> actix_web::Error {
+        ApiError::Unprocessable(err.to_string()).into()
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Unprocessable(_) | ApiError::Invalid(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let ApiError::TooManyRequests(retry_after) = self {
+            response.header(header::RETRY_AFTER, retry_after.to_string());
+        }
+
+        response.json(self.body())
+    }
+}
+
+impl From<DieselError> for ApiError {
+    fn from(err: DieselError) -> Self {
+        match err {
+            DieselError::NotFound => ApiError::NotFound,
+            DieselError::DatabaseError(kind, info) => match kind {
+                DatabaseErrorKind::UniqueViolation => {
+                    ApiError::Conflict("Resource already exists.".into())
+                }
+                DatabaseErrorKind::ForeignKeyViolation => {
+                    ApiError::Unprocessable(
+                        "Referenced resource does not exist.".into(),
+                    )
+                }
+                DatabaseErrorKind::UnableToSendCommand => {
+                    log::error!("database error: {}", info.message());
+                    ApiError::ServiceUnavailable
+                }
+                _ => {
+                    log::error!("database error: {}", info.message());
+                    ApiError::Internal
+                }
+            },
+            err => {
+                log::error!("database error: {}", err);
+                ApiError::Internal
+            }
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for ApiError {
+    fn from(err: diesel::r2d2::PoolError) -> Self {
+        log::error!("couldn't get db connection from pool: {}", err);
+        ApiError::ServiceUnavailable
+    }
+}
+
+impl From<bcrypt::BcryptError> for ApiError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        log::error!("bcrypt error: {}", err);
+        ApiError::Internal
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for ApiError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        ApiError::Unauthorized(format!("Invalid token: {}.", err))
+    }
+}
+
+impl From<MailError> for ApiError {
+    fn from(err: MailError) -> Self {
+        log::error!("{}", err);
+        ApiError::ServiceUnavailable
+    }
+}
+
+impl<E> From<BlockingError<E>> for ApiError
+where
+    E: Into<ApiError> + Debug,
+{
+    fn from(err: BlockingError<E>) -> Self {
+        match err {
+            BlockingError::Error(err) => err.into(),
+            BlockingError::Canceled => ApiError::ServiceUnavailable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiError;
+    use actix_web::{http::StatusCode, ResponseError};
+    use diesel::result::Error as DieselError;
+
+    #[test]
+    fn test_not_found_maps_to_404() {
+        let err = ApiError::from(DieselError::NotFound);
+
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(err.code(), "not_found");
+    }
+
+    #[test]
+    fn test_rollback_maps_to_500() {
+        let err = ApiError::from(DieselError::RollbackTransaction);
+
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,262 @@
+//! Conditional requests on items.
+//!
+//! The ETag of an item is its version, which the database bumps on every
+//! change to it. Clients send it back in `If-Match` when they change the
+//! item, so they don't overwrite changes they haven't seen, and in
+//! `If-None-Match` when they load it again, to skip loading it if nothing
+//! changed.
+
+use actix_web::{
+    http::{header, HeaderMap, StatusCode},
+    Error, HttpRequest, HttpResponse,
+};
+use serde::Serialize;
+
+use crate::utils::error::ApiError;
+
+/// A resource, with the version it has
+pub struct Versioned<T> {
+    pub value: T,
+    pub version: i32,
+}
+
+/// The ETags in the conditional headers of a request
+#[derive(Default, Clone, Debug)]
+pub struct Conditions {
+    if_match: Option<Vec<String>>,
+    if_none_match: Option<Vec<String>>,
+}
+
+pub fn etag(version: i32) -> String {
+    format!("\"{}\"", version)
+}
+
+/// The ETags in a header, `None` if it's missing
+fn etags(headers: &HeaderMap, name: header::HeaderName) -> Option<Vec<String>> {
+    let values = headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().to_owned())
+        .filter(|tag| !tag.is_empty())
+        .collect::<Vec<_>>();
+
+    if values.is_empty() {
+        None
+    } else {
+        Some(values)
+    }
+}
+
+fn matches(tags: &[String], version: i32, weak: bool) -> bool {
+    let etag = etag(version);
+
+    tags.iter().any(|tag| {
+        let tag = match tag.strip_prefix("W/") {
+            Some(tag) if weak => tag,
+            Some(_) => return false,
+            None => tag,
+        };
+        tag == "*" || tag == etag
+    })
+}
+
+impl Conditions {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        Conditions {
+            if_match: etags(req.headers(), header::IF_MATCH),
+            if_none_match: etags(req.headers(), header::IF_NONE_MATCH),
+        }
+    }
+
+    /// Fails unless the resource is still at a version the client expects
+    pub fn check(&self, version: i32) -> Result<(), ApiError> {
+        match &self.if_match {
+            Some(tags) if !matches(tags, version, false) => {
+                Err(ApiError::PreconditionFailed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the client already has the version of the resource
+    fn is_fresh(&self, version: i32) -> bool {
+        match &self.if_none_match {
+            Some(tags) => matches(tags, version, true),
+            None => false,
+        }
+    }
+}
+
+/// Responds with the resource and its ETag
+pub fn respond<T: Serialize>(
+    result: Result<Versioned<T>, ApiError>,
+) -> Result<HttpResponse, Error> {
+    let Versioned { value, version } = result?;
+
+    Ok(HttpResponse::Ok().header(header::ETAG, etag(version)).json(value))
+}
+
+/// Like [`respond`](fn.respond.html), but with `304 Not Modified` when the
+/// client already has the version of the resource.
+pub fn respond_if_modified<T: Serialize>(
+    result: Result<Versioned<T>, ApiError>,
+    conditions: &Conditions,
+) -> Result<HttpResponse, Error> {
+    match result {
+        Ok(Versioned { version, .. }) if conditions.is_fresh(version) => {
+            Ok(HttpResponse::build(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag(version))
+                .finish())
+        }
+        result => respond(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use super::Conditions;
+    use crate::items::{item::Item, registry};
+    use crate::users::User;
+    use crate::utils::{error::ApiError, validator};
+
+    #[test]
+    fn test_conditions() {
+        let conditions = Conditions::from_request(
+            &TestRequest::default()
+                .header(header::IF_MATCH, "\"1\", \"3\"")
+                .header(header::IF_NONE_MATCH, "W/\"2\"")
+                .to_http_request(),
+        );
+
+        assert!(conditions.check(3).is_ok());
+        assert!(matches!(
+            conditions.check(2),
+            Err(ApiError::PreconditionFailed)
+        ));
+        assert!(conditions.is_fresh(2));
+        assert!(!conditions.is_fresh(3));
+
+        let conditions = Conditions::from_request(
+            &TestRequest::default()
+                .header(header::IF_MATCH, "*")
+                .to_http_request(),
+        );
+        assert!(conditions.check(7).is_ok());
+        assert!(!conditions.is_fresh(7));
+
+        let conditions = Conditions::default();
+        assert!(conditions.check(7).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_conditional_requests(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(registry::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "concurrent");
+
+                let request = TestRequest::post()
+                    .uri("/pages")
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "title": "shared" }))
+                    .to_request();
+                let page: Value = read_response_json(&mut app, request).await;
+                let id = page["item"]["id"].as_str().unwrap().to_owned();
+                let uri = format!("/pages/{}", id);
+
+                let request = TestRequest::get()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"1\"");
+
+                let request = TestRequest::get()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .header(header::IF_NONE_MATCH, "\"1\"")
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+                // The first device saves, the second one is told it's behind
+                for (title, status) in &[
+                    ("first", StatusCode::OK),
+                    ("second", StatusCode::PRECONDITION_FAILED),
+                ] {
+                    let request = TestRequest::patch()
+                        .uri(&uri)
+                        .header(header::AUTHORIZATION, bearer.clone())
+                        .header(header::IF_MATCH, "\"1\"")
+                        .set_json(&json!({ "title": title }))
+                        .to_request();
+                    let resp = call_service(&mut app, request).await;
+                    assert_eq!(resp.status(), *status);
+                    if resp.status() == StatusCode::OK {
+                        assert_eq!(
+                            resp.headers().get(header::ETAG).unwrap(),
+                            "\"2\""
+                        );
+                    }
+                }
+
+                let request = TestRequest::get()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .header(header::IF_NONE_MATCH, "\"1\"")
+                    .to_request();
+                let page: Value = read_response_json(&mut app, request).await;
+                assert_eq!(page["title"], "first");
+
+                // Changing the item itself changes the version too
+                let request = TestRequest::patch()
+                    .uri(&format!("/items/{}", id))
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .header(header::IF_MATCH, "\"2\"")
+                    .set_json(&json!({ "due_date": "2020-07-01T00:00:00Z" }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"3\"");
+
+                let request = TestRequest::delete()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .header(header::IF_MATCH, "\"2\"")
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+
+                let request = TestRequest::delete()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer)
+                    .header(header::IF_MATCH, "\"3\"")
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                Ok(())
+            }
+        }
+    }
+}
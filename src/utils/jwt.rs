@@ -2,9 +2,18 @@ use chrono::{Duration, Utc};
 use jsonwebtoken::errors::Error;
 use uuid::Uuid;
 
+use crate::utils::keys::keys;
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct Token {
+    /// The short lived access token
     pub token: String,
+
+    /// Opaque token that can be exchanged for a new token pair
+    pub refresh_token: String,
+
+    /// Lifetime of the access token in seconds
+    pub expires_in: i64,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -19,50 +28,63 @@ pub struct Jwt {
 
     /// subject
     sub: Uuid,
-}
 
-fn get_secret() -> String {
-    std::env::var("SECRET").expect("SECRET")
+    /// The session (refresh token) this token was issued for
+    sid: Uuid,
+
+    /// Unique id of this token, only the last token
+    /// issued for a session is accepted
+    jti: Uuid,
 }
 
 impl Jwt {
-    pub fn new(iss: String, duration: Duration, sub: Uuid) -> Self {
+    pub fn new(
+        iss: String,
+        duration: Duration,
+        sub: Uuid,
+        sid: Uuid,
+        jti: Uuid,
+    ) -> Self {
         let now = Utc::now();
         let exp = now + duration;
 
-        Self { iss, exp: exp.timestamp(), sub }
+        Self { iss, exp: exp.timestamp(), sub, sid, jti }
     }
 
     pub fn sub(&self) -> Uuid {
         self.sub
     }
 
-    pub fn tokenize(self) -> Token {
-        use jsonwebtoken::{encode, EncodingKey, Header};
+    pub fn sid(&self) -> Uuid {
+        self.sid
+    }
 
-        let secret = get_secret();
-        let token = encode(
-            &Header::default(),
-            &self,
-            &EncodingKey::from_secret(secret.as_bytes()),
-        )
-        .unwrap();
+    pub fn jti(&self) -> Uuid {
+        self.jti
+    }
+
+    pub fn tokenize(self) -> String {
+        use jsonwebtoken::{encode, Header};
+
+        let signing = &keys().signing;
+        let mut header = Header::new(signing.algorithm);
+        header.kid = signing.kid.clone();
 
-        Token { token }
+        encode(&header, &self, &signing.key).unwrap()
     }
 
     pub fn decrypt(jwt: &str) -> Result<Jwt, Error> {
-        use jsonwebtoken::{decode, DecodingKey, Validation};
-
-        let secret = get_secret();
-
-        let mut validation = Validation::default();
-        validation.iss = Some("journali.nl".into());
-        decode::<Jwt>(
-            jwt,
-            &DecodingKey::from_secret(secret.as_bytes()),
-            &validation,
-        )
-        .map(|token| token.claims)
+        use jsonwebtoken::{
+            decode, decode_header, errors::ErrorKind, Validation,
+        };
+
+        let header = decode_header(jwt)?;
+        let key = keys()
+            .verifying(header.kid.as_deref())
+            .ok_or(ErrorKind::InvalidSignature)?;
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_issuer(&["journali.nl"]);
+        decode::<Jwt>(jwt, &key.key, &validation).map(|token| token.claims)
     }
 }
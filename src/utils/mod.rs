@@ -1,20 +1,29 @@
 use actix_web::dev::ServiceRequest;
-use actix_web::error::ErrorUnauthorized;
-use actix_web::Error;
-use actix_web::HttpMessage;
+use actix_web::{Error, HttpMessage, HttpRequest};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 
 use crate::database::exec_on_pool;
-use crate::users::User;
+use crate::users::{
+    access_token::{self, AccessToken, Scope},
+    session::ClientInfo,
+    RefreshToken, Session, User,
+};
+use crate::utils::error::ApiError;
 use crate::utils::jwt::Jwt;
 use crate::DbPool;
 
+pub mod config;
+pub mod error;
+pub mod etag;
 pub(crate) mod jwt;
+pub mod keys;
+pub(crate) mod password;
+pub mod patch;
+pub mod policy;
+pub mod rate_limit;
 pub(crate) mod responsable;
-
-pub(crate) fn hash_password(password: &str) -> String {
-    bcrypt::hash(password, bcrypt::DEFAULT_COST).unwrap()
-}
+pub(crate) mod token;
+pub(crate) mod totp;
 
 pub async fn validator(
     req: ServiceRequest,
@@ -26,14 +35,86 @@ pub async fn validator(
 
     let pool = req.app_data::<DbPool>().unwrap();
 
+    if _credentials.token().starts_with(access_token::PREFIX) {
+        return exec_on_pool(&pool, move |conn| {
+            AccessToken::find_user(_credentials.token(), conn).map_err(|err| {
+                match err {
+                    diesel::result::Error::NotFound => ApiError::Unauthorized(
+                        "Access token expired or revoked.".into(),
+                    ),
+                    err => err.into(),
+                }
+            })
+        })
+        .await
+        .map(|(user, access_token)| {
+            req.extensions_mut().insert(user);
+            req.extensions_mut().insert(access_token);
+            req
+        })
+        .map_err(Error::from);
+    }
+
+    let client = ClientInfo::new(&req.connection_info(), req.headers());
+
     exec_on_pool(&pool, move |conn| {
-        let jwt = Jwt::decrypt(_credentials.token()).unwrap();
-        User::find_by_id(&conn, jwt.sub())
+        let jwt = Jwt::decrypt(_credentials.token())?;
+        let (user, session) = RefreshToken::find_session(&jwt, conn).map_err(
+            |err| match err {
+                diesel::result::Error::NotFound => {
+                    ApiError::Unauthorized("Session expired or revoked.".into())
+                }
+                err => err.into(),
+            },
+        )?;
+
+        Session::touch(session.id, &client, conn)?;
+        Ok::<_, ApiError>((user, session))
     })
     .await
-    .map(|user| {
+    .map(|(user, session)| {
         req.extensions_mut().insert(user);
+        req.extensions_mut().insert(session);
         req
     })
-    .map_err(ErrorUnauthorized)
+    .map_err(Error::from)
+}
+
+/// The user a request was made by, as long as its credentials grant the
+/// scope. A session grants every scope, an access token only its own.
+pub(crate) fn authorize(
+    req: &HttpRequest,
+    scope: Scope,
+) -> Result<User, ApiError> {
+    let extensions = req.extensions();
+    let allowed = extensions.get::<RefreshToken>().is_some()
+        || matches!(
+            extensions.get::<AccessToken>(),
+            Some(access_token) if access_token.allows(scope)
+        );
+
+    match (allowed, extensions.get::<User>()) {
+        (true, Some(user)) => Ok(user.clone()),
+        (false, Some(_)) => Err(ApiError::Forbidden(format!(
+            "The access token is missing the {} scope.",
+            scope.as_str()
+        ))),
+        (_, None) => Err(ApiError::Unauthorized("Not logged in.".into())),
+    }
+}
+
+/// The user and session a request was made with. Routes that manage the
+/// account itself can't be used with an access token.
+pub(crate) fn session(
+    req: &HttpRequest,
+) -> Result<(User, RefreshToken), ApiError> {
+    let extensions = req.extensions();
+
+    match (extensions.get::<User>(), extensions.get::<RefreshToken>()) {
+        (Some(user), Some(session)) => Ok((user.clone(), session.clone())),
+        (Some(_), None) => Err(ApiError::Forbidden(
+            "This route can't be used with an access token.".into(),
+        )),
+        _ => Err(ApiError::Unauthorized("Not logged in.".into())),
+    }
 }
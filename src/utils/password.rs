@@ -0,0 +1,131 @@
+//! Hashing and verification of passwords.
+//!
+//! New passwords are hashed with Argon2id. Its cost is configured with
+//! `ARGON2_MEMORY_KIB` (default 19456), `ARGON2_ITERATIONS` (default 2)
+//! and `ARGON2_PARALLELISM` (default 1). Hashes made with other settings,
+//! or with bcrypt, are still verified, and get rehashed on the next login.
+
+use std::convert::TryFrom;
+
+use argon2::{
+    password_hash::{
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Algorithm, Argon2, Params, Version,
+};
+use once_cell::sync::Lazy;
+use ring::rand::{SecureRandom, SystemRandom};
+
+const SALT_BYTES: usize = 16;
+
+static PARAMS: Lazy<Params> = Lazy::new(|| {
+    fn env_or(name: &str, default: u32) -> u32 {
+        std::env::var(name)
+            .map(|value| {
+                value.parse().unwrap_or_else(|_| panic!("Invalid {}", name))
+            })
+            .unwrap_or(default)
+    }
+
+    Params::new(
+        env_or("ARGON2_MEMORY_KIB", 19 * 1024),
+        env_or("ARGON2_ITERATIONS", 2),
+        env_or("ARGON2_PARALLELISM", 1),
+        None,
+    )
+    .expect("Invalid Argon2 parameters")
+});
+
+fn argon2(params: Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+pub(crate) fn hash(password: &str) -> String {
+    hash_with(password, PARAMS.clone())
+}
+
+fn hash_with(password: &str, params: Params) -> String {
+    let mut salt = [0u8; SALT_BYTES];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .expect("Failed to generate random bytes");
+    let salt = SaltString::encode_b64(&salt).unwrap();
+
+    argon2(params)
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
+pub(crate) fn verify(password: &str, hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+
+    // The parameters are read from the hash itself
+    PasswordHash::new(hash)
+        .and_then(|hash| {
+            argon2(Params::default())
+                .verify_password(password.as_bytes(), &hash)
+        })
+        .is_ok()
+}
+
+/// Whether the hash was made with another algorithm or cost than new
+/// hashes are, only call this once the password has been verified.
+pub(crate) fn needs_rehash(hash: &str) -> bool {
+    needs_rehash_with(hash, &PARAMS)
+}
+
+fn needs_rehash_with(hash: &str, params: &Params) -> bool {
+    let hash = match PasswordHash::new(hash) {
+        Ok(hash) => hash,
+        Err(_) => return true,
+    };
+
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || Params::try_from(&hash).map_or(true, |used| {
+            used.m_cost() != params.m_cost()
+                || used.t_cost() != params.t_cost()
+                || used.p_cost() != params.p_cost()
+        })
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::Params;
+
+    use super::{hash_with, needs_rehash_with, verify};
+
+    fn params(m_cost: u32) -> Params {
+        Params::new(m_cost, 1, 1, None).unwrap()
+    }
+
+    #[test]
+    fn test_argon2_hashes() {
+        let hash = hash_with("black pearl", params(1024));
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify("black pearl", &hash));
+        assert!(!verify("white pearl", &hash));
+
+        assert!(!needs_rehash_with(&hash, &params(1024)));
+        assert!(needs_rehash_with(&hash, &params(2048)));
+    }
+
+    #[test]
+    fn test_bcrypt_hashes_are_verified_and_upgraded() {
+        let hash = bcrypt::hash("black pearl", 4).unwrap();
+
+        assert!(verify("black pearl", &hash));
+        assert!(!verify("white pearl", &hash));
+        assert!(needs_rehash_with(&hash, &params(1024)));
+    }
+}
@@ -0,0 +1,527 @@
+//! Partial updates.
+//!
+//! PATCH routes accept a JSON Merge Patch ([RFC 7396]) by default: fields
+//! that are left out stay the same, and `null` clears a nullable field.
+//! Fields that can't be cleared can't be set to `null` or removed. With
+//! the `application/json-patch+json` content type they accept a JSON Patch
+//! ([RFC 6902]) instead, which is applied to the current version of the
+//! resource and then handled like the merge patch of what it changed.
+//!
+//! [RFC 7396]: https://tools.ietf.org/html/rfc7396
+//! [RFC 6902]: https://tools.ietf.org/html/rfc6902
+
+use actix_web::{HttpMessage, HttpRequest};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::utils::error::ApiError;
+
+pub const JSON_PATCH: &str = "application/json-patch+json";
+
+/// The body of a PATCH request
+#[derive(Debug)]
+pub enum Patch {
+    Merge(Value),
+    Operations(Vec<Operation>),
+}
+
+/// An operation of a JSON Patch, the paths are JSON Pointers
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// The form of a partial update, which has every field of the patch set
+/// and the others left out
+pub trait Changes: DeserializeOwned {
+    /// The fields that `null` clears
+    const NULLABLE: &'static [&'static str] = &[];
+
+    /// Whether the form leaves everything as it is
+    fn is_empty(&self) -> bool;
+}
+
+/// Deserializes a field that is `None` when it's left out, and `Some(None)`
+/// when it's `null`. Use it with `#[serde(default)]`.
+pub fn nullable<'de, T, D>(
+    deserializer: D,
+) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl Patch {
+    pub fn from_request(
+        req: &HttpRequest,
+        body: Value,
+    ) -> Result<Self, ApiError> {
+        if req.content_type() == JSON_PATCH {
+            serde_json::from_value(body)
+                .map(Patch::Operations)
+                .map_err(|err| ApiError::Unprocessable(err.to_string()))
+        } else {
+            Ok(Patch::Merge(body))
+        }
+    }
+
+    /// Turns the patch into the form `T` of the update
+    pub fn into_form<T>(self, current: &impl Serialize) -> Result<T, ApiError>
+    where
+        T: Changes,
+    {
+        let merge = match self {
+            Patch::Merge(merge @ Value::Object(_)) => merge,
+            Patch::Merge(_) => {
+                return Err(ApiError::Unprocessable(
+                    "A merge patch must be an object.".into(),
+                ))
+            }
+            Patch::Operations(operations) => {
+                let current = serde_json::to_value(current)
+                    .expect("Failed to serialize resource");
+                let mut patched = current.clone();
+                for operation in operations {
+                    operation.apply(&mut patched)?;
+                }
+                merge_patch(&current, &patched)
+            }
+        };
+
+        if let Value::Object(fields) = &merge {
+            let removed = fields.iter().find(|(field, value)| {
+                value.is_null() && !T::NULLABLE.contains(&field.as_str())
+            });
+            if let Some((field, _)) = removed {
+                return Err(unprocessable(format!(
+                    "{} can't be removed.",
+                    field
+                )));
+            }
+        }
+
+        serde_json::from_value(merge)
+            .map_err(|err| ApiError::Unprocessable(err.to_string()))
+    }
+}
+
+/// The merge patch that turns `from` into `to`
+fn merge_patch(from: &Value, to: &Value) -> Value {
+    let (from, to) = match (from, to) {
+        (Value::Object(from), Value::Object(to)) => (from, to),
+        _ => return to.clone(),
+    };
+
+    let mut patch = Map::new();
+    for (field, value) in to {
+        match from.get(field) {
+            Some(old) if old == value => {}
+            Some(old) => {
+                patch.insert(field.clone(), merge_patch(old, value));
+            }
+            None => {
+                patch.insert(field.clone(), value.clone());
+            }
+        }
+    }
+    for field in from.keys().filter(|field| !to.contains_key(*field)) {
+        patch.insert(field.clone(), Value::Null);
+    }
+    Value::Object(patch)
+}
+
+fn unprocessable(message: String) -> ApiError {
+    ApiError::Unprocessable(message)
+}
+
+/// The reference tokens of a JSON Pointer
+fn tokens(pointer: &str) -> Result<Vec<String>, ApiError> {
+    if pointer.is_empty() {
+        return Ok(vec![]);
+    }
+    if !pointer.starts_with('/') {
+        return Err(unprocessable(format!(
+            "{} isn't a JSON Pointer.",
+            pointer
+        )));
+    }
+
+    Ok(pointer[1..]
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn index(token: &str, len: usize, path: &str) -> Result<usize, ApiError> {
+    match token.parse::<usize>() {
+        Ok(index)
+            if index < len && (token == "0" || !token.starts_with('0')) =>
+        {
+            Ok(index)
+        }
+        _ => Err(unprocessable(format!("{} doesn't exist.", path))),
+    }
+}
+
+/// The value the tokens point to
+fn get_mut<'a>(
+    mut value: &'a mut Value,
+    tokens: &[String],
+    path: &str,
+) -> Result<&'a mut Value, ApiError> {
+    for token in tokens {
+        value = match value {
+            Value::Object(map) => map.get_mut(token),
+            Value::Array(array) => {
+                let index = index(token, array.len(), path)?;
+                array.get_mut(index)
+            }
+            _ => None,
+        }
+        .ok_or_else(|| unprocessable(format!("{} doesn't exist.", path)))?;
+    }
+    Ok(value)
+}
+
+fn split(path: &str) -> Result<(Vec<String>, String), ApiError> {
+    let mut tokens = tokens(path)?;
+    let last = tokens.pop().ok_or_else(|| {
+        unprocessable("The whole document can't be patched.".into())
+    })?;
+    Ok((tokens, last))
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), ApiError> {
+    let (parent, last) = split(path)?;
+
+    match get_mut(document, &parent, path)? {
+        Value::Object(map) => {
+            map.insert(last, value);
+        }
+        Value::Array(array) if last == "-" => array.push(value),
+        Value::Array(array) => {
+            let index = index(&last, array.len() + 1, path)?;
+            array.insert(index, value);
+        }
+        _ => return Err(unprocessable(format!("{} doesn't exist.", path))),
+    }
+    Ok(())
+}
+
+fn remove(document: &mut Value, path: &str) -> Result<Value, ApiError> {
+    let (parent, last) = split(path)?;
+
+    match get_mut(document, &parent, path)? {
+        Value::Object(map) => map.remove(&last),
+        Value::Array(array) => {
+            let index = index(&last, array.len(), path)?;
+            Some(array.remove(index))
+        }
+        _ => None,
+    }
+    .ok_or_else(|| unprocessable(format!("{} doesn't exist.", path)))
+}
+
+impl Operation {
+    fn apply(self, document: &mut Value) -> Result<(), ApiError> {
+        match self {
+            Operation::Add { path, value } => add(document, &path, value),
+            Operation::Remove { path } => remove(document, &path).map(drop),
+            Operation::Replace { path, value } => {
+                remove(document, &path)?;
+                add(document, &path, value)
+            }
+            Operation::Move { from, path } => {
+                if path.starts_with(&format!("{}/", from)) {
+                    return Err(unprocessable(format!(
+                        "{} can't be moved into itself.",
+                        from
+                    )));
+                }
+                let value = remove(document, &from)?;
+                add(document, &path, value)
+            }
+            Operation::Copy { from, path } => {
+                let value = get_mut(document, &tokens(&from)?, &from)?.clone();
+                add(document, &path, value)
+            }
+            Operation::Test { path, value } => {
+                if *get_mut(document, &tokens(&path)?, &path)? == value {
+                    Ok(())
+                } else {
+                    Err(ApiError::Conflict(format!(
+                        "{} doesn't have the tested value.",
+                        path
+                    )))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use super::{merge_patch, nullable, Changes, Patch, JSON_PATCH};
+    use crate::items::{item::Item, registry};
+    use crate::tags::tag::Tag;
+    use crate::users::User;
+    use crate::utils::{error::ApiError, validator};
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Form {
+        title: Option<String>,
+        #[serde(default, deserialize_with = "nullable")]
+        due_date: Option<Option<String>>,
+    }
+
+    impl Changes for Form {
+        const NULLABLE: &'static [&'static str] = &["due_date"];
+
+        fn is_empty(&self) -> bool {
+            self.title.is_none() && self.due_date.is_none()
+        }
+    }
+
+    fn operations(operations: Value) -> Patch {
+        Patch::Operations(serde_json::from_value(operations).unwrap())
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let current = json!({ "title": "a", "due_date": "today" });
+
+        let form: Form = Patch::Merge(json!({})).into_form(&current).unwrap();
+        assert_eq!(form, Form { title: None, due_date: None });
+
+        let form: Form = Patch::Merge(json!({ "due_date": null }))
+            .into_form(&current)
+            .unwrap();
+        assert_eq!(form, Form { title: None, due_date: Some(None) });
+
+        let form: Form =
+            Patch::Merge(json!({ "title": "b", "due_date": "now" }))
+                .into_form(&current)
+                .unwrap();
+        assert_eq!(
+            form,
+            Form {
+                title: Some("b".into()),
+                due_date: Some(Some("now".into()))
+            }
+        );
+
+        assert!(Patch::Merge(json!([])).into_form::<Form>(&current).is_err());
+        assert!(Patch::Merge(json!({}))
+            .into_form::<Form>(&current)
+            .unwrap()
+            .is_empty());
+
+        // Only nullable fields can be cleared
+        match Patch::Merge(json!({ "title": null })).into_form::<Form>(&current)
+        {
+            Err(ApiError::Unprocessable(message)) => {
+                assert_eq!(message, "title can't be removed.")
+            }
+            result => panic!("title was removed: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let current =
+            json!({ "title": "a", "due_date": "today", "tags": ["x"] });
+
+        let form: Form = operations(json!([
+            { "op": "test", "path": "/title", "value": "a" },
+            { "op": "replace", "path": "/title", "value": "b" },
+            { "op": "remove", "path": "/due_date" },
+        ]))
+        .into_form(&current)
+        .unwrap();
+        assert_eq!(
+            form,
+            Form { title: Some("b".into()), due_date: Some(None) }
+        );
+
+        // Only what changed ends up in the form
+        let form: Form = operations(json!([
+            { "op": "copy", "from": "/title", "path": "/subtitle" },
+            { "op": "add", "path": "/tags/-", "value": "y" },
+        ]))
+        .into_form(&current)
+        .unwrap();
+        assert_eq!(form, Form { title: None, due_date: None });
+
+        match operations(
+            json!([{ "op": "test", "path": "/title", "value": "b" }]),
+        )
+        .into_form::<Form>(&current)
+        {
+            Err(ApiError::Conflict(_)) => {}
+            result => panic!("the test should fail, not {:?}", result.is_ok()),
+        }
+        assert!(operations(json!([{ "op": "remove", "path": "/missing" }]))
+            .into_form::<Form>(&current)
+            .is_err());
+        assert!(operations(json!([{ "op": "remove", "path": "/title" }]))
+            .into_form::<Form>(&current)
+            .is_err());
+        assert!(operations(json!([
+            { "op": "move", "from": "/tags", "path": "/tags/0" }
+        ]))
+        .into_form::<Form>(&current)
+        .is_err());
+    }
+
+    #[test]
+    fn test_merge_patch_between_documents() {
+        assert_eq!(
+            merge_patch(
+                &json!({ "a": 1, "b": { "c": 2, "d": 3 }, "e": 4 }),
+                &json!({ "a": 1, "b": { "c": 5, "d": 3 }, "f": 6 }),
+            ),
+            json!({ "b": { "c": 5 }, "e": null, "f": 6 })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_patch_routes() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup {
+                |cfg| {
+                    User::routes(cfg);
+                    cfg.service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::bearer(validator))
+                            .configure(Item::routes)
+                            .configure(registry::routes)
+                            .configure(Tag::routes),
+                    );
+                }
+            }
+
+            test = |app| {
+                let (_, bearer) = login!(app, "patcher");
+
+                macro_rules! send {
+                    ($request:expr, $uri:expr, $body:expr) => {
+                        read_response_json::<_, _, Value>(
+                            &mut app,
+                            $request
+                                .uri(&$uri)
+                                .header(header::AUTHORIZATION, bearer.clone())
+                                .set_json(&$body)
+                                .to_request(),
+                        )
+                        .await
+                    };
+                }
+
+                let page = send!(TestRequest::post(), "/pages", json!({ "title": "list" }));
+                let todo = send!(TestRequest::post(), "/todos", json!({
+                    "title": "chores",
+                    "page_id": page["item"]["id"],
+                    "coord_x": 0,
+                    "coord_y": 0,
+                }));
+                let todo_item = send!(TestRequest::post(), "/todo_items", json!({
+                    "title": "dishes",
+                    "todo_id": todo["item"]["id"],
+                    "is_checked": false,
+                }));
+                let id = todo_item["item"]["id"].as_str().unwrap().to_owned();
+                let uri = format!("/todo_items/{}", id);
+
+                // Checking a todo item doesn't need its title
+                let patched = send!(TestRequest::patch(), uri, json!({ "is_checked": true }));
+                assert_eq!(patched["title"], "dishes");
+                assert_eq!(patched["is_checked"], true);
+
+                let patched = send!(TestRequest::patch(), uri, json!({}));
+                assert_eq!(patched["is_checked"], true);
+
+                // A title can be changed, not removed
+                let request = TestRequest::patch()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!({ "title": null }))
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                let request = TestRequest::patch()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!([{ "op": "remove", "path": "/title" }]))
+                    .header(header::CONTENT_TYPE, JSON_PATCH)
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                let request = TestRequest::patch()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!([
+                        { "op": "test", "path": "/title", "value": "dishes" },
+                        { "op": "replace", "path": "/title", "value": "laundry" },
+                    ]))
+                    .header(header::CONTENT_TYPE, JSON_PATCH)
+                    .to_request();
+                let patched: Value = read_response_json(&mut app, request).await;
+                assert_eq!(patched["title"], "laundry");
+                assert_eq!(patched["is_checked"], true);
+
+                let request = TestRequest::patch()
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, bearer.clone())
+                    .set_json(&json!([{ "op": "test", "path": "/title", "value": "dishes" }]))
+                    .header(header::CONTENT_TYPE, JSON_PATCH)
+                    .to_request();
+                let resp = call_service(&mut app, request).await;
+                assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+                // A due date is only cleared by an explicit null
+                let item_uri = format!("/items/{}", id);
+                let due_date = "2020-07-01T00:00:00Z";
+                let item = send!(TestRequest::patch(), item_uri, json!({ "due_date": due_date }));
+                assert_eq!(item["due_date"], due_date);
+                let item = send!(TestRequest::patch(), item_uri, json!({}));
+                assert_eq!(item["due_date"], due_date);
+                let item = send!(TestRequest::patch(), item_uri, json!({ "due_date": null }));
+                assert_eq!(item["due_date"], Value::Null);
+
+                let tag = send!(
+                    TestRequest::post(),
+                    "/tags",
+                    json!({ "name": "home", "color": "0x00FF00" })
+                );
+                let tag = send!(
+                    TestRequest::patch(),
+                    format!("/tags/{}", tag["id"].as_str().unwrap()),
+                    json!({ "color": "0xFF0000" })
+                );
+                assert_eq!(tag["name"], "home");
+                assert_eq!(tag["color"], "0xFF0000");
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Who may access which resource.
+//!
+//! Every resource a user owns implements [`Policy`](trait.Policy.html), and
+//! is checked with it before it is read or changed. Resources of other users
+//! are reported as not found, so it isn't leaked whether they exist.
+
+use diesel::dsl::{exists, select};
+use diesel::{pg::PgConnection, prelude::*, QueryResult};
+use uuid::Uuid;
+
+use crate::items::{item::Item, TypeMarker};
+use crate::schema::{items, tags};
+use crate::tags::tag::Tag;
+use crate::users::User;
+use crate::utils::error::ApiError;
+
+pub trait Policy {
+    /// Whether the resource with the id belongs to the user
+    fn owns(id: Uuid, user: &User, conn: &PgConnection) -> QueryResult<bool>;
+
+    /// Fails with `NotFound` unless the resource belongs to the user
+    fn authorize(
+        id: Uuid,
+        user: &User,
+        conn: &PgConnection,
+    ) -> Result<(), ApiError> {
+        if Self::owns(id, user, conn)? {
+            Ok(())
+        } else {
+            Err(ApiError::NotFound)
+        }
+    }
+}
+
+/// Any item, whatever its type. Items in the trash count as not found.
+impl Policy for Item {
+    fn owns(id: Uuid, user: &User, conn: &PgConnection) -> QueryResult<bool> {
+        select(exists(
+            items::table
+                .filter(items::id.eq(id))
+                .filter(items::owner_id.eq(user.id))
+                .filter(items::deleted_at.is_null()),
+        ))
+        .get_result(conn)
+    }
+}
+
+/// An item of one specific type, e.g. a page
+impl<T: TypeMarker> Policy for T {
+    fn owns(id: Uuid, user: &User, conn: &PgConnection) -> QueryResult<bool> {
+        select(exists(
+            items::table
+                .filter(items::id.eq(id))
+                .filter(items::owner_id.eq(user.id))
+                .filter(items::item_type.eq(T::TYPE as i16))
+                .filter(items::deleted_at.is_null()),
+        ))
+        .get_result(conn)
+    }
+}
+
+impl Policy for Tag {
+    fn owns(id: Uuid, user: &User, conn: &PgConnection) -> QueryResult<bool> {
+        select(exists(
+            tags::table
+                .filter(tags::id.eq(id))
+                .filter(tags::owner_id.eq(user.id)),
+        ))
+        .get_result(conn)
+    }
+}
+
+/// Users only own their own account
+impl Policy for User {
+    fn owns(id: Uuid, user: &User, _: &PgConnection) -> QueryResult<bool> {
+        Ok(id == user.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, Method, StatusCode},
+        test::{read_response_json, TestRequest},
+        web,
+    };
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use serde_json::{json, Value};
+
+    use crate::items::{item::Item, registry};
+    use crate::tags::tag::Tag;
+    use crate::testing::call_status;
+    use crate::users::User;
+    use crate::utils::validator;
+
+    fn request(
+        method: Method,
+        uri: &str,
+        bearer: &str,
+        body: &Value,
+    ) -> TestRequest {
+        TestRequest::with_uri(uri)
+            .method(method)
+            .header(header::AUTHORIZATION, bearer)
+            .set_json(body)
+    }
+
+    fn configure(cfg: &mut web::ServiceConfig) {
+        User::routes(cfg);
+        cfg.service(
+            web::scope("")
+                .wrap(HttpAuthentication::bearer(validator))
+                .configure(Item::routes)
+                .configure(registry::routes)
+                .configure(Tag::routes)
+                .configure(User::route_me),
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_items_of_others() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup { configure }
+
+            test = |app| {
+                let (_, owner) = login!(app, "owner");
+                let (_, other) = login!(app, "other");
+
+                let create = |uri, bearer, body| {
+                    request(Method::POST, uri, bearer, &body).to_request()
+                };
+
+                let page: Value = read_response_json(
+                    &mut app,
+                    create("/pages", &owner, json!({ "title": "diary" })),
+                )
+                .await;
+                let page_id = page["item"]["id"].as_str().unwrap().to_string();
+                let on_page = json!({
+                    "title": "chores",
+                    "text": "chores",
+                    "page_id": page_id,
+                    "coord_x": 0,
+                    "coord_y": 0,
+                });
+                let todo: Value = read_response_json(
+                    &mut app,
+                    create("/todos", &owner, on_page.clone()),
+                )
+                .await;
+                let todo_id = todo["item"]["id"].as_str().unwrap().to_string();
+                let todo_item: Value = read_response_json(
+                    &mut app,
+                    create(
+                        "/todo_items",
+                        &owner,
+                        json!({
+                            "title": "dishes",
+                            "todo_id": todo_id,
+                            "is_checked": false,
+                        }),
+                    ),
+                )
+                .await;
+                let text_field: Value = read_response_json(
+                    &mut app,
+                    create("/text_fields", &owner, on_page.clone()),
+                )
+                .await;
+
+                let routes = vec![
+                    ("/pages", &page, json!({ "title": "mine" })),
+                    ("/todos", &todo, on_page.clone()),
+                    ("/todo_items", &todo_item, json!({
+                        "title": "mine",
+                        "is_checked": true,
+                    })),
+                    ("/text_fields", &text_field, on_page.clone()),
+                ];
+                for (uri, item, update) in routes {
+                    let uri =
+                        format!("{}/{}", uri, item["item"]["id"].as_str().unwrap());
+
+                    for (method, body) in [
+                        (Method::GET, json!(null)),
+                        (Method::PATCH, update),
+                        (Method::DELETE, json!(null)),
+                    ] {
+                        let status = call_status(
+                            &mut app,
+                            request(method.clone(), &uri, &other, &body)
+                                .to_request(),
+                        )
+                        .await;
+                        assert_eq!(status, StatusCode::NOT_FOUND, "{} {}", method, uri);
+                    }
+
+                    let status = call_status(
+                        &mut app,
+                        request(Method::GET, &uri, &owner, &json!(null))
+                            .to_request(),
+                    )
+                    .await;
+                    assert_eq!(status, StatusCode::OK, "GET {}", uri);
+                }
+
+                // Nothing can be created inside an item of someone else
+                let status = call_status(
+                    &mut app,
+                    create("/todos", &other, on_page.clone()),
+                )
+                .await;
+                assert_eq!(status, StatusCode::NOT_FOUND);
+
+                let status = call_status(
+                    &mut app,
+                    request(
+                        Method::PATCH,
+                        &format!("/items/{}", page_id),
+                        &other,
+                        &json!({ "parent_id": null }),
+                    )
+                    .to_request(),
+                )
+                .await;
+                assert_eq!(status, StatusCode::NOT_FOUND);
+
+                // Nor can an item be moved into it
+                let own_page: Value = read_response_json(
+                    &mut app,
+                    create("/pages", &other, json!({ "title": "mine" })),
+                )
+                .await;
+                let status = call_status(
+                    &mut app,
+                    request(
+                        Method::PATCH,
+                        &format!("/items/{}", own_page["item"]["id"].as_str().unwrap()),
+                        &other,
+                        &json!({ "parent_id": page_id, "parent_type": 100 }),
+                    )
+                    .to_request(),
+                )
+                .await;
+                assert_eq!(status, StatusCode::NOT_FOUND);
+
+                Ok(())
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_tags_of_others() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup { configure }
+
+            test = |app| {
+                let (_, owner) = login!(app, "owner");
+                let (_, other) = login!(app, "other");
+
+                let new_tag = json!({ "name": "school", "color": "0xFFFFFF" });
+                let tag: Value = read_response_json(
+                    &mut app,
+                    request(Method::POST, "/tags", &owner, &new_tag).to_request(),
+                )
+                .await;
+                let tag_uri = format!("/tags/{}", tag["id"].as_str().unwrap());
+                let own_tag: Value = read_response_json(
+                    &mut app,
+                    request(Method::POST, "/tags", &other, &new_tag).to_request(),
+                )
+                .await;
+                let own_tag_uri =
+                    format!("/tags/{}", own_tag["id"].as_str().unwrap());
+
+                let page: Value = read_response_json(
+                    &mut app,
+                    request(Method::POST, "/pages", &owner, &json!({ "title": "diary" }))
+                        .to_request(),
+                )
+                .await;
+                let items = json!([{ "id": page["item"]["id"], "item_type": 100 }]);
+
+                let routes = vec![
+                    (Method::PATCH, tag_uri.clone(), new_tag.clone()),
+                    (Method::DELETE, tag_uri.clone(), json!(null)),
+                    (Method::PATCH, format!("{}/items", tag_uri), items.clone()),
+                    (Method::DELETE, format!("{}/items", tag_uri), items.clone()),
+                    // Items of others can't be tagged either
+                    (Method::PATCH, format!("{}/items", own_tag_uri), items.clone()),
+                ];
+                for (method, uri, body) in routes {
+                    let status = call_status(
+                        &mut app,
+                        request(method.clone(), &uri, &other, &body).to_request(),
+                    )
+                    .await;
+                    assert_eq!(status, StatusCode::NOT_FOUND, "{} {}", method, uri);
+                }
+
+                let status = call_status(
+                    &mut app,
+                    request(Method::PATCH, &format!("{}/items", tag_uri), &owner, &items)
+                        .to_request(),
+                )
+                .await;
+                assert_eq!(status, StatusCode::OK);
+
+                Ok(())
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_users_of_others() -> Result<(), Box<dyn std::error::Error>> {
+        test! {
+            setup { configure }
+
+            test = |app| {
+                let (owner, _) = login!(app, "owner");
+                let (other, bearer) = login!(app, "other");
+
+                let update = json!({ "password": "Hijacked password" });
+
+                let status = call_status(
+                    &mut app,
+                    TestRequest::patch()
+                        .uri(&format!("/users/{}", owner.id))
+                        .set_json(&update)
+                        .to_request(),
+                )
+                .await;
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+                let status = call_status(
+                    &mut app,
+                    request(
+                        Method::PATCH,
+                        &format!("/users/{}", owner.id),
+                        &bearer,
+                        &update,
+                    )
+                    .to_request(),
+                )
+                .await;
+                assert_eq!(status, StatusCode::NOT_FOUND);
+
+                let status = call_status(
+                    &mut app,
+                    request(
+                        Method::PATCH,
+                        &format!("/users/{}", other.id),
+                        &bearer,
+                        &update,
+                    )
+                    .to_request(),
+                )
+                .await;
+                assert_eq!(status, StatusCode::OK);
+
+                Ok(())
+            }
+        }
+    }
+}
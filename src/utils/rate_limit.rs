@@ -0,0 +1,591 @@
+//! Rate limiting of the routes that don't require a login.
+//!
+//! Requests are counted in fixed windows, per client IP by the
+//! [`RateLimit`](struct.RateLimit.html) middleware and per username by
+//! the login route. After too many failed logins an account is locked
+//! for a while. The limits are configured with environment variables,
+//! as `<requests>/<seconds>`:
+//!
+//! - `RATE_LIMIT_IP` (default `30/60`)
+//! - `RATE_LIMIT_USERNAME` (default `10/60`)
+//! - `LOGIN_LOCKOUT` (default `5/900`), failed logins before a lockout
+//!   and how long it lasts.
+//!
+//! Clients are told apart by the address they connect from. Behind a
+//! reverse proxy, list its addresses in `TRUSTED_PROXIES` (comma separated)
+//! so the `X-Forwarded-For` header it sets is used instead. The header is
+//! ignored on requests from anywhere else, since clients can send any
+//! value in it.
+//!
+//! Counters are kept in memory, unless `RATE_LIMIT_STORE` is `postgres`,
+//! in which case they are shared by every instance through the database.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error as StdError,
+    future::{ready, Future, Ready},
+    net::IpAddr,
+    pin::Pin,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error,
+};
+use chrono::{DateTime, Duration, Utc};
+use diesel::{prelude::*, sql_types};
+
+use crate::schema::rate_limits;
+use crate::utils::{config::env_or, error::ApiError};
+use crate::DbPool;
+
+/// Memory stores are cleaned up once they track this many keys
+const MEMORY_STORE_PRUNE_SIZE: usize = 10_000;
+
+/// The error for a limit that isn't formatted as `<requests>/<seconds>`
+#[derive(Debug)]
+pub struct InvalidLimit;
+
+impl std::fmt::Display for InvalidLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "must be formatted as <requests>/<seconds>")
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub requests: i32,
+    pub window: Duration,
+}
+
+impl Limit {
+    pub fn new(requests: i32, seconds: i64) -> Self {
+        Self { requests, window: Duration::seconds(seconds) }
+    }
+}
+
+impl std::str::FromStr for Limit {
+    type Err = InvalidLimit;
+
+    /// Reads a limit formatted as `<requests>/<seconds>`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split_once('/')
+            .and_then(|(requests, seconds)| {
+                Some(Limit::new(
+                    requests.trim().parse().ok()?,
+                    seconds.trim().parse().ok()?,
+                ))
+            })
+            .ok_or(InvalidLimit)
+    }
+}
+
+/// The hits counted in the current window of a key
+#[derive(QueryableByName, Clone, Copy, Debug)]
+#[table_name = "rate_limits"]
+pub struct Bucket {
+    pub hits: i32,
+    pub resets_at: DateTime<Utc>,
+}
+
+impl Bucket {
+    fn retry_after(&self) -> i64 {
+        (self.resets_at - Utc::now()).num_seconds().max(1)
+    }
+}
+
+pub trait Store: Send + Sync {
+    /// Counts a hit for the key, starting a new window when needed
+    fn hit(&self, key: &str, window: Duration) -> Result<Bucket, ApiError>;
+
+    /// The current window of the key, without counting a hit
+    fn get(&self, key: &str) -> Result<Option<Bucket>, ApiError>;
+
+    fn reset(&self, key: &str) -> Result<(), ApiError>;
+}
+
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// How many keys are tracked before the next cleanup
+    prune_at: Mutex<usize>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::default(),
+            prune_at: Mutex::new(MEMORY_STORE_PRUNE_SIZE),
+        }
+    }
+}
+
+impl Store for MemoryStore {
+    fn hit(&self, key: &str, window: Duration) -> Result<Bucket, ApiError> {
+        let now = Utc::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut prune_at = self.prune_at.lock().unwrap();
+        if buckets.len() >= *prune_at {
+            buckets.retain(|_, bucket| bucket.resets_at > now);
+            // Only expired windows are dropped, so when most keys are still
+            // in theirs the next cleanup waits until the store has doubled
+            *prune_at = (buckets.len() * 2).max(MEMORY_STORE_PRUNE_SIZE);
+        }
+
+        let bucket = buckets
+            .entry(key.to_string())
+            .and_modify(|bucket| {
+                if bucket.resets_at <= now {
+                    *bucket = Bucket { hits: 0, resets_at: now + window };
+                }
+            })
+            .or_insert(Bucket { hits: 0, resets_at: now + window });
+        bucket.hits += 1;
+
+        Ok(*bucket)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Bucket>, ApiError> {
+        let buckets = self.buckets.lock().unwrap();
+
+        Ok(buckets
+            .get(key)
+            .filter(|bucket| bucket.resets_at > Utc::now())
+            .copied())
+    }
+
+    fn reset(&self, key: &str) -> Result<(), ApiError> {
+        self.buckets.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+pub struct PgStore {
+    pool: DbPool,
+}
+
+impl PgStore {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl Store for PgStore {
+    fn hit(&self, key: &str, window: Duration) -> Result<Bucket, ApiError> {
+        let now = Utc::now();
+
+        diesel::sql_query(
+            "INSERT INTO rate_limits (key, hits, resets_at) VALUES ($1, 1, $2)
+             ON CONFLICT (key) DO UPDATE SET
+                 hits = CASE WHEN rate_limits.resets_at <= $3
+                     THEN 1 ELSE rate_limits.hits + 1 END,
+                 resets_at = CASE WHEN rate_limits.resets_at <= $3
+                     THEN excluded.resets_at ELSE rate_limits.resets_at END
+             RETURNING hits, resets_at",
+        )
+        .bind::<sql_types::Text, _>(key)
+        .bind::<sql_types::Timestamptz, _>(now + window)
+        .bind::<sql_types::Timestamptz, _>(now)
+        .get_result(&self.pool.get()?)
+        .map_err(ApiError::from)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Bucket>, ApiError> {
+        rate_limits::table
+            .find(key)
+            .filter(rate_limits::resets_at.gt(Utc::now()))
+            .select((rate_limits::hits, rate_limits::resets_at))
+            .first::<(i32, DateTime<Utc>)>(&self.pool.get()?)
+            .optional()
+            .map(|bucket| {
+                bucket.map(|(hits, resets_at)| Bucket { hits, resets_at })
+            })
+            .map_err(ApiError::from)
+    }
+
+    fn reset(&self, key: &str) -> Result<(), ApiError> {
+        diesel::delete(rate_limits::table.find(key))
+            .execute(&self.pool.get()?)
+            .map(drop)
+            .map_err(ApiError::from)
+    }
+}
+
+/// The limits, and where the hits are counted.
+/// Shared by every worker, register it with `App::app_data`.
+pub struct RateLimiter {
+    store: Arc<dyn Store>,
+    pub per_ip: Limit,
+    pub per_username: Limit,
+    pub lockout: Limit,
+    /// The proxies whose `X-Forwarded-For` header is believed
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        store: Arc<dyn Store>,
+        per_ip: Limit,
+        per_username: Limit,
+        lockout: Limit,
+    ) -> Self {
+        Self { store, per_ip, per_username, lockout, trusted_proxies: vec![] }
+    }
+
+    /// An in-memory limiter with the default limits
+    pub fn memory() -> Self {
+        Self::new(
+            Arc::new(MemoryStore::default()),
+            Limit::new(30, 60),
+            Limit::new(10, 60),
+            Limit::new(5, 900),
+        )
+    }
+
+    pub fn from_env() -> Result<Self, Box<dyn StdError>> {
+        let store: Arc<dyn Store> =
+            match std::env::var("RATE_LIMIT_STORE").as_deref() {
+                Ok("postgres") => Arc::new(PgStore::new(crate::create_pool())),
+                _ => Arc::new(MemoryStore::default()),
+            };
+        let defaults = Self::memory();
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy.parse().map_err(|err| {
+                    format!("TRUSTED_PROXIES is invalid: {}", err)
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            trusted_proxies,
+            ..Self::new(
+                store,
+                env_or("RATE_LIMIT_IP", defaults.per_ip)?,
+                env_or("RATE_LIMIT_USERNAME", defaults.per_username)?,
+                env_or("LOGIN_LOCKOUT", defaults.lockout)?,
+            )
+        })
+    }
+
+    /// Counts a request by the client, fails when it made too many
+    pub async fn hit_ip(&self, ip: &str) -> Result<(), ApiError> {
+        self.hit(format!("ip:{}", ip), self.per_ip).await
+    }
+
+    /// Counts a login attempt for the username, fails when there were
+    /// too many attempts or when the account is locked.
+    pub async fn hit_username(&self, username: &str) -> Result<(), ApiError> {
+        let username = username.to_lowercase();
+        let lockout = self.lockout;
+
+        let store = self.store.clone();
+        let key = format!("login-failures:{}", username);
+        let failures = web::block(move || store.get(&key)).await?;
+        if let Some(bucket) = failures {
+            if bucket.hits >= lockout.requests {
+                return Err(ApiError::TooManyRequests(bucket.retry_after()));
+            }
+        }
+
+        self.hit(format!("username:{}", username), self.per_username).await
+    }
+
+    /// Counts a failed login, the account gets locked after too many
+    pub async fn login_failed(&self, username: &str) -> Result<(), ApiError> {
+        let store = self.store.clone();
+        let key = format!("login-failures:{}", username.to_lowercase());
+        let window = self.lockout.window;
+
+        web::block(move || store.hit(&key, window)).await.map(drop)?;
+        Ok(())
+    }
+
+    pub async fn login_succeeded(
+        &self,
+        username: &str,
+    ) -> Result<(), ApiError> {
+        let store = self.store.clone();
+        let key = format!("login-failures:{}", username.to_lowercase());
+
+        web::block(move || store.reset(&key)).await?;
+        Ok(())
+    }
+
+    async fn hit(&self, key: String, limit: Limit) -> Result<(), ApiError> {
+        let store = self.store.clone();
+        let bucket = web::block(move || store.hit(&key, limit.window)).await?;
+
+        if bucket.hits > limit.requests {
+            Err(ApiError::TooManyRequests(bucket.retry_after()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Limits the requests per client IP to the given paths
+pub struct RateLimit {
+    limiter: web::Data<RateLimiter>,
+    paths: Rc<Vec<String>>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: web::Data<RateLimiter>, paths: &[&str]) -> Self {
+        Self {
+            limiter,
+            paths: Rc::new(paths.iter().map(|path| path.to_string()).collect()),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = Error,
+        > + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            limiter: self.limiter.clone(),
+            paths: self.paths.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    limiter: web::Data<RateLimiter>,
+    paths: Rc<Vec<String>>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = Error,
+        > + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        if !self.paths.iter().any(|path| path == req.path()) {
+            return Box::pin(service.borrow_mut().call(req));
+        }
+
+        let limiter = self.limiter.clone();
+        let ip = client_ip(&req, &limiter.trusted_proxies);
+
+        Box::pin(async move {
+            limiter.hit_ip(&ip).await?;
+
+            let response = service.borrow_mut().call(req);
+            response.await
+        })
+    }
+}
+
+/// The IP of the client. The `X-Forwarded-For` header is followed from
+/// the right for as long as the hops are trusted proxies, the first
+/// address that isn't one is the client.
+fn client_ip(req: &ServiceRequest, trusted_proxies: &[IpAddr]) -> String {
+    let mut client = match req.peer_addr() {
+        Some(address) => address.ip(),
+        None => return "unknown".into(),
+    };
+
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded.into_iter().rev() {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(address) => client = address,
+            Err(_) => break,
+        }
+    }
+
+    client.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{
+        dev::Service,
+        http::{header, StatusCode},
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+    use serde_json::json;
+
+    use super::{Limit, MemoryStore, PgStore, RateLimit, RateLimiter, Store};
+    use crate::{create_pool, testing, users::User};
+
+    #[test]
+    fn test_pg_store_counts_per_window(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let store = PgStore::new(create_pool());
+        let key = format!("test:{}", uuid::Uuid::new_v4());
+
+        assert_eq!(store.hit(&key, chrono::Duration::seconds(60))?.hits, 1);
+        assert_eq!(store.hit(&key, chrono::Duration::seconds(60))?.hits, 2);
+        assert_eq!(store.get(&key)?.map(|bucket| bucket.hits), Some(2));
+
+        store.reset(&key)?;
+        assert!(store.get(&key)?.is_none());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_rate_limit_and_lockout(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let limiter = web::Data::new(RateLimiter::new(
+            Arc::new(MemoryStore::default()),
+            Limit::new(6, 60),
+            Limit::new(10, 60),
+            Limit::new(2, 60),
+        ));
+        let mut app = init_service(
+            App::new()
+                .data(create_pool())
+                .app_data(limiter.clone())
+                .wrap(RateLimit::new(limiter.clone(), &["/login", "/register"]))
+                .configure(User::routes),
+        )
+        .await;
+
+        let username = testing::username("locked");
+        let login = |password: &str| {
+            TestRequest::post()
+                .uri("/login")
+                .set_json(
+                    &json!({ "username": username, "password": password }),
+                )
+                .to_request()
+        };
+
+        let request = TestRequest::post()
+            .uri("/register")
+            .set_json(
+                &json!({ "username": username, "password": testing::PASSWORD }),
+            )
+            .to_request();
+        call_service(&mut app, request).await;
+
+        for _ in 0..2 {
+            let resp = call_service(&mut app, login("wrong")).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // The account is locked, even for the right password
+        let resp = call_service(&mut app, login(testing::PASSWORD)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Once the lockout is lifted, the IP limit is what's left
+        limiter.login_succeeded(&username).await?;
+        let resp = call_service(&mut app, login(testing::PASSWORD)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = call_service(&mut app, login(testing::PASSWORD)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        match app.call(login(testing::PASSWORD)).await {
+            Err(err) => {
+                let resp = err.as_response_error().error_response();
+                assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+                assert!(resp.headers().contains_key(header::RETRY_AFTER));
+            }
+            Ok(_) => panic!("Expected the request to be rate limited"),
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_rate_limit_ignores_forwarded_for_from_clients() {
+        let mut limiter = RateLimiter::new(
+            Arc::new(MemoryStore::default()),
+            Limit::new(2, 60),
+            Limit::new(10, 60),
+            Limit::new(5, 60),
+        );
+        limiter.trusted_proxies = vec!["10.0.0.2".parse().unwrap()];
+        let limiter = web::Data::new(limiter);
+        let mut app = init_service(
+            App::new()
+                .wrap(RateLimit::new(limiter, &["/login"]))
+                .route("/login", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let login = |peer: &str, forwarded_for: &str| {
+            TestRequest::post()
+                .uri("/login")
+                .peer_addr(peer.parse().unwrap())
+                .header("X-Forwarded-For", forwarded_for)
+                .to_request()
+        };
+
+        // A client can't get a fresh limit by making up its address
+        for i in 0..2 {
+            let request = login("10.0.0.1:4000", &format!("192.0.2.{}", i));
+            let resp = call_service(&mut app, request).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        match app.call(login("10.0.0.1:4000", "192.0.2.9")).await {
+            Err(err) => {
+                let resp = err.as_response_error().error_response();
+                assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+            }
+            Ok(_) => panic!("Expected the request to be rate limited"),
+        }
+
+        // Behind a trusted proxy, every forwarded client has its own limit
+        for i in 0..3 {
+            let forwarded_for = format!("198.51.100.7, 192.0.2.{}", i);
+            let resp =
+                call_service(&mut app, login("10.0.0.2:4000", &forwarded_for))
+                    .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+    }
+}
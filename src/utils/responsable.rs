@@ -1,6 +1,8 @@
 use actix_web::{Error, HttpResponse};
 use serde::Serialize;
 
+use crate::utils::error::ApiError;
+
 pub trait Responsable {
     fn into_response(self) -> Result<HttpResponse, Error>;
 }
@@ -8,9 +10,12 @@ pub trait Responsable {
 impl<T, E> Responsable for Result<T, E>
 where
     T: Serialize,
+    E: Into<ApiError>,
 {
     fn into_response(self) -> Result<HttpResponse, Error> {
-        self.map(|item| HttpResponse::Ok().json(item))
-            .map_err(|_| HttpResponse::InternalServerError().finish().into())
+        self.map(|item| HttpResponse::Ok().json(item)).map_err(|err| {
+            let err: ApiError = err.into();
+            err.into()
+        })
     }
 }
@@ -0,0 +1,28 @@
+//! Helpers for opaque, random tokens that get stored hashed.
+
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+
+const TOKEN_BYTES: usize = 32;
+
+/// Generates a new url safe random token
+pub(crate) fn generate() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("Failed to generate random bytes");
+
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Hashes a token so it can be stored in- and looked up from the database.
+///
+/// The tokens have enough entropy, so a fast hash is sufficient here.
+pub(crate) fn hash(token: &str) -> String {
+    base64::encode_config(
+        digest(&SHA256, token.as_bytes()).as_ref(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
@@ -0,0 +1,151 @@
+//! Time-based one-time passwords, as described in RFC 6238.
+//!
+//! Codes are six digits, change every 30 seconds and are derived with
+//! HMAC-SHA1, which is what every authenticator app supports.
+
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+const SECRET_BYTES: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+
+/// How many steps a code may be off, to allow for clock drift
+const ALLOWED_DRIFT: i64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generates a new base32 encoded secret
+pub(crate) fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("Failed to generate random bytes");
+
+    base32_encode(&bytes)
+}
+
+/// The link authenticator apps read from a QR code
+pub(crate) fn provisioning_uri(secret: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/Journali:{}?secret={}&issuer=Journali&digits={}&period={}",
+        account.replace(|c: char| !c.is_ascii_alphanumeric(), "_"),
+        secret,
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+/// The time step a unix timestamp falls in
+pub(crate) fn step(timestamp: i64) -> i64 {
+    timestamp / STEP_SECONDS
+}
+
+/// Finds the step a code is valid for, around the given timestamp
+pub(crate) fn verify(secret: &str, code: &str, timestamp: i64) -> Option<i64> {
+    let key = base32_decode(secret)?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+
+    let current = step(timestamp);
+    (current - ALLOWED_DRIFT..=current + ALLOWED_DRIFT)
+        .find(|&step| generate(&key, step as u64, DIGITS) == code)
+}
+
+/// The HOTP (RFC 4226) value of a key at a counter
+pub(crate) fn generate(key: &[u8], counter: u64, digits: u32) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let hash = tag.as_ref();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!("{:0width$}", binary % 10u32.pow(digits), width = digits as usize)
+}
+
+/// RFC 4648 base32, without padding
+pub(crate) fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded
+                .push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(
+            BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char,
+        );
+    }
+
+    encoded
+}
+
+pub(crate) fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&b| b == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base32_encode, generate, verify};
+
+    // The SHA1 test vectors from RFC 6238, appendix B
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc_6238_vectors() {
+        let vectors: [(u64, &str); 4] = [
+            (59, "94287082"),
+            (1_111_111_109, "07081804"),
+            (1_234_567_890, "89005924"),
+            (20_000_000_000, "65353130"),
+        ];
+
+        for (time, code) in vectors.iter() {
+            assert_eq!(generate(SECRET, time / 30, 8), *code);
+        }
+    }
+
+    #[test]
+    fn test_verify_allows_drift() {
+        let secret = base32_encode(SECRET);
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+
+        assert_eq!(verify(&secret, "287082", 59), Some(1));
+        assert_eq!(verify(&secret, "287082", 89), Some(1));
+        assert_eq!(verify(&secret, "287082", 150), None);
+        assert_eq!(verify(&secret, "28708", 59), None);
+    }
+}